@@ -0,0 +1,127 @@
+/// How the mono APU output is laid out in the device buffer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Channels {
+    #[default]
+    Mono,
+    // the same sample on both sides
+    Stereo,
+}
+
+impl Channels {
+    pub fn count(self) -> u8 {
+        match self {
+            Channels::Mono => 1,
+            Channels::Stereo => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioConfig {
+    /// Output rate in Hz, usually 44100 or 48000.
+    pub sample_rate: u32,
+    pub channels: Channels,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        AudioConfig {
+            sample_rate: 44100,
+            channels: Channels::Mono,
+        }
+    }
+}
+
+/// Receives the samples the bus produces as it runs, at the rate it was
+/// given with `BUS::set_audio_sink`.
+pub trait AudioSink {
+    fn push_sample(&mut self, sample: f32);
+}
+
+/// Turns the APU level, which changes every CPU cycle, into samples at the
+/// output rate by picking the level at each sample point.
+pub struct Resampler {
+    channels: Channels,
+    cycles_per_sample: f64,
+    // CPU cycles since the last sample was taken
+    phase: f64,
+    samples: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(config: AudioConfig, cpu_clock: u32) -> Self {
+        Resampler {
+            channels: config.channels,
+            cycles_per_sample: cpu_clock as f64 / config.sample_rate as f64,
+            phase: 0.0,
+            samples: vec![],
+        }
+    }
+
+    /// `level` held for `cycles` CPU cycles.
+    pub fn push(&mut self, level: f32, cycles: usize) {
+        self.phase += cycles as f64;
+        while self.phase >= self.cycles_per_sample {
+            self.phase -= self.cycles_per_sample;
+            for _ in 0..self.channels.count() {
+                self.samples.push(level);
+            }
+        }
+    }
+
+    /// Interleaved samples produced so far.
+    pub fn pending(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timing::{NTSC_CPU_CLOCK, NTSC_FRAME_RATE};
+
+    fn samples_per_frame(config: AudioConfig) -> usize {
+        let mut resampler = Resampler::new(config, NTSC_CPU_CLOCK);
+        // instructions take 2 to 7 cycles, push in uneven steps
+        let frame = (NTSC_CPU_CLOCK as f64 / NTSC_FRAME_RATE) as usize;
+        let mut pushed = 0;
+        for cycles in [2, 3, 4, 5, 6, 7].iter().cycle() {
+            if pushed + cycles > frame {
+                resampler.push(0.5, frame - pushed);
+                break;
+            }
+            resampler.push(0.5, *cycles);
+            pushed += cycles;
+        }
+        resampler.take_samples().len()
+    }
+
+    #[test]
+    fn test_48000_hz_samples_per_frame() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            channels: Channels::Mono,
+        };
+        // 48000 / 60.0988 = 798.7
+        assert_eq!(samples_per_frame(config), 798);
+        assert_eq!(samples_per_frame(AudioConfig::default()), 733);
+    }
+
+    #[test]
+    fn test_stereo_duplicates_samples() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            channels: Channels::Stereo,
+        };
+        assert_eq!(samples_per_frame(config), 2 * 798);
+
+        let mut resampler = Resampler::new(config, 48000 * 10);
+        resampler.push(0.25, 10);
+        assert_eq!(resampler.take_samples(), vec![0.25, 0.25]);
+        assert_eq!(resampler.pending(), 0);
+    }
+}
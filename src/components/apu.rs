@@ -0,0 +1,1200 @@
+use crate::timing;
+
+// length counter loads, indexed by the top 5 bits of the channel's last register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// output order of the 8 step pulse waveform for each duty setting
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// the triangle ramps down to 0 then back up to 15
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+// CPU cycles at which the frame sequencer steps, the last entry wraps it around
+const FOUR_STEP_SEQUENCE: [usize; 4] = [7457, 14913, 22371, 29830];
+const FIVE_STEP_SEQUENCE: [usize; 5] = [7457, 14913, 22371, 29829, 37282];
+const PAL_FOUR_STEP_SEQUENCE: [usize; 4] = [8313, 16627, 24939, 33253];
+const PAL_FIVE_STEP_SEQUENCE: [usize; 5] = [8313, 16627, 24939, 33252, 41565];
+
+// noise timer periods in CPU cycles, indexed by the low 4 bits of $400E
+const NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+// DMC output periods in CPU cycles, indexed by the low 4 bits of $4010
+const DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// TV system the console was built for. The 2A07 of PAL consoles runs off a
+/// slower clock and has its own noise periods and frame sequencer timing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TvSystem {
+    #[default]
+    Ntsc,
+    Pal,
+}
+
+impl TvSystem {
+    pub fn cpu_clock(&self) -> u32 {
+        match self {
+            TvSystem::Ntsc => timing::NTSC_CPU_CLOCK,
+            TvSystem::Pal => timing::PAL_CPU_CLOCK,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            TvSystem::Ntsc => timing::NTSC_FRAME_RATE,
+            TvSystem::Pal => timing::PAL_FRAME_RATE,
+        }
+    }
+
+    fn noise_periods(&self) -> &'static [u16; 16] {
+        match self {
+            TvSystem::Ntsc => &NOISE_PERIODS,
+            TvSystem::Pal => &PAL_NOISE_PERIODS,
+        }
+    }
+
+    fn dmc_rates(&self) -> &'static [u16; 16] {
+        match self {
+            TvSystem::Ntsc => &DMC_RATES,
+            TvSystem::Pal => &PAL_DMC_RATES,
+        }
+    }
+
+    fn frame_sequence(&self, five_step_mode: bool) -> &'static [usize] {
+        match (self, five_step_mode) {
+            (TvSystem::Ntsc, false) => &FOUR_STEP_SEQUENCE,
+            (TvSystem::Ntsc, true) => &FIVE_STEP_SEQUENCE,
+            (TvSystem::Pal, false) => &PAL_FOUR_STEP_SEQUENCE,
+            (TvSystem::Pal, true) => &PAL_FIVE_STEP_SEQUENCE,
+        }
+    }
+}
+
+/// Counts a note's duration down in half frames. The halt flag freezes it,
+/// the same bit doubles as the envelope loop flag (or triangle control flag).
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    // loads are ignored while the channel is disabled through $4015
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[index as usize];
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.value > 0
+    }
+}
+
+/// Volume decay of the pulse and noise channels, restarted when the last
+/// register of the channel is written.
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // constant volume, or the divider period when decaying
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.period = data & 0b0000_1111;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+/// Bends the pulse period up or down every few half frames. Pulse 1 negates
+/// with the ones' complement, so it bends down one step further than pulse 2.
+#[derive(Default)]
+struct Sweep {
+    enabled: bool,
+    period: u8,
+    negate: bool,
+    shift: u8,
+    divider: u8,
+    reload: bool,
+    ones_complement: bool,
+}
+
+impl Sweep {
+    fn write(&mut self, data: u8) {
+        self.enabled = data & 0b1000_0000 != 0;
+        self.period = (data >> 4) & 0b111;
+        self.negate = data & 0b0000_1000 != 0;
+        self.shift = data & 0b0000_0111;
+        self.reload = true;
+    }
+
+    fn target_period(&self, timer_period: u16) -> u16 {
+        let change = timer_period >> self.shift;
+        if self.negate {
+            timer_period.saturating_sub(change + self.ones_complement as u16)
+        } else {
+            timer_period + change
+        }
+    }
+
+    // the target is checked all the time, even with the sweep disabled
+    fn mutes(&self, timer_period: u16) -> bool {
+        timer_period < 8 || self.target_period(timer_period) > 0x7ff
+    }
+
+    fn clock(&mut self, timer_period: &mut u16) {
+        if self.divider == 0 && self.enabled && self.shift > 0 && !self.mutes(*timer_period) {
+            *timer_period = self.target_period(*timer_period);
+        }
+        if self.divider == 0 || self.reload {
+            self.divider = self.period;
+            self.reload = false;
+        } else {
+            self.divider -= 1;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    duty: u8,
+    envelope: Envelope,
+    sweep: Sweep,
+    length: LengthCounter,
+    timer_period: u16,
+    timer: u16,
+    sequence_step: usize,
+}
+
+impl Pulse {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => self.sweep.write(data),
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+                self.sequence_step = 0;
+            }
+            _ => {}
+        }
+    }
+
+    // clocked every APU cycle, steps the waveform each time the timer runs out
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_step = (self.sequence_step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.clock(&mut self.timer_period);
+    }
+
+    // periods under 8 would be ultrasonic, the hardware mutes them, as it
+    // does when the sweep would overflow the 11 bit period
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.sweep.mutes(self.timer_period) {
+            return 0;
+        }
+        DUTY_TABLE[self.duty as usize][self.sequence_step] * self.envelope.volume()
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    // halts the length counter and keeps reloading the linear counter
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    length: LengthCounter,
+    timer_period: u16,
+    timer: u16,
+    sequence_step: usize,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, data: u8) {
+        match register {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            3 => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                self.length.load(data >> 3);
+                self.linear_reload = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // clocked every CPU cycle, the sequence only moves while both counters run
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.linear_counter > 0 && self.length.active() {
+                self.sequence_step = (self.sequence_step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // the real DAC holds the last step when silenced, 0 avoids a DC offset
+    fn output(&self) -> u8 {
+        if self.linear_counter == 0 || !self.length.active() {
+            return 0;
+        }
+        TRIANGLE_SEQUENCE[self.sequence_step]
+    }
+}
+
+#[derive(Default)]
+struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    period: u16,
+    timer: u16,
+    // mode 1 taps bit 6 instead of bit 1, for a short metallic loop
+    short_mode: bool,
+    // 15 bit LFSR, it must never be 0
+    shift_register: u16,
+}
+
+impl Noise {
+    fn write(&mut self, register: u16, data: u8, periods: &[u16; 16]) {
+        match register {
+            0 => {
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = periods[(data & 0b1111) as usize];
+            }
+            3 => {
+                self.length.load(data >> 3);
+                self.envelope.start = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_shift_register(&mut self) {
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    // the periods are in CPU cycles, so this is clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period.saturating_sub(1);
+            self.clock_shift_register();
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+/// Delta modulated samples read from PRG memory one byte at a time. The bus
+/// does the fetches, `fetch_address` tells it when the buffer ran dry.
+#[derive(Default)]
+struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, data: u8, rates: &[u16; 16]) {
+        match register {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0b0100_0000 != 0;
+                self.period = rates[(data & 0b1111) as usize];
+            }
+            1 => self.output_level = data & 0b0111_1111,
+            2 => self.sample_address = 0xC000 + data as u16 * 64,
+            _ => self.sample_length = data as u16 * 16 + 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // $4015 bit 4, a new sample only starts once the last one has finished
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn fetch_address(&self) -> Option<u16> {
+        match (self.sample_buffer, self.bytes_remaining) {
+            (None, 1..) => Some(self.current_address),
+            _ => None,
+        }
+    }
+
+    // the timer empties the buffer into the shift register once the bits left
+    // are shifted out, a fetch is due from then on
+    fn cycles_until_fetch(&self) -> Option<u32> {
+        if self.bytes_remaining == 0 {
+            return None;
+        }
+        if self.sample_buffer.is_none() {
+            return Some(0);
+        }
+        let expiries = self.bits_remaining.max(1) as u32 - 1;
+        Some(self.timer as u32 + 1 + expiries * self.period as u32)
+    }
+
+    // the address wraps from $FFFF around to $8000
+    fn fill_buffer(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // moves the output level by 2 for each bit, 1 up and 0 down
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period.saturating_sub(1);
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        if self.bits_remaining > 0 {
+            self.bits_remaining -= 1;
+        }
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+// user side volume controls, each between 0.0 and 1.0 so nothing clips
+struct Mixer {
+    master_volume: f32,
+    channel_gains: [f32; 5],
+    muted: bool,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer {
+            master_volume: 1.0,
+            channel_gains: [1.0; 5],
+            muted: false,
+        }
+    }
+}
+
+/// The 2A03 sound channels and the frame sequencer clocking their envelopes
+/// and length counters. `$4000-$4013`, `$4015` and `$4017` land here.
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    tv_system: TvSystem,
+    mixer: Mixer,
+
+    // the pulse timers only run on every other CPU cycle
+    odd_cycle: bool,
+    five_step_mode: bool,
+    // set by the last 4-step sequencer step unless $4017 bit 6 inhibits it
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles since the frame sequencer last wrapped
+    frame_cycle: usize,
+    frame_step: usize,
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU::new_with_tv_system(TvSystem::default())
+    }
+
+    pub fn new_with_tv_system(tv_system: TvSystem) -> Self {
+        APU {
+            pulse1: Pulse {
+                sweep: Sweep {
+                    ones_complement: true,
+                    ..Sweep::default()
+                },
+                ..Pulse::default()
+            },
+            pulse2: Pulse::default(),
+            triangle: Triangle::default(),
+            noise: Noise {
+                shift_register: 1,
+                ..Noise::default()
+            },
+            dmc: Dmc {
+                silence: true,
+                ..Dmc::default()
+            },
+            tv_system,
+            mixer: Mixer::default(),
+            odd_cycle: false,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            frame_step: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(address - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(address - 0x4008, data),
+            0x400C..=0x400F => {
+                let periods = self.tv_system.noise_periods();
+                self.noise.write(address - 0x400C, data, periods)
+            }
+            0x4010..=0x4013 => {
+                let rates = self.tv_system.dmc_rates();
+                self.dmc.write(address - 0x4010, data, rates)
+            }
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & 0b0001 != 0);
+                self.pulse2.length.set_enabled(data & 0b0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0100 != 0);
+                self.noise.length.set_enabled(data & 0b1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step_mode = data & 0b1000_0000 != 0;
+                self.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                self.frame_step = 0;
+                // the 5-step mode clocks everything right away
+                if self.five_step_mode {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // bits 0-3 report which length counters are still running, bit 4 whether
+    // the DMC has bytes left, bit 6 the frame interrupt, which the read
+    // acknowledges, and bit 7 the DMC interrupt
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        for (bit, active) in [
+            self.pulse1.length.active(),
+            self.pulse2.length.active(),
+            self.triangle.length.active(),
+            self.noise.length.active(),
+        ]
+        .iter()
+        .enumerate()
+        {
+            if *active {
+                status |= 1 << bit;
+            }
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0b0001_0000;
+        }
+        if self.frame_irq {
+            status |= 0b0100_0000;
+            self.frame_irq = false;
+        }
+        if self.dmc.irq {
+            status |= 0b1000_0000;
+        }
+        status
+    }
+
+    /// The frame interrupt holds the CPU IRQ line until $4015 is read or
+    /// $4017 inhibits it, the DMC one until $4015 or $4010 is written.
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// Address of the next DMC sample byte, once the sample buffer is empty.
+    pub fn dmc_fetch_address(&self) -> Option<u16> {
+        self.dmc.fetch_address()
+    }
+
+    /// CPU cycles until `dmc_fetch_address` has an address, 0 when it already
+    /// does and None while no sample is playing.
+    pub fn dmc_cycles_until_fetch(&self) -> Option<u32> {
+        self.dmc.cycles_until_fetch()
+    }
+
+    pub fn fill_dmc_buffer(&mut self, data: u8) {
+        self.dmc.fill_buffer(data);
+    }
+
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.step();
+        }
+    }
+
+    /// Advances `cycles` APU cycles, two CPU cycles each.
+    pub fn clock(&mut self, cycles: usize) {
+        for _ in 0..cycles * 2 {
+            self.step();
+        }
+    }
+
+    /// Mixed level of the channels, between 0.0 and 1.0, using the nesdev
+    /// approximation of the non linear mixer.
+    pub fn current_output(&self) -> f32 {
+        if self.mixer.muted {
+            return 0.0;
+        }
+        let gains = &self.mixer.channel_gains;
+        let pulses = self.pulse1.output() as f32 * gains[Channel::Pulse1 as usize]
+            + self.pulse2.output() as f32 * gains[Channel::Pulse2 as usize];
+        let triangle = self.triangle.output() as f32 * gains[Channel::Triangle as usize];
+        let noise = self.noise.output() as f32 * gains[Channel::Noise as usize];
+        let dmc = self.dmc.output_level as f32 * gains[Channel::Dmc as usize];
+
+        let mut level = 0.0;
+        if pulses != 0.0 {
+            level += 95.88 / (8128.0 / pulses + 100.0);
+        }
+        let tnd = triangle / 8227.0 + noise / 12241.0 + dmc / 22638.0;
+        if tnd != 0.0 {
+            level += 159.79 / (1.0 / tnd + 100.0);
+        }
+        level * self.mixer.master_volume
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.mixer.master_volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.mixer.master_volume
+    }
+
+    /// Scales one channel before mixing.
+    pub fn set_channel_gain(&mut self, channel: Channel, gain: f32) {
+        self.mixer.channel_gains[channel as usize] = gain.clamp(0.0, 1.0);
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.mixer.muted = muted;
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.mixer.muted
+    }
+
+    /// Rate in Hz at which the noise shift register is clocked.
+    pub fn noise_frequency(&self) -> f64 {
+        if self.noise.period == 0 {
+            return 0.0;
+        }
+        self.tv_system.cpu_clock() as f64 / self.noise.period as f64
+    }
+
+    // one CPU cycle
+    fn step(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.odd_cycle = !self.odd_cycle;
+        self.step_frame_sequencer();
+    }
+
+    // 4-step: quarter frames on every step, half frames on 2 and 4
+    // 5-step: quarter frames on 1, 2, 3 and 5, half frames on 2 and 5
+    fn step_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+        let sequence = self.tv_system.frame_sequence(self.five_step_mode);
+        if self.frame_cycle != sequence[self.frame_step] {
+            return;
+        }
+
+        let last = sequence.len() - 1;
+        let step = self.frame_step;
+        if !(self.five_step_mode && step == 3) {
+            self.quarter_frame();
+        }
+        if step == 1 || step == last {
+            self.half_frame();
+        }
+
+        if step == last && !self.five_step_mode && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+
+        if step == last {
+            self.frame_cycle = 0;
+            self.frame_step = 0;
+        } else {
+            self.frame_step += 1;
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        APU::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timing::{NTSC_CPU_CLOCK, PAL_CPU_CLOCK};
+
+    fn run_frames(apu: &mut APU, frames: usize) {
+        for _ in 0..frames * FOUR_STEP_SEQUENCE[3] {
+            apu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_halted_length_counter_sustains() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0011);
+        // pulse 1 halted, pulse 2 not, both with a length of 10 half frames
+        apu.write_register(0x4000, 0b0011_1111);
+        apu.write_register(0x4004, 0b0001_1111);
+        apu.write_register(0x4003, 0);
+        apu.write_register(0x4007, 0);
+        assert_eq!(apu.read_status(), 0b0011);
+
+        run_frames(&mut apu, 4);
+        assert_eq!(apu.pulse2.length.value, 2);
+
+        run_frames(&mut apu, 1);
+        assert_eq!(apu.read_status() & 0b1111, 0b0001);
+        assert_eq!(apu.pulse1.length.value, 10);
+    }
+
+    #[test]
+    fn test_disabled_channel_ignores_length_loads() {
+        let mut apu = APU::new();
+        apu.write_register(0x400F, 0b1111_1000);
+        assert_eq!(apu.read_status(), 0);
+
+        apu.write_register(0x4015, 0b1000);
+        apu.write_register(0x400F, 0b0000_1000);
+        assert_eq!(apu.noise.length.value, 254);
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status(), 0);
+    }
+
+    #[test]
+    fn test_envelope_decays_and_loops() {
+        let mut envelope = Envelope::default();
+        envelope.write(0b0010_0000);
+        envelope.start = true;
+
+        envelope.clock();
+        assert_eq!(envelope.decay, 15);
+        for _ in 0..15 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.decay, 0);
+        envelope.clock();
+        assert_eq!(envelope.decay, 15);
+    }
+
+    #[test]
+    fn test_triangle_linear_counter_control() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0100);
+
+        // control clear: reloads once, then counts down to silence
+        apu.write_register(0x4008, 0x05);
+        apu.write_register(0x400B, 0);
+        for _ in 0..6 {
+            apu.quarter_frame();
+        }
+        assert_eq!(apu.triangle.linear_counter, 0);
+        assert!(!apu.triangle.linear_reload);
+
+        // control set: keeps reloading and the length counter is halted
+        apu.write_register(0x4008, 0x85);
+        apu.write_register(0x400B, 0);
+        run_frames(&mut apu, 10);
+        assert_eq!(apu.triangle.linear_counter, 5);
+        assert_eq!(apu.read_status() & 0b1111, 0b0100);
+    }
+
+    #[test]
+    fn test_pulse_duty_cycle_over_one_period() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0001);
+        // 50% duty, halted, constant volume 15, timer period 100
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 100);
+        apu.write_register(0x4003, 0);
+
+        let high = 95.88 / (8128.0 / 15.0 + 100.0);
+        let period = 8 * (100 + 1);
+        let mut samples = vec![];
+        for _ in 0..period {
+            apu.clock(1);
+            samples.push(apu.current_output());
+        }
+
+        assert!(samples.iter().all(|&level| level == 0.0 || level == high));
+        let high_samples = samples.iter().filter(|&&level| level == high).count();
+        assert_eq!(high_samples, period / 2);
+    }
+
+    #[test]
+    fn test_short_pulse_period_is_muted() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0001);
+        apu.write_register(0x4000, 0b1111_1111);
+        apu.write_register(0x4002, 7);
+        apu.write_register(0x4003, 0);
+
+        for _ in 0..64 {
+            apu.clock(1);
+            assert_eq!(apu.current_output(), 0.0);
+        }
+    }
+
+    #[test]
+    fn test_five_step_mode_clocks_immediately() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0001);
+        apu.write_register(0x4000, 0);
+        apu.write_register(0x4003, 0);
+
+        apu.write_register(0x4017, 0b1000_0000);
+        assert_eq!(apu.pulse1.length.value, 9);
+    }
+
+    #[test]
+    fn test_pal_noise_runs_at_pal_frequency() {
+        let mut ntsc = APU::new();
+        let mut pal = APU::new_with_tv_system(TvSystem::Pal);
+        ntsc.write_register(0x400E, 2);
+        pal.write_register(0x400E, 2);
+
+        assert_eq!(ntsc.noise_frequency(), NTSC_CPU_CLOCK as f64 / 16.0);
+        assert_eq!(pal.noise_frequency(), PAL_CPU_CLOCK as f64 / 14.0);
+    }
+
+    #[test]
+    fn test_pal_frame_sequencer_is_slower() {
+        let mut apu = APU::new_with_tv_system(TvSystem::Pal);
+        apu.write_register(0x4015, 0b0001);
+        apu.write_register(0x4003, 0);
+
+        // 16200 cycles, past the NTSC half frame but before the PAL one
+        for _ in 0..81 {
+            apu.tick(200);
+        }
+        assert_eq!(apu.pulse1.length.value, 10);
+
+        for _ in 0..4 {
+            apu.tick(200);
+        }
+        assert_eq!(apu.pulse1.length.value, 9);
+    }
+
+    // pulse 1 at constant volume 15 and 50% duty, clocked until it is high
+    fn sounding_apu() -> APU {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 100);
+        apu.write_register(0x4003, 0);
+        while apu.current_output() == 0.0 {
+            apu.clock(1);
+        }
+        apu
+    }
+
+    #[test]
+    fn test_master_volume_scales_mixed_output() {
+        let mut apu = sounding_apu();
+        let full = apu.current_output();
+
+        apu.set_master_volume(0.5);
+        assert_eq!(apu.current_output(), full / 2.0);
+
+        apu.set_master_volume(3.0);
+        assert_eq!(apu.master_volume(), 1.0);
+
+        apu.set_muted(true);
+        assert_eq!(apu.current_output(), 0.0);
+    }
+
+    #[test]
+    fn test_channel_gain_scales_before_mixing() {
+        let mut apu = sounding_apu();
+        apu.set_channel_gain(Channel::Pulse1, 0.0);
+        assert_eq!(apu.current_output(), 0.0);
+
+        apu.set_channel_gain(Channel::Pulse1, 1.0 / 3.0);
+        assert_eq!(apu.current_output(), 95.88 / (8128.0 / 5.0 + 100.0));
+    }
+
+    #[test]
+    fn test_frame_irq_until_status_read() {
+        let mut apu = APU::new();
+        run_frames(&mut apu, 1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4017, 0b0100_0000);
+        run_frames(&mut apu, 1);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_sweep_bends_period_and_mutes_on_overflow() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0011);
+        // both pulses at period 0x100, sweeping down by 1/2 every half frame
+        for base in [0x4000, 0x4004] {
+            apu.write_register(base, 0b1011_1111);
+            apu.write_register(base + 1, 0b1000_1001);
+            apu.write_register(base + 2, 0x00);
+            apu.write_register(base + 3, 0x01);
+        }
+        apu.half_frame();
+        assert_eq!(apu.pulse1.timer_period, 0x80 - 1);
+        assert_eq!(apu.pulse2.timer_period, 0x80);
+
+        // from 0x600 the target overflows, which silences even a disabled sweep
+        apu.write_register(0x4005, 0b0000_0001);
+        apu.write_register(0x4006, 0x00);
+        apu.write_register(0x4007, 0x06);
+        assert!(apu.pulse2.sweep.mutes(apu.pulse2.timer_period));
+        apu.half_frame();
+        assert_eq!(apu.pulse2.timer_period, 0x600);
+        assert_eq!(apu.pulse2.output(), 0);
+    }
+
+    #[test]
+    fn test_triangle_steps_through_sequence() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0100);
+        apu.write_register(0x4008, 0x85);
+        apu.write_register(0x400A, 3);
+        apu.write_register(0x400B, 0);
+        apu.quarter_frame();
+
+        // one step every 4 CPU cycles
+        let mut steps = vec![];
+        for _ in 0..32 {
+            steps.push(apu.triangle.output());
+            apu.tick(4);
+        }
+        assert_eq!(steps, TRIANGLE_SEQUENCE.to_vec());
+        assert_eq!(apu.triangle.output(), 15);
+
+        // a silenced linear counter freezes the sequence
+        apu.write_register(0x4008, 0x00);
+        apu.write_register(0x400B, 0);
+        apu.quarter_frame();
+        apu.quarter_frame();
+        let step = apu.triangle.sequence_step;
+        apu.tick(64);
+        assert_eq!(apu.triangle.sequence_step, step);
+        assert_eq!(apu.triangle.output(), 0);
+    }
+
+    // clocks of the shift register until it is back to its starting value
+    fn lfsr_period(short_mode: bool) -> usize {
+        let mut noise = Noise {
+            short_mode,
+            shift_register: 1,
+            ..Noise::default()
+        };
+        let mut clocks = 0;
+        loop {
+            noise.clock_shift_register();
+            clocks += 1;
+            if noise.shift_register == 1 {
+                return clocks;
+            }
+        }
+    }
+
+    #[test]
+    fn test_noise_lfsr_periods() {
+        assert_eq!(lfsr_period(false), 32767);
+        assert_eq!(lfsr_period(true), 93);
+
+        // period index 4 is 64 CPU cycles between shifts
+        let mut apu = APU::new();
+        apu.write_register(0x400E, 0x04);
+        apu.tick(1);
+        let after_first = apu.noise.shift_register;
+        apu.tick(63);
+        assert_eq!(apu.noise.shift_register, after_first);
+        apu.tick(1);
+        assert_ne!(apu.noise.shift_register, after_first);
+    }
+
+    #[test]
+    fn test_triangle_and_noise_are_mixed() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b1100);
+        // noise at constant volume 15, silent while bit 0 of the LFSR is set
+        apu.write_register(0x400C, 0b0011_1111);
+        apu.write_register(0x400F, 0);
+        apu.noise.shift_register = 2;
+        assert_eq!(
+            apu.current_output(),
+            159.79 / (1.0 / (15.0 / 12241.0) + 100.0)
+        );
+
+        apu.noise.shift_register = 1;
+        assert_eq!(apu.current_output(), 0.0);
+        assert_eq!(apu.read_status() & 0b1111, 0b1000);
+    }
+
+    #[test]
+    fn test_dmc_plays_sample_bits() {
+        let mut apu = APU::new();
+        // fastest rate, from $C040 for 17 bytes
+        apu.write_register(0x4010, 0x0F);
+        apu.write_register(0x4011, 0x40);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.dmc_fetch_address(), Some(0xC040));
+        assert_eq!(apu.read_status() & 0b1_0000, 0b1_0000);
+
+        apu.fill_dmc_buffer(0b0000_0111);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        // the first output clock only takes the buffered byte
+        apu.tick(1);
+        assert_eq!(apu.dmc.output_level, 0x40);
+        assert_eq!(apu.dmc_fetch_address(), Some(0xC041));
+
+        let mut levels = vec![];
+        for _ in 0..8 {
+            apu.tick(54);
+            levels.push(apu.dmc.output_level);
+        }
+        assert_eq!(levels, vec![0x42, 0x44, 0x46, 0x44, 0x42, 0x40, 0x3E, 0x3C]);
+    }
+
+    #[test]
+    fn test_dmc_cycles_until_fetch() {
+        let mut apu = APU::new();
+        apu.write_register(0x4010, 0x0F);
+        assert_eq!(apu.dmc_cycles_until_fetch(), None);
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.dmc_cycles_until_fetch(), Some(0));
+
+        apu.fill_dmc_buffer(0);
+        apu.tick(1);
+        apu.fill_dmc_buffer(0);
+        // 8 bits of 54 cycles, the first one already started
+        assert_eq!(apu.dmc_cycles_until_fetch(), Some(8 * 54));
+        for _ in 0..7 {
+            apu.tick(54);
+        }
+        apu.tick(53);
+        assert_eq!(apu.dmc_fetch_address(), None);
+        apu.tick(1);
+        assert!(apu.dmc_fetch_address().is_some());
+    }
+
+    #[test]
+    fn test_dmc_irq_at_sample_end() {
+        let mut apu = APU::new();
+        apu.write_register(0x4010, 0x80);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0b1_0000);
+        apu.fill_dmc_buffer(0);
+
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b1001_0000, 0b1000_0000);
+        // reading doesn't acknowledge it, writing $4015 does
+        assert!(apu.irq_pending());
+        apu.write_register(0x4015, 0);
+        assert!(!apu.irq_pending());
+    }
+}
@@ -1,4 +1,4 @@
-use super::cpu::{AddressingMode, CPU};
+use super::cpu::{AddressingMode, CpuError, CPU};
 use std::collections::HashMap;
 
 pub struct OpCode {
@@ -306,18 +306,18 @@ lazy_static! {
         OpCode::new(0xe3, "*ISB", 2,8, AddressingMode::IndirectX),
         OpCode::new(0xf3, "*ISB", 2,8, AddressingMode::IndirectY),
 
-        OpCode::new(0x02, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x12, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x22, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x32, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x42, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x52, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x62, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x72, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0x92, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xb2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xd2, "*NOP", 1,2, AddressingMode::NoneAddressing),
-        OpCode::new(0xf2, "*NOP", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x02, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x12, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x22, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x32, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x42, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x52, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x62, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x72, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0x92, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xb2, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xd2, "*JAM", 1,2, AddressingMode::NoneAddressing),
+        OpCode::new(0xf2, "*JAM", 1,2, AddressingMode::NoneAddressing),
 
         OpCode::new(0x1a, "*NOP", 1,2, AddressingMode::NoneAddressing),
         OpCode::new(0x3a, "*NOP", 1,2, AddressingMode::NoneAddressing),
@@ -358,6 +358,9 @@ lazy_static! {
         }
         map
     };
+
+    // shared by every CPU, building one copies the whole opcode map
+    pub static ref ASSEMBLER: Assembler = Assembler::new();
 }
 
 pub struct Assembler {
@@ -371,12 +374,12 @@ impl Assembler {
         }
     }
 
-    pub fn interpret(&self, cpu: &mut CPU, code: u8) -> bool {
+    pub fn interpret(&self, cpu: &mut CPU, code: u8) -> Result<bool, CpuError> {
         let pc_state = cpu.register_pc;
         let opcode = self
             .opcodes
             .get(&code)
-            .expect(&format!("OpCode {:x} is not recognized", code));
+            .ok_or(CpuError::UnknownOpcode(code, pc_state.wrapping_sub(1)))?;
 
         match code {
             /* ADC */
@@ -413,7 +416,13 @@ impl Assembler {
 
             /* BPL */ 0x10 => cpu.bpl(),
 
-            /* BRK */ 0x00 => return true,
+            /* BRK */
+            0x00 => {
+                if cpu.brk_ends_run {
+                    return Ok(true);
+                }
+                cpu.brk();
+            }
 
             /* BVC */ 0x50 => cpu.bvc(),
 
@@ -624,9 +633,13 @@ impl Assembler {
                 cpu.isb(&opcode.mode);
             }
 
+            /* JAM */
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2 => {
+                cpu.jam();
+            }
+
             /* NOPs */
-            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92 | 0xb2 | 0xd2 | 0xf2
-            | 0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {}
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa => {}
 
             /* LAX */
             0xa7 | 0xb7 | 0xaf | 0xbf | 0xa3 | 0xb3 => {
@@ -664,6 +677,40 @@ impl Assembler {
         }
 
         cpu.update_pc(&opcode, pc_state);
-        false
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::bus::BUS;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::cpu::CpuError;
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+
+    #[test]
+    fn test_unknown_opcode_is_an_error() {
+        let mut cpu = CPU::new(
+            BUS::new(
+                test_rom(),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        let mut assembler = Assembler::new();
+        assembler.opcodes.remove(&0x02);
+
+        // the PC is already past the opcode when it gets interpreted
+        cpu.register_pc = 0x0601;
+        assert_eq!(
+            assembler.interpret(&mut cpu, 0x02),
+            Err(CpuError::UnknownOpcode(0x02, 0x0600))
+        );
+        assert_eq!(
+            CpuError::UnknownOpcode(0x02, 0x0600).to_string(),
+            "OpCode 2 at 0600 is not recognized"
+        );
     }
 }
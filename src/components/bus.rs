@@ -1,6 +1,11 @@
-use super::cartridge::Rom;
+use super::apu::{TvSystem, APU};
+use super::cartridge::{Rom, TRAINER_SIZE};
 use super::joypads::Joypad;
+use super::mappers::{self, SharedMapper};
 use super::ppu::PPU;
+use crate::audio::{AudioConfig, AudioSink, Resampler};
+use std::ops::RangeInclusive;
+use std::path::Path;
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -29,81 +34,395 @@ use super::ppu::PPU;
 // |_ _ _ _ _ _ _ _| $0100 |               |
 // | Zero Page     |       |               |
 // |_______________| $0000 |_______________|
-const RAM: u16 = 0x0000;
-const RAM_MIRRORS_END: u16 = 0x1FFF;
-const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const JOYPAD_OPEN_BUS: u8 = 0x40;
+// CPU cycles lost to each DMC sample fetch. The hardware takes 3 or 4 cycles
+// depending on where the halt lands, and more behind a write, this is always
+// the usual 4: halt, dummy, alignment and the read.
+const DMC_DMA_CYCLES: u8 = 4;
+
+/// Devices on the CPU bus, see `MEMORY_MAP` for the addresses each one owns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ram,
+    PpuRegisters,
+    Apu,
+    OamDma,
+    Joypad1,
+    // reads come from the second controller, writes go to the APU frame counter
+    Joypad2,
+    PrgRam,
+    Cartridge,
+    Unmapped,
+}
+
+const MEMORY_MAP: [(RangeInclusive<u16>, Region); 9] = [
+    (0x0000..=0x1FFF, Region::Ram),
+    (0x2000..=0x3FFF, Region::PpuRegisters),
+    (0x4000..=0x4013, Region::Apu),
+    (0x4014..=0x4014, Region::OamDma),
+    (0x4015..=0x4015, Region::Apu),
+    (0x4016..=0x4016, Region::Joypad1),
+    (0x4017..=0x4017, Region::Joypad2),
+    (0x6000..=0x7FFF, Region::PrgRam),
+    (0x8000..=0xFFFF, Region::Cartridge),
+];
+
+// the trainer sits in PRG-RAM at 0x7000..=0x71FF
+const TRAINER_START: u16 = 0x7000;
+
+pub fn region(address: u16) -> Region {
+    MEMORY_MAP
+        .iter()
+        .find(|(range, _)| range.contains(&address))
+        .map_or(Region::Unmapped, |(_, region)| *region)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UninitializedRead {
+    pub pc: u16,
+    pub address: u16,
+}
+
+// remembers which RAM bytes were written since power-on
+struct RamTracker {
+    written: [bool; 2048],
+    pc: u16,
+    reads: Vec<UninitializedRead>,
+}
+
+type GameloopCallback<'call> = Box<dyn FnMut(&PPU, &mut Joypad, &mut Joypad) + 'call>;
+type ScanlineCallback<'call> = Box<dyn FnMut(u16, &PPU) + 'call>;
+type ReadWatch<'call> = Box<dyn FnMut(u16, u8) + 'call>;
+type WriteWatch<'call> = Box<dyn FnMut(u16, u8, u8) + 'call>;
+
+// RAM and the PPU registers answer on many addresses, watches see the
+// address they mirror down to
+fn mirror_down(address: u16) -> u16 {
+    match region(address) {
+        Region::Ram => address & 0b00000111_11111111,
+        Region::PpuRegisters => address & 0b00100000_00000111,
+        _ => address,
+    }
+}
 
 pub struct BUS<'call> {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    mapper: SharedMapper,
+    mapper_number: u8,
+    battery: bool,
+    // copied over the PRG-RAM again when a save is loaded
+    trainer: Option<[u8; TRAINER_SIZE]>,
     ppu: PPU,
+    apu: APU,
+    tv_system: TvSystem,
 
     cycles: usize,
-    gameloop_callback: Box<dyn FnMut(&PPU, &mut Joypad) + 'call>,
+    gameloop_callback: GameloopCallback<'call>,
+    scanline_callback: Option<ScanlineCallback<'call>>,
     joypad1: Joypad,
+    joypad2: Joypad,
+    ram_tracker: Option<RamTracker>,
+    /// Models the DMC DMA glitch that clocks the controllers an extra time.
+    pub accurate_dmc_conflicts: bool,
+    dmc_dma_pending: bool,
+    // the CPU ticks the bus once an instruction is done, these place the reads
+    // in between so a DMC fetch can halt the one it falls on
+    reads_since_tick: u8,
+    ticked_ahead: u8,
+    writes: u64,
+    read_watches: Vec<(RangeInclusive<u16>, ReadWatch<'call>)>,
+    write_watches: Vec<(RangeInclusive<u16>, WriteWatch<'call>)>,
+    audio_sink: Option<(Box<dyn AudioSink + 'call>, Resampler)>,
 }
 
 impl<'a> BUS<'a> {
-    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> BUS<'call>
+    /// Fails when the cartridge's mapper is not supported.
+    pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Result<BUS<'call>, String>
+    where
+        F: FnMut(&PPU, &mut Joypad, &mut Joypad) + 'call,
+    {
+        BUS::new_with_tv_system(rom, TvSystem::default(), gameloop_callback)
+    }
+
+    /// A console of the given TV system, which sets the APU tables and the
+    /// clock the audio is resampled from.
+    pub fn new_with_tv_system<'call, F>(
+        rom: Rom,
+        tv_system: TvSystem,
+        gameloop_callback: F,
+    ) -> Result<BUS<'call>, String>
     where
-        F: FnMut(&PPU, &mut Joypad) + 'call,
+        F: FnMut(&PPU, &mut Joypad, &mut Joypad) + 'call,
     {
-        let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        let mapper_number = rom.mapper;
+        let trainer = rom.trainer;
+        let battery = rom.has_battery();
+        let mapper = mappers::create(rom)?;
+        let ppu = PPU::new_with_mapper(mapper.clone());
 
-        BUS {
+        let mut bus = BUS {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
-            ppu: ppu,
+            mapper,
+            mapper_number,
+            battery,
+            trainer,
+            ppu,
+            apu: APU::new_with_tv_system(tv_system),
+            tv_system,
             cycles: 0,
             gameloop_callback: Box::from(gameloop_callback),
+            scanline_callback: None,
             joypad1: Joypad::new(),
+            joypad2: Joypad::new(),
+            ram_tracker: None,
+            accurate_dmc_conflicts: false,
+            dmc_dma_pending: false,
+            reads_since_tick: 0,
+            ticked_ahead: 0,
+            writes: 0,
+            read_watches: vec![],
+            write_watches: vec![],
+            audio_sink: None,
+        };
+        bus.joypad1.set_tv_system(tv_system);
+        bus.joypad2.set_tv_system(tv_system);
+        bus.load_trainer();
+        Ok(bus)
+    }
+
+    fn load_trainer(&self) {
+        if let Some(trainer) = &self.trainer {
+            let mut mapper = self.mapper.borrow_mut();
+            for (offset, &data) in trainer.iter().enumerate() {
+                mapper.prg_ram_write(TRAINER_START + offset as u16, data);
+            }
+        }
+    }
+
+    /// Writes the PRG-RAM to `path`, only for cartridges with a battery.
+    pub fn save_sram(&self, path: &Path) -> Result<(), String> {
+        if !self.battery {
+            return Ok(());
+        }
+        match self.mapper.borrow_mut().prg_ram() {
+            Some(ram) => std::fs::write(path, ram.data()).map_err(|err| err.to_string()),
+            None => Ok(()),
         }
     }
 
+    /// Restores the PRG-RAM saved by `save_sram`, a missing file is a new save.
+    /// The trainer is copied over it again, as it is at power on.
+    pub fn load_sram(&mut self, path: &Path) -> Result<(), String> {
+        if !self.battery || !path.exists() {
+            return Ok(());
+        }
+        let data = std::fs::read(path).map_err(|err| err.to_string())?;
+        if let Some(ram) = self.mapper.borrow_mut().prg_ram() {
+            ram.load(&data)?;
+        }
+        self.load_trainer();
+        Ok(())
+    }
+
+    /// Feeds the APU output to `sink`, resampled to `config.sample_rate`.
+    pub fn set_audio_sink(&mut self, sink: Box<dyn AudioSink + 'a>, config: AudioConfig) {
+        let resampler = Resampler::new(config, self.tv_system.cpu_clock());
+        self.audio_sink = Some((sink, resampler));
+    }
+
+    /// Calls `watch` with the address and value of every CPU read in `range`.
+    pub fn add_read_watch(&mut self, range: RangeInclusive<u16>, watch: ReadWatch<'a>) {
+        self.read_watches.push((range, watch));
+    }
+
+    /// Calls `watch` with the address, the old and the new value of every CPU
+    /// write in `range`.
+    pub fn add_write_watch(&mut self, range: RangeInclusive<u16>, watch: WriteWatch<'a>) {
+        self.write_watches.push((range, watch));
+    }
+
     pub fn memory_read(&mut self, address: u16) -> u8 {
-        match address {
-            RAM..=RAM_MIRRORS_END => {
-                let mirror_down_address = address & 0b00000111_11111111;
-                self.cpu_vram[mirror_down_address as usize]
+        self.service_dmc_fetch();
+        let value = self.read_device(address);
+        // a DMC fetch only collides with the read it halted
+        self.dmc_dma_pending = false;
+        self.reads_since_tick = self.reads_since_tick.saturating_add(1);
+        if !self.read_watches.is_empty() {
+            let address = mirror_down(address);
+            for (range, watch) in self.read_watches.iter_mut() {
+                if range.contains(&address) {
+                    watch(address, value);
+                }
             }
-            0x2000 | 0x2001 | 0x2003 | 0x2005 | 0x2006 | 0x4014 => {
-                // panic!("Attempt to read from write-only PPU address {:x}", address);
+        }
+        value
+    }
+
+    fn read_device(&mut self, address: u16) -> u8 {
+        match region(address) {
+            Region::Ram => self.read_ram(address),
+            Region::PpuRegisters => self.read_ppu_register(address),
+            Region::Apu if address == 0x4015 => self.apu.read_status(),
+            Region::Apu | Region::OamDma => {
+                // the channel registers and OAMDMA are write-only
                 0
             }
-            0x2002 => self.ppu.read_status(),
-            0x2004 => self.ppu.read_oam_data(),
-            0x2007 => self.ppu.read_data(),
 
-            0x4000..=0x4015 => {
-                //ignore APU
-                0
+            // only bit 0 is driven by the controller, the rest is open bus
+            // and holds the high byte of the address
+            Region::Joypad1 => {
+                if self.take_dmc_conflict() {
+                    self.joypad1.read();
+                }
+                JOYPAD_OPEN_BUS | self.joypad1.read()
             }
 
-            0x4016 => self.joypad1.read(),
+            Region::Joypad2 => {
+                if self.take_dmc_conflict() {
+                    self.joypad2.read();
+                }
+                JOYPAD_OPEN_BUS | self.joypad2.read()
+            }
+            Region::PrgRam => self.read_prg_ram(address),
+            Region::Cartridge => self.mapper.borrow().cpu_read(address),
 
-            0x4017 => {
-                // ignore joypad 2
+            Region::Unmapped => {
+                println!("Ignoring memory access at {:x}", address);
                 0
             }
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_address = address & 0b00100000_00000111;
-                self.memory_read(mirror_down_address)
+        }
+    }
+
+    /// Reads memory for debugging tools, without the side effects a CPU read
+    /// has on PPU and controller registers. Registers that cannot be read
+    /// without changing state return 0.
+    pub fn peek(&self, address: u16) -> u8 {
+        match region(address) {
+            Region::Ram => self.cpu_vram[(address & 0b00000111_11111111) as usize],
+            Region::PpuRegisters => match address & 0b00100000_00000111 {
+                0x2002 => self.ppu.peek_status(),
+                0x2004 => self.ppu.read_oam_data(),
+                _ => 0,
+            },
+            Region::PrgRam => self.read_prg_ram(address),
+            Region::Cartridge => self.mapper.borrow().cpu_read(address),
+            _ => 0,
+        }
+    }
+
+    pub fn memory_write(&mut self, address: u16, data: u8) {
+        self.writes += 1;
+        if self.write_watches.is_empty() {
+            self.write_device(address, data);
+            return;
+        }
+
+        let old = self.peek(address);
+        self.write_device(address, data);
+        let address = mirror_down(address);
+        for (range, watch) in self.write_watches.iter_mut() {
+            if range.contains(&address) {
+                watch(address, old, data);
             }
-            0x8000..=0xFFFF => self.read_prg_rom(address),
+        }
+    }
+
+    fn write_device(&mut self, address: u16, data: u8) {
+        match region(address) {
+            Region::Ram => self.write_ram(address, data),
+            Region::PpuRegisters => self.write_ppu_register(address, data),
+            Region::Apu => self.apu.write_register(address, data),
+            Region::OamDma => self.oam_dma(data),
+
+            // the strobe line is shared by both controller ports
+            Region::Joypad1 => {
+                self.joypad1.write(data);
+                self.joypad2.write(data);
+            }
+
+            Region::Joypad2 => self.apu.write_register(address, data),
+            Region::PrgRam => self.mapper.borrow_mut().prg_ram_write(address, data),
+            Region::Cartridge => {
+                self.mapper.borrow_mut().cpu_write(address, data);
+                self.ppu.chr_dirty.mark_all();
+            }
+
+            Region::Unmapped => {
+                println!("Ignoring memory write-access at {:x}", address);
+            }
+        }
+    }
+
+    /// Cartridge side of a save state: work RAM and the mapper registers,
+    /// tagged with the mapper number so a state from another board is rejected.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = vec![self.mapper_number];
+        state.extend_from_slice(&self.cpu_vram);
+        state.extend(self.mapper.borrow().serialize());
+        state
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let ram_end = 1 + self.cpu_vram.len();
+        if state.len() < ram_end {
+            return Err(format!("State has {} bytes", state.len()));
+        }
+        if state[0] != self.mapper_number {
+            return Err(format!(
+                "State is for mapper {}, cartridge uses mapper {}",
+                state[0], self.mapper_number
+            ));
+        }
+
+        self.mapper.borrow_mut().deserialize(&state[ram_end..])?;
+        self.cpu_vram.copy_from_slice(&state[1..ram_end]);
+        Ok(())
+    }
+
+    // missing or disabled PRG-RAM leaves the high byte of the address on the bus
+    fn read_prg_ram(&self, address: u16) -> u8 {
+        self.mapper
+            .borrow()
+            .prg_ram_read(address)
+            .unwrap_or((address >> 8) as u8)
+    }
 
+    fn read_ram(&mut self, address: u16) -> u8 {
+        let mirror_down_address = address & 0b00000111_11111111;
+        if let Some(tracker) = self.ram_tracker.as_mut() {
+            if !tracker.written[mirror_down_address as usize] {
+                tracker.reads.push(UninitializedRead {
+                    pc: tracker.pc,
+                    address: mirror_down_address,
+                });
+            }
+        }
+        self.cpu_vram[mirror_down_address as usize]
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        let mirror_down_address = address & 0b11111111111;
+        if let Some(tracker) = self.ram_tracker.as_mut() {
+            tracker.written[mirror_down_address as usize] = true;
+        }
+        self.cpu_vram[mirror_down_address as usize] = data;
+    }
+
+    // the 8 registers repeat every 8 bytes up to 0x3FFF
+    fn read_ppu_register(&mut self, address: u16) -> u8 {
+        match address & 0b00100000_00000111 {
+            0x2002 => self.ppu.read_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.read_data(),
             _ => {
-                println!("Ignoring memory access at {:x}", address);
+                // panic!("Attempt to read from write-only PPU address {:x}", address);
                 0
             }
         }
     }
 
-    pub fn memory_write(&mut self, address: u16, data: u8) {
-        match address {
-            RAM..=RAM_MIRRORS_END => {
-                let mirror_down_address = address & 0b11111111111;
-                self.cpu_vram[mirror_down_address as usize] = data;
-            }
+    fn write_ppu_register(&mut self, address: u16, data: u8) {
+        match address & 0b00100000_00000111 {
             0x2000 => {
                 self.ppu.write_to_control(data);
             }
@@ -129,41 +448,22 @@ impl<'a> BUS<'a> {
             0x2007 => {
                 self.ppu.write_to_data(data);
             }
-            0x4000..=0x4013 | 0x4015 => {
-                //ignore APU
-            }
-
-            0x4016 => self.joypad1.write(data),
-
-            0x4017 => {
-                // ignore joypad 2
-            }
-
-            0x4014 => {
-                let mut buffer: [u8; 256] = [0; 256];
-                let hi: u16 = (data as u16) << 8;
-                for i in 0..256u16 {
-                    buffer[i as usize] = self.memory_read(hi + i);
-                }
-
-                self.ppu.write_oam_dma(&buffer);
+            _ => unreachable!(),
+        }
+    }
 
-                // todo: handle this eventually
-                // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
-                // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
-            }
+    fn oam_dma(&mut self, page: u8) {
+        // any page can be the source, the bytes go through the normal read path
+        let mut buffer: [u8; 256] = [0; 256];
+        for (low, byte) in buffer.iter_mut().enumerate() {
+            *byte = self.memory_read(u16::from_be_bytes([page, low as u8]));
+        }
 
-            0x2008..=PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_address = address & 0b00100000_00000111;
-                self.memory_write(mirror_down_address, data);
-                // todo!("PPU is not supported yet");
-            }
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", address),
+        self.ppu.write_oam_dma(&buffer);
 
-            _ => {
-                println!("Ignoring memory write-access at {:x}", address);
-            }
-        }
+        // todo: handle this eventually
+        // let add_cycles: u16 = if self.cycles % 2 == 1 { 514 } else { 513 };
+        // self.tick(add_cycles); //todo this will cause weird effects as PPU will have 513/514 * 3 ticks
     }
 
     pub fn memory_read_u16(&mut self, address: u16) -> u16 {
@@ -181,37 +481,745 @@ impl<'a> BUS<'a> {
         self.memory_write(pos + 1, high);
     }
 
-    fn read_prg_rom(&self, mut address: u16) -> u8 {
-        address -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && address >= 0x4000 {
-            //mirror if needed
-            address = address % 0x4000;
-        }
-        self.prg_rom[address as usize]
+    /// Replaces the per frame callback, starting with the next frame.
+    pub fn set_callback<F>(&mut self, gameloop_callback: F)
+    where
+        F: FnMut(&PPU, &mut Joypad, &mut Joypad) + 'a,
+    {
+        self.gameloop_callback = Box::from(gameloop_callback);
+    }
+
+    /// Called with the number of each scanline the PPU finishes.
+    pub fn set_scanline_callback<F>(&mut self, scanline_callback: F)
+    where
+        F: FnMut(u16, &PPU) + 'a,
+    {
+        self.scanline_callback = Some(Box::from(scanline_callback));
     }
 
     pub fn tick(&mut self, cycles: u8) {
+        // cycles already run for a DMC fetch in the middle of the instruction
+        let early = cycles.min(self.ticked_ahead);
+        self.ticked_ahead -= early;
+        self.reads_since_tick = 0;
+        if cycles > early {
+            self.advance(cycles - early);
+        }
+    }
+
+    // each read of an instruction is taken to be one cycle after the last, so
+    // a DMC fetch falling due before this read is run up to and serviced here
+    fn service_dmc_fetch(&mut self) {
+        let lag = self.reads_since_tick.saturating_sub(self.ticked_ahead);
+        match self.apu.dmc_cycles_until_fetch() {
+            Some(cycles) if cycles <= lag as u32 => {
+                if lag > 0 {
+                    self.ticked_ahead += lag;
+                    self.advance(lag);
+                }
+                self.dmc_fetch();
+            }
+            _ => {}
+        }
+    }
+
+    fn advance(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
+        let scanline = self.ppu.scanline;
         let new_frame = self.ppu.tick(cycles * 3);
+        self.apu.tick(cycles);
+        if let Some((sink, resampler)) = self.audio_sink.as_mut() {
+            resampler.push(self.apu.current_output(), cycles as usize);
+            for sample in resampler.take_samples() {
+                sink.push_sample(sample);
+            }
+        }
+        if self.ppu.scanline != scanline {
+            // visible lines and the pre-render line fetch sprite patterns
+            if (scanline < 240 || scanline == 261) && self.ppu.rendering_enabled() {
+                self.mapper.borrow_mut().clock_scanline();
+            }
+            self.ppu.render_scanline(scanline);
+            if let Some(callback) = self.scanline_callback.as_mut() {
+                callback(scanline, &self.ppu);
+            }
+        }
         if new_frame {
-            (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
+            (self.gameloop_callback)(&self.ppu, &mut self.joypad1, &mut self.joypad2);
+        }
+
+        self.dmc_fetch();
+    }
+
+    // the CPU is halted while the DMC fetches its next sample byte
+    fn dmc_fetch(&mut self) {
+        if let Some(address) = self.apu.dmc_fetch_address() {
+            let data = self.peek(address);
+            self.apu.fill_dmc_buffer(data);
+            self.schedule_dmc_dma();
+            self.advance(DMC_DMA_CYCLES);
+        }
+    }
+
+    /// Number of CPU writes so far, to any address.
+    pub fn write_count(&self) -> u64 {
+        self.writes
+    }
+
+    /// CPU cycles elapsed since power on.
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    pub fn ram(&self) -> &[u8; 2048] {
+        &self.cpu_vram
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    // for setting up input before the first frame callback runs
+    pub fn joypad1_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad1
+    }
+
+    pub fn joypad2_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad2
+    }
+
+    /// Marks a DMC sample fetch as landing on the next CPU read.
+    pub fn schedule_dmc_dma(&mut self) {
+        self.dmc_dma_pending = true;
+    }
+
+    // the halted CPU repeats its read, so a controller read sees one extra clock
+    fn take_dmc_conflict(&mut self) -> bool {
+        let pending = self.dmc_dma_pending;
+        self.dmc_dma_pending = false;
+        pending && self.accurate_dmc_conflicts
+    }
+
+    /// Starts collecting reads of RAM bytes that were never written, see
+    /// `uninitialized_reads`. Only writes made after this call count as
+    /// initialization.
+    pub fn track_uninitialized_ram(&mut self) {
+        self.ram_tracker = Some(RamTracker {
+            written: [false; 2048],
+            pc: 0,
+            reads: vec![],
+        });
+    }
+
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead] {
+        self.ram_tracker
+            .as_ref()
+            .map_or(&[], |tracker| tracker.reads.as_slice())
+    }
+
+    // lets the tracker attribute reads to the instruction being executed
+    pub fn set_current_pc(&mut self, pc: u16) {
+        if let Some(tracker) = self.ram_tracker.as_mut() {
+            tracker.pc = pc;
         }
     }
 
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_interrupt()
     }
+
+    // level triggered, stays set until the source is acknowledged
+    pub fn poll_irq_status(&self) -> bool {
+        self.apu.irq_pending() || self.mapper.borrow().irq_pending()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::components::cartridge::test;
+    use crate::components::cartridge::{test, Mirroring};
+    use crate::components::joypads::JoypadButton;
+    use crate::timing::{NTSC_CPU_CLOCK, PAL_CPU_CLOCK};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    fn test_bus(rom: Rom) -> BUS<'static> {
+        BUS::new(
+            rom,
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap()
+    }
 
     #[test]
     fn test_memory_read_write_to_ram() {
-        let mut bus = BUS::new(test::test_rom(), |_ppu: &PPU, _joypad: &mut Joypad| {});
+        let mut bus = test_bus(test::test_rom());
         bus.memory_write(0x01, 0x55);
         assert_eq!(bus.memory_read(0x01), 0x55);
     }
+
+    #[test]
+    fn test_memory_map_regions() {
+        assert_eq!(region(0x0000), Region::Ram);
+        assert_eq!(region(0x1FFF), Region::Ram);
+        assert_eq!(region(0x2000), Region::PpuRegisters);
+        assert_eq!(region(0x3FFF), Region::PpuRegisters);
+        assert_eq!(region(0x4000), Region::Apu);
+        assert_eq!(region(0x4014), Region::OamDma);
+        assert_eq!(region(0x4015), Region::Apu);
+        assert_eq!(region(0x4016), Region::Joypad1);
+        assert_eq!(region(0x4017), Region::Joypad2);
+        assert_eq!(region(0x4018), Region::Unmapped);
+        assert_eq!(region(0x5FFF), Region::Unmapped);
+        assert_eq!(region(0x6000), Region::PrgRam);
+        assert_eq!(region(0x7FFF), Region::PrgRam);
+        assert_eq!(region(0x8000), Region::Cartridge);
+        assert_eq!(region(0xFFFF), Region::Cartridge);
+    }
+
+    #[test]
+    fn test_ram_is_mirrored() {
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x0801, 0x12);
+        assert_eq!(bus.memory_read(0x0001), 0x12);
+        assert_eq!(bus.memory_read(0x1801), 0x12);
+    }
+
+    #[test]
+    fn test_ppu_registers_are_mirrored() {
+        let mut bus = test_bus(test::test_rom());
+        // OAMADDR then OAMDATA through mirrors of the register block
+        bus.memory_write(0x3FFB, 0x05);
+        bus.memory_write(0x200C, 0x99);
+        bus.memory_write(0x2003, 0x05);
+        assert_eq!(bus.memory_read(0x3FFC), 0x99);
+        // write-only registers read back as 0
+        assert_eq!(bus.memory_read(0x2000), 0);
+    }
+
+    #[test]
+    fn test_apu_and_unmapped_reads_are_zero() {
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x4000, 0xff);
+        bus.memory_write(0x5000, 0xff);
+        assert_eq!(bus.memory_read(0x4000), 0);
+        assert_eq!(bus.memory_read(0x4014), 0);
+        assert_eq!(bus.memory_read(0x5000), 0);
+    }
+
+    #[test]
+    fn test_oam_dma_copies_a_ram_page() {
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x0200, 0x42);
+        bus.memory_write(0x02FF, 0x24);
+        bus.memory_write(0x4014, 0x02);
+        assert_eq!(bus.ppu().oam_data[0], 0x42);
+        assert_eq!(bus.ppu().oam_data[255], 0x24);
+    }
+
+    #[test]
+    fn test_oam_dma_from_mirrored_ram_and_top_page() {
+        let mut prg = vec![0; 0x4000];
+        prg[0x3F00] = 0x11;
+        prg[0x3FFF] = 0x99;
+        let mut bus = test_bus(Rom::from_parts(
+            prg,
+            vec![0; 0x2000],
+            0,
+            Mirroring::Vertical,
+        ));
+
+        bus.memory_write(0x0200, 0x42);
+        bus.memory_write(0x4014, 0x0A);
+        assert_eq!(bus.ppu().oam_data[0], 0x42);
+
+        bus.memory_write(0x4014, 0xFF);
+        assert_eq!(bus.ppu().oam_data[0], 0x11);
+        assert_eq!(bus.ppu().oam_data[255], 0x99);
+    }
+
+    #[test]
+    fn test_oam_dma_leaves_oam_address_where_it_started() {
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x0200, 0x42);
+        bus.memory_write(0x2003, 0x20);
+        bus.memory_write(0x4014, 0x02);
+
+        assert_eq!(bus.ppu().oam_address(), 0x20);
+        assert_eq!(bus.ppu().oam_data[0x20], 0x42);
+    }
+
+    #[test]
+    fn test_peek_does_not_clear_vblank() {
+        let mut bus = test_bus(test::test_rom());
+        bus.ppu.status.set_vblank_status(true);
+        bus.memory_write(0x07ff, 0x42);
+
+        assert_eq!(bus.peek(0x200a) >> 7, 1);
+        assert_eq!(bus.peek(0x2002) >> 7, 1);
+        assert_eq!(bus.peek(0x1fff), 0x42);
+        assert_eq!(bus.memory_read(0x2002) >> 7, 1);
+        assert_eq!(bus.peek(0x2002) >> 7, 0);
+    }
+
+    #[test]
+    fn test_joypad_reads_include_open_bus_bits() {
+        let mut bus = test_bus(test::test_rom());
+        bus.joypad1
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+
+        assert_eq!(bus.memory_read(0x4016), 0x41);
+        assert_eq!(bus.memory_read(0x4016), 0x40);
+        assert_eq!(bus.memory_read(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_both_pads_share_the_strobe() {
+        // the host sets the buttons of both players at the end of a frame
+        let mut bus = BUS::new(
+            test::test_rom(),
+            |_ppu: &PPU, joypad1: &mut Joypad, joypad2: &mut Joypad| {
+                joypad1.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+                joypad2.set_button_pressed_status(JoypadButton::START, true);
+            },
+        )
+        .unwrap();
+        while bus.ppu().scanline != 261 {
+            bus.tick(1);
+        }
+        while bus.ppu().scanline != 0 {
+            bus.tick(1);
+        }
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+
+        let joypad1: Vec<u8> = (0..8).map(|_| bus.memory_read(0x4016) & 1).collect();
+        let joypad2: Vec<u8> = (0..8).map(|_| bus.memory_read(0x4017) & 1).collect();
+        assert_eq!(joypad1, vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(joypad2, vec![0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_dmc_conflict_drops_controller_bit() {
+        let mut bus = test_bus(test::test_rom());
+        bus.joypad1
+            .set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+
+        // without the flag the DMA is harmless
+        bus.schedule_dmc_dma();
+        assert_eq!(bus.memory_read(0x4016) & 1, 0);
+        assert_eq!(bus.memory_read(0x4016) & 1, 1);
+
+        bus.accurate_dmc_conflicts = true;
+        bus.memory_write(0x4016, 1);
+        bus.memory_write(0x4016, 0);
+        assert_eq!(bus.memory_read(0x4016) & 1, 0);
+        // B is shifted out by the repeated read, select is seen instead
+        bus.schedule_dmc_dma();
+        assert_eq!(bus.memory_read(0x4016) & 1, 0);
+    }
+
+    #[test]
+    fn test_mapper_writes_mark_every_tile_dirty() {
+        let mut bus = test_bus(test::test_rom());
+        bus.ppu_mut().chr_dirty.clear();
+        bus.memory_write(0x8000, 0x01);
+        assert_eq!(bus.ppu().chr_dirty.dirty_regions().len(), 512);
+    }
+
+    #[test]
+    fn test_bus_from_rom_parts() {
+        let mut prg = vec![0xEA; 0x4000];
+        prg[0] = 0xA9;
+        prg[1] = 0x05;
+        let rom = Rom::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Vertical);
+        let mut bus = test_bus(rom);
+
+        assert_eq!(bus.memory_read(0x8000), 0xA9);
+        assert_eq!(bus.memory_read(0x8001), 0x05);
+        // 16KB of PRG is mirrored into the upper bank
+        assert_eq!(bus.memory_read(0xC000), 0xA9);
+    }
+
+    #[test]
+    fn test_unsupported_mapper_is_an_error() {
+        let rom = Rom::from_parts(vec![0; 0x4000], vec![0; 0x2000], 200, Mirroring::Vertical);
+        let bus = BUS::new(
+            rom,
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        );
+        assert_eq!(bus.err(), Some("Mapper 200 is not supported".to_string()));
+    }
+
+    #[test]
+    fn test_scanline_callback_fires_for_every_scanline() {
+        let scanlines = Rc::new(RefCell::new(vec![]));
+        let frames = Rc::new(Cell::new(0));
+
+        let frames_seen = frames.clone();
+        let mut bus = BUS::new(
+            test::test_rom(),
+            move |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {
+                frames_seen.set(frames_seen.get() + 1);
+            },
+        )
+        .unwrap();
+        let recorded = scanlines.clone();
+        bus.set_scanline_callback(move |scanline, _ppu| recorded.borrow_mut().push(scanline));
+
+        while frames.get() == 0 {
+            bus.tick(1);
+        }
+
+        assert_eq!(*scanlines.borrow(), (0..262).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn test_set_callback_swaps_frame_callback() {
+        let frames = Rc::new(RefCell::new(vec![]));
+
+        let first = frames.clone();
+        let mut bus = BUS::new(
+            test::test_rom(),
+            move |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {
+                first.borrow_mut().push("first");
+            },
+        )
+        .unwrap();
+
+        let run_frame = |bus: &mut BUS, frames: &Rc<RefCell<Vec<&str>>>| {
+            let before = frames.borrow().len();
+            while frames.borrow().len() == before {
+                bus.tick(1);
+            }
+        };
+
+        run_frame(&mut bus, &frames);
+        let second = frames.clone();
+        bus.set_callback(
+            move |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {
+                second.borrow_mut().push("second");
+            },
+        );
+        run_frame(&mut bus, &frames);
+
+        assert_eq!(*frames.borrow(), vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_prg_ram_goes_through_mapper() {
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x6000, 0x5a);
+        bus.memory_write(0x7FFF, 0xa5);
+        assert_eq!(bus.memory_read(0x6000), 0x5a);
+        assert_eq!(bus.peek(0x7FFF), 0xa5);
+    }
+
+    #[test]
+    fn test_trainer_is_loaded_at_7000() {
+        let mut bus = test_bus(test::trainer_rom());
+        assert_eq!(bus.memory_read(0x7000), 0x00);
+        assert_eq!(bus.memory_read(0x7001), 0x01);
+        assert_eq!(bus.memory_read(0x71FF), 0xff);
+        assert_eq!(bus.memory_read(0x7200), 0x00);
+    }
+
+    #[test]
+    fn test_sram_survives_save_and_load() {
+        let path = std::env::temp_dir().join(format!("nes-sram-{}.sav", std::process::id()));
+        let mut bus = test_bus(test::battery_rom());
+        bus.memory_write(0x6000, 0x12);
+        bus.memory_write(0x7FFF, 0x34);
+        bus.save_sram(&path).unwrap();
+
+        let mut bus = test_bus(test::battery_rom());
+        assert_eq!(bus.memory_read(0x6000), 0);
+        bus.load_sram(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bus.memory_read(0x6000), 0x12);
+        assert_eq!(bus.memory_read(0x7FFF), 0x34);
+
+        // without a battery nothing is written
+        let bus = test_bus(test::test_rom());
+        bus.save_sram(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_trainer_is_kept_over_loaded_sram() {
+        let path = std::env::temp_dir().join(format!("nes-trainer-{}.sav", std::process::id()));
+        let mut bus = test_bus(test::battery_rom());
+        bus.memory_write(0x6000, 0x12);
+        bus.memory_write(0x7001, 0xee);
+        bus.memory_write(0x7200, 0x34);
+        bus.save_sram(&path).unwrap();
+
+        let mut rom = test::battery_rom();
+        rom.trainer = test::trainer_rom().trainer;
+        let mut bus = test_bus(rom);
+        bus.load_sram(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bus.memory_read(0x6000), 0x12);
+        assert_eq!(bus.memory_read(0x7001), 0x01);
+        assert_eq!(bus.memory_read(0x7200), 0x34);
+    }
+
+    #[test]
+    fn test_prg_reads_go_through_mapper() {
+        let mut bus = test_bus(test::test_rom());
+        assert_eq!(bus.memory_read(0x8000), 1);
+        assert_eq!(bus.memory_read(0xFFFF), 1);
+
+        // test rom is CNROM, bank select writes must not panic
+        bus.memory_write(0x8000, 0);
+        assert_eq!(bus.ppu().read_chr(0x0000), 2);
+    }
+
+    fn uxrom_bus() -> BUS<'static> {
+        let prg = (0..4).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        test_bus(Rom::from_parts(
+            prg,
+            vec![0; 0x2000],
+            2,
+            Mirroring::Vertical,
+        ))
+    }
+
+    #[test]
+    fn test_load_state_restores_bank_selection() {
+        let mut bus = uxrom_bus();
+        bus.memory_write(0x8000, 1);
+        bus.memory_write(0x0010, 0x77);
+        let state = bus.save_state();
+
+        bus.memory_write(0x8000, 2);
+        bus.memory_write(0x0010, 0);
+        assert_eq!(bus.memory_read(0x8000), 2);
+
+        bus.load_state(&state).unwrap();
+        assert_eq!(bus.memory_read(0x8000), 1);
+        assert_eq!(bus.memory_read(0x0010), 0x77);
+    }
+
+    #[test]
+    fn test_load_state_rejects_other_mapper() {
+        let state = test_bus(test::test_rom()).save_state();
+
+        let mut bus = uxrom_bus();
+        assert_eq!(
+            bus.load_state(&state),
+            Err("State is for mapper 3, cartridge uses mapper 2".to_string())
+        );
+    }
+
+    #[test]
+    fn test_write_watch_sees_old_and_new_value() {
+        let changes = Rc::new(RefCell::new(vec![]));
+        let seen = changes.clone();
+        let mut bus = test_bus(test::test_rom());
+        bus.add_write_watch(
+            0x0075..=0x0075,
+            Box::new(move |address, old, new| seen.borrow_mut().push((address, old, new))),
+        );
+
+        bus.memory_write(0x0075, 0x10);
+        bus.memory_write(0x0076, 0x20);
+        // a mirror of $0075 fires once, with the mirrored-down address
+        bus.memory_write(0x0875, 0x11);
+        assert_eq!(
+            *changes.borrow(),
+            vec![(0x0075, 0x00, 0x10), (0x0075, 0x10, 0x11)]
+        );
+    }
+
+    #[test]
+    fn test_read_watch_counts_each_read_once() {
+        let reads = Rc::new(Cell::new(0));
+        let counter = reads.clone();
+        let mut bus = test_bus(test::test_rom());
+        bus.memory_write(0x0300, 0xab);
+        bus.add_read_watch(
+            0x0300..=0x03ff,
+            Box::new(move |address, value| {
+                assert_eq!((address, value), (0x0300, 0xab));
+                counter.set(counter.get() + 1);
+            }),
+        );
+
+        assert_eq!(bus.memory_read(0x1b00), 0xab);
+        bus.memory_read(0x0400);
+        assert_eq!(reads.get(), 1);
+    }
+
+    #[test]
+    fn test_scroll_split_at_scanline_100() {
+        // tile 1 is solid color 1, tile 2 solid color 2
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xff; 8]);
+        chr[0x28..0x30].copy_from_slice(&[0xff; 8]);
+        let rom = Rom::from_parts(vec![0; 0x4000], chr, 0, Mirroring::Vertical);
+        let mut bus = test_bus(rom);
+        let ppu = bus.ppu_mut();
+        ppu.enable_scanline_rendering();
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[2] = 0x2a;
+        // columns alternate between tiles 1 and 2
+        for (index, name) in ppu.vram[..0x3c0].iter_mut().enumerate() {
+            *name = 1 + (index % 2) as u8;
+        }
+        // show the background, leftmost pixels too
+        bus.memory_write(0x2001, 0b0000_1010);
+
+        while bus.ppu().scanline != 100 {
+            bus.tick(1);
+        }
+        // scroll one tile right, from the next line on
+        bus.memory_write(0x2005, 8);
+        bus.memory_write(0x2005, 0);
+        while bus.ppu().scanline != 240 {
+            bus.tick(1);
+        }
+
+        let frame = bus.ppu().frame().unwrap();
+        let at = |x: usize, y: usize| frame.index_buffer()[y * 256 + x];
+        assert_eq!((at(0, 0), at(8, 0)), (0x16, 0x2a));
+        assert_eq!((at(0, 100), at(8, 100)), (0x16, 0x2a));
+        assert_eq!((at(0, 101), at(8, 101)), (0x2a, 0x16));
+        assert_eq!((at(0, 239), at(8, 239)), (0x2a, 0x16));
+        // the $2005 write shifted the whole bottom half a tile left
+        let line = |y: usize| &frame.index_buffer()[y * 256..(y + 1) * 256];
+        assert_eq!(line(50)[8..], line(150)[..248]);
+        assert_ne!(line(50), line(150));
+    }
+
+    #[test]
+    fn test_ppu_address_write_at_scanline_100() {
+        // tile 1 is solid color 1, tile 2 solid color 2
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xff; 8]);
+        chr[0x28..0x30].copy_from_slice(&[0xff; 8]);
+        let rom = Rom::from_parts(vec![0; 0x4000], chr, 0, Mirroring::Vertical);
+        let mut bus = test_bus(rom);
+        let ppu = bus.ppu_mut();
+        ppu.enable_scanline_rendering();
+        ppu.palette_table[1] = 0x16;
+        ppu.palette_table[2] = 0x2a;
+        // the first nametable is all tile 1, the second has two rows of
+        // tile 1 and then tile 2
+        ppu.vram[..0x3c0].fill(1);
+        ppu.vram[0x400..0x440].fill(1);
+        ppu.vram[0x440..0x7c0].fill(2);
+        bus.memory_write(0x2001, 0b0000_1010);
+
+        while bus.ppu().scanline != 100 {
+            bus.tick(1);
+        }
+        // row 2 of the second nametable, rendering picks it up from the next line
+        bus.memory_write(0x2006, 0x24);
+        bus.memory_write(0x2006, 0x40);
+        while bus.ppu().scanline != 240 {
+            bus.tick(1);
+        }
+
+        let frame = bus.ppu().frame().unwrap();
+        let line = |y: usize| &frame.index_buffer()[y * 256..(y + 1) * 256];
+        for y in 0..=100 {
+            assert!(line(y).iter().all(|&color| color == 0x16), "line {}", y);
+        }
+        for y in 101..240 {
+            assert!(line(y).iter().all(|&color| color == 0x2a), "line {}", y);
+        }
+    }
+
+    #[test]
+    fn test_mmc3_irq_after_latched_scanlines() {
+        let rom = Rom::from_parts(vec![0; 0x8000], vec![0; 0x2000], 4, Mirroring::Horizontal);
+        let mut bus = test_bus(rom);
+        bus.memory_write(0xC000, 10);
+        bus.memory_write(0xC001, 0);
+        bus.memory_write(0xE001, 0);
+        bus.memory_write(0x2001, 0b0001_1000);
+
+        // line 0 reloads the counter, lines 1 to 10 count it down
+        while !bus.poll_irq_status() {
+            assert!(bus.ppu().scanline <= 11);
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu().scanline, 11);
+
+        bus.memory_write(0xE000, 0);
+        assert!(!bus.poll_irq_status());
+    }
+
+    struct CollectSamples(Rc<RefCell<Vec<f32>>>);
+
+    impl AudioSink for CollectSamples {
+        fn push_sample(&mut self, sample: f32) {
+            self.0.borrow_mut().push(sample);
+        }
+    }
+
+    #[test]
+    fn test_pal_bus_uses_pal_apu_and_clock() {
+        let samples = Rc::new(RefCell::new(vec![]));
+        let mut bus = BUS::new_with_tv_system(
+            test::test_rom(),
+            TvSystem::Pal,
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        bus.set_audio_sink(
+            Box::new(CollectSamples(samples.clone())),
+            AudioConfig::default(),
+        );
+
+        bus.memory_write(0x400E, 2);
+        assert_eq!(bus.apu().noise_frequency(), PAL_CPU_CLOCK as f64 / 14.0);
+        // 10ms of PAL cycles still make 10ms of samples
+        for _ in 0..PAL_CPU_CLOCK / 100 {
+            bus.tick(1);
+        }
+        assert_eq!(samples.borrow().len(), 440);
+    }
+
+    #[test]
+    fn test_pulse_reaches_audio_sink() {
+        let samples = Rc::new(RefCell::new(vec![]));
+        let mut bus = test_bus(test::test_rom());
+        bus.set_audio_sink(
+            Box::new(CollectSamples(samples.clone())),
+            AudioConfig::default(),
+        );
+
+        // pulse 1, 50% duty at constant volume 15, about 440Hz
+        bus.memory_write(0x4015, 0b0001);
+        bus.memory_write(0x4000, 0b1011_1111);
+        bus.memory_write(0x4002, 0xfd);
+        bus.memory_write(0x4003, 0x00);
+        for _ in 0..NTSC_CPU_CLOCK / 100 {
+            bus.tick(1);
+        }
+
+        let samples = samples.borrow();
+        // 10ms at 44100Hz, the 441st sample point is a fraction of a cycle later
+        assert_eq!(samples.len(), 440);
+        let high = samples.iter().filter(|&&sample| sample > 0.0).count();
+        assert!(high > 150 && high < 290, "{} high samples", high);
+    }
 }
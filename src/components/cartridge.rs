@@ -1,12 +1,17 @@
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+pub const TRAINER_SIZE: usize = 512;
+// 8KB INST-ROM followed by 16 bytes of PROM data and 16 bytes of PROM counter out
+const PLAYCHOICE_DATA_SIZE: usize = 8192 + 32;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Mirroring {
     Vertical,
     Horizontal,
     FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
 }
 
 pub struct Rom {
@@ -14,10 +19,40 @@ pub struct Rom {
     pub chr_rom: Vec<u8>,
     pub mapper: u8,
     pub screen_mirroring: Mirroring,
+    /// Loaded at `$7000`, some older dumps patch the game from there.
+    pub trainer: Option<[u8; TRAINER_SIZE]>,
+    vs_system: bool,
+    playchoice: bool,
+    battery: bool,
+    size_warning: Option<String>,
+    prg_pages: u8,
+    chr_pages: u8,
+    chr_ram: bool,
+}
+
+// the header sizes should account for every byte of the file, anything else
+// usually means a bad dump or a format extension we don't know about
+fn check_size(file_size: usize, expected: usize) -> Option<String> {
+    if file_size > expected {
+        Some(format!(
+            "ROM file has {} unexpected trailing bytes",
+            file_size - expected
+        ))
+    } else if file_size < expected {
+        Some(format!(
+            "ROM file is {} bytes shorter than its header says",
+            expected - file_size
+        ))
+    } else {
+        None
+    }
 }
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
+        if raw.len() < 16 {
+            return Err("truncated ROM".to_string());
+        }
         if &raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
@@ -29,6 +64,10 @@ impl Rom {
             return Err("NES2.0 format is not supported".to_string());
         }
 
+        let vs_system = raw[7] & 0b01 != 0;
+        let battery = raw[6] & 0b10 != 0;
+        let playchoice = raw[7] & 0b10 != 0;
+
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical_mirroring = raw[6] & 0b1 != 0;
         let screen_mirroring = match (four_screen, vertical_mirroring) {
@@ -42,16 +81,110 @@ impl Rom {
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
+        let prg_rom_start = 16 + if skip_trainer { TRAINER_SIZE } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        if raw.len() < chr_rom_start + chr_rom_size {
+            return Err("truncated ROM".to_string());
+        }
+
+        let trainer = if skip_trainer {
+            let mut trainer = [0; TRAINER_SIZE];
+            trainer.copy_from_slice(&raw[16..prg_rom_start]);
+            Some(trainer)
+        } else {
+            None
+        };
+
+        // the PlayChoice INST-ROM/PROM comes after CHR, it is not used by the emulator
+        // but is accounted for so nothing mistakes it for cartridge data. A dump
+        // missing it still loads, with a size warning
+        let playchoice_start = chr_rom_start + chr_rom_size;
+
+        // no CHR-ROM pages means the board carries 8KB of CHR-RAM instead
+        let chr_ram = raw[5] == 0;
+        let chr_rom = if chr_ram {
+            vec![0; CHR_ROM_PAGE_SIZE]
+        } else {
+            raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec()
+        };
+
+        let expected_size = playchoice_start + if playchoice { PLAYCHOICE_DATA_SIZE } else { 0 };
+        let size_warning = check_size(raw.len(), expected_size);
 
         Ok(Rom {
             prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            chr_rom,
             mapper: mapper,
             screen_mirroring: screen_mirroring,
+            trainer,
+            vs_system,
+            playchoice,
+            battery,
+            size_warning,
+            prg_pages: raw[4],
+            chr_pages: raw[5],
+            chr_ram,
         })
     }
+
+    // builds a cartridge straight from raw PRG/CHR images, without an iNES header
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>, mapper: u8, mirroring: Mirroring) -> Rom {
+        let chr_ram = chr.is_empty();
+        let chr_pages = chr.len().div_ceil(CHR_ROM_PAGE_SIZE) as u8;
+        let chr = if chr_ram {
+            vec![0; CHR_ROM_PAGE_SIZE]
+        } else {
+            chr
+        };
+        Rom {
+            prg_pages: prg.len().div_ceil(PRG_ROM_PAGE_SIZE) as u8,
+            chr_pages,
+            prg_rom: prg,
+            chr_rom: chr,
+            mapper,
+            screen_mirroring: mirroring,
+            trainer: None,
+            vs_system: false,
+            playchoice: false,
+            battery: false,
+            size_warning: None,
+            chr_ram,
+        }
+    }
+
+    /// 16KB PRG-ROM pages, from header byte 4.
+    pub fn prg_pages(&self) -> u8 {
+        self.prg_pages
+    }
+
+    /// 8KB CHR-ROM pages, from header byte 5. 0 means the board has CHR-RAM.
+    pub fn chr_pages(&self) -> u8 {
+        self.chr_pages
+    }
+
+    /// `chr_rom` is writable pattern memory rather than ROM.
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_ram
+    }
+
+    pub fn is_vs_system(&self) -> bool {
+        self.vs_system
+    }
+
+    pub fn is_playchoice(&self) -> bool {
+        self.playchoice
+    }
+
+    /// The PRG-RAM is battery backed and should be kept between runs.
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// Set when the file length doesn't match the sizes in the header. The
+    /// ROM still loads, this is only a hint that the file is odd.
+    pub fn size_warning(&self) -> Option<&str> {
+        self.size_warning.as_deref()
+    }
 }
 
 pub mod test {
@@ -96,6 +229,48 @@ pub mod test {
         Rom::new(&test_rom).unwrap()
     }
 
+    // every trainer byte holds the low byte of its offset
+    pub fn trainer_rom() -> Rom {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x05, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: Some((0..TRAINER_SIZE).map(|i| i as u8).collect()),
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        Rom::new(&test_rom).unwrap()
+    }
+
+    // NROM with battery backed PRG-RAM
+    pub fn battery_rom() -> Rom {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x03, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        Rom::new(&test_rom).unwrap()
+    }
+
+    // NROM with no CHR pages in the header
+    pub fn chr_ram_rom() -> Rom {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        Rom::new(&test_rom).unwrap()
+    }
+
     #[test]
     fn test() {
         let test_rom = create_rom(TestRom {
@@ -115,6 +290,33 @@ pub mod test {
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
     }
 
+    #[test]
+    fn test_page_counts_match_header() {
+        let rom = test_rom();
+        assert_eq!(rom.prg_pages(), 2);
+        assert_eq!(rom.chr_pages(), 1);
+
+        let rom = Rom::from_parts(vec![0; 0x4000], vec![], 0, Mirroring::Vertical);
+        assert_eq!(rom.prg_pages(), 1);
+        assert_eq!(rom.chr_pages(), 0);
+    }
+
+    #[test]
+    fn test_missing_chr_rom_allocates_chr_ram() {
+        let rom = chr_ram_rom();
+        assert!(rom.has_chr_ram());
+        assert_eq!(rom.chr_rom, vec![0; CHR_ROM_PAGE_SIZE]);
+        assert_eq!(rom.size_warning(), None);
+
+        assert!(!test_rom().has_chr_ram());
+    }
+
+    #[test]
+    fn test_battery_flag() {
+        assert!(battery_rom().has_battery());
+        assert!(!test_rom().has_battery());
+    }
+
     #[test]
     fn test_with_trainer() {
         let test_rom = create_rom(TestRom {
@@ -147,6 +349,109 @@ pub mod test {
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert_eq!(rom.trainer, Some([0; TRAINER_SIZE]));
+        assert_eq!(trainer_rom().trainer.unwrap()[0x1ff], 0xff);
+        assert_eq!(self::test_rom().trainer, None);
+    }
+
+    #[test]
+    fn test_with_playchoice_data() {
+        let mut test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0b10, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        test_rom.extend(vec![3; PLAYCHOICE_DATA_SIZE]);
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert!(rom.is_playchoice());
+        assert!(!rom.is_vs_system());
+    }
+
+    #[test]
+    fn test_truncated_playchoice_data_loads_with_warning() {
+        let mut test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0b10, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        test_rom.extend(vec![3; 8192]);
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert_eq!(rom.chr_rom, vec!(2; CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert!(rom.is_playchoice());
+        assert_eq!(
+            rom.size_warning(),
+            Some("ROM file is 32 bytes shorter than its header says")
+        );
+    }
+
+    #[test]
+    fn test_truncated_rom_is_rejected() {
+        let mut test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        test_rom.truncate(test_rom.len() - 1);
+        assert_eq!(Rom::new(&test_rom).err(), Some("truncated ROM".to_string()));
+
+        test_rom.truncate(10);
+        assert_eq!(Rom::new(&test_rom).err(), Some("truncated ROM".to_string()));
+    }
+
+    #[test]
+    fn test_vs_system_flag() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0b01, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom: Rom = Rom::new(&test_rom).unwrap();
+
+        assert!(rom.is_vs_system());
+        assert!(!rom.is_playchoice());
+        assert_eq!(rom.mapper, 3);
+    }
+
+    #[test]
+    fn test_trailing_bytes_warn_but_load() {
+        let mut raw = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        raw.extend(vec![0; 128]);
+
+        let rom: Rom = Rom::new(&raw).unwrap();
+
+        assert_eq!(
+            rom.size_warning(),
+            Some("ROM file has 128 unexpected trailing bytes")
+        );
+        assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
+        assert_eq!(test_rom().size_warning(), None);
     }
 
     #[test]
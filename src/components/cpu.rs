@@ -1,5 +1,10 @@
-use super::assembly::{Assembler, OpCode};
+use std::collections::HashSet;
+use std::fmt;
+
+use super::assembly::{self, Assembler, OpCode, ASSEMBLER};
 use super::bus::BUS;
+use super::cycle_validator::CycleValidator;
+use crate::trace::{trace, TraceHistory};
 
 bitflags! {
     /// # Status Register (P) http://wiki.nesdev.com/w/index.php/Status_flags
@@ -37,6 +42,81 @@ pub struct CPU<'a> {
     pub register_pc: u16,
     pub register_sp: u8,
     pub bus: BUS<'a>,
+    /// When set, JAM opcodes lock up the CPU until reset like on hardware,
+    /// otherwise they are executed as 1 byte NOPs.
+    pub halt_on_jam: bool,
+    pub jammed: bool,
+    /// When set, BRK ends the run instead of taking the interrupt. Only for
+    /// test programs, `load_and_run` turns it on.
+    pub brk_ends_run: bool,
+    history: Option<TraceHistory>,
+    mnemonic_breakpoints: HashSet<String>,
+    /// PC of the instruction the last run stopped on because of a breakpoint.
+    pub breakpoint_hit: Option<u16>,
+    instruction_count: u64,
+    cycles_at_reset: usize,
+    /// Count ADC/SBC run with the decimal flag set, see `decimal_warnings`. The
+    /// 2A03 has no BCD mode so such code only works on this core by accident.
+    pub warn_on_decimal: bool,
+    /// BCD arithmetic for ADC/SBC when the decimal flag is set, for running
+    /// the core as a plain 6502. Off by default like on the 2A03.
+    pub decimal_enabled: bool,
+    decimal_warnings: u64,
+    stop_requested: bool,
+    // set when BRK ended the program, see `brk_ends_run`
+    program_ended: bool,
+    /// Strict mode: checks every instruction's cycles against a reference table.
+    pub cycle_validator: Option<CycleValidator>,
+    // interrupt lines driven from outside the bus devices, see `assert_nmi`
+    nmi_pending: bool,
+    irq_line: bool,
+}
+
+/// The registers and the cycles since reset, see `save_state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub cycles: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuError {
+    /// The byte and the PC it was fetched from.
+    UnknownOpcode(u8, u16),
+    /// A JAM opcode halted the CPU at this PC before the cycles ran out.
+    Jammed(u16),
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode(code, pc) => {
+                write!(f, "OpCode {:x} at {:04x} is not recognized", code, pc)
+            }
+            CpuError::Jammed(pc) => write!(f, "CPU jammed at {:04x}", pc),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Break,
+    Jammed,
+    Breakpoint(u16),
+}
+
+/// What `load_and_run` left behind.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunSummary {
+    pub instructions: u64,
+    pub registers: CpuState,
+    pub stop_reason: StopReason,
 }
 
 #[derive(Debug)]
@@ -53,6 +133,72 @@ pub enum AddressingMode {
     NoneAddressing,
 }
 
+// the addressing modes' memory reads in CPU order, `read` decides whether
+// they are real reads or peeks
+fn resolve_address(
+    mode: &AddressingMode,
+    address: u16,
+    x: u8,
+    y: u8,
+    mut read: impl FnMut(u16) -> u8,
+) -> (u16, bool) {
+    match mode {
+        AddressingMode::ZeroPage => (read(address) as u16, false),
+
+        AddressingMode::Absolute => (read_u16(&mut read, address), false),
+
+        AddressingMode::ZeroPageX => {
+            let index = read(address);
+            let address = index.wrapping_add(x) as u16;
+            (address, false)
+        }
+        AddressingMode::ZeroPageY => {
+            let index = read(address);
+            let address = index.wrapping_add(y) as u16;
+            (address, false)
+        }
+
+        AddressingMode::AbsoluteX => {
+            let base = read_u16(&mut read, address);
+            let address = base.wrapping_add(x as u16);
+            (address, page_cross(base, address))
+        }
+        AddressingMode::AbsoluteY => {
+            let base = read_u16(&mut read, address);
+            let address = base.wrapping_add(y as u16);
+            (address, page_cross(base, address))
+        }
+
+        AddressingMode::IndirectX => {
+            let base = read(address);
+
+            let ptr: u8 = (base as u8).wrapping_add(x);
+            let lo = read(ptr as u16);
+            let hi = read(ptr.wrapping_add(1) as u16);
+            ((hi as u16) << 8 | (lo as u16), false)
+        }
+        AddressingMode::IndirectY => {
+            let base = read(address);
+
+            let lo = read(base as u16);
+            let hi = read((base as u8).wrapping_add(1) as u16);
+            let deref_base = (hi as u16) << 8 | (lo as u16);
+            let deref = deref_base.wrapping_add(y as u16);
+            (deref, page_cross(deref, deref_base))
+        }
+
+        _ => {
+            panic!("mode {:?} is not supported", mode);
+        }
+    }
+}
+
+fn read_u16(read: &mut impl FnMut(u16) -> u8, address: u16) -> u16 {
+    let low = read(address) as u16;
+    let high = read(address.wrapping_add(1)) as u16;
+    (high << 8) | low
+}
+
 fn page_cross(addr1: u16, addr2: u16) -> bool {
     addr1 & 0xFF00 != addr2 & 0xFF00
 }
@@ -60,6 +206,7 @@ fn page_cross(addr1: u16, addr2: u16) -> bool {
 #[derive(PartialEq, Eq)]
 pub enum InterruptType {
     NMI,
+    IRQ,
 }
 
 #[derive(PartialEq, Eq)]
@@ -74,7 +221,14 @@ pub const NMI: Interrupt = Interrupt {
     interrupt_type: InterruptType::NMI,
     vector_address: 0xfffA,
     binary_flag_mask: 0b00100000,
-    cpu_cycles: 2,
+    cpu_cycles: 7,
+};
+
+pub const IRQ: Interrupt = Interrupt {
+    interrupt_type: InterruptType::IRQ,
+    vector_address: 0xfffe,
+    binary_flag_mask: 0b00100000,
+    cpu_cycles: 7,
 };
 
 impl<'a> CPU<'a> {
@@ -87,9 +241,96 @@ impl<'a> CPU<'a> {
             register_pc: 0,
             register_p: CpuFlags::from_bits_truncate(0b100100),
             bus,
+            halt_on_jam: true,
+            jammed: false,
+            brk_ends_run: false,
+            history: None,
+            mnemonic_breakpoints: HashSet::new(),
+            breakpoint_hit: None,
+            instruction_count: 0,
+            cycles_at_reset: 0,
+            warn_on_decimal: false,
+            decimal_enabled: false,
+            decimal_warnings: 0,
+            stop_requested: false,
+            program_ended: false,
+            cycle_validator: None,
+            nmi_pending: false,
+            irq_line: false,
         }
     }
 
+    /// Keeps a trace of the last `size` executed instructions, see `dump_history`.
+    /// Tracing reads the operands through the bus, so it is meant for debugging only.
+    pub fn enable_history(&mut self, size: usize) {
+        self.history = Some(TraceHistory::new(size));
+    }
+
+    pub fn dump_history(&self) -> Vec<String> {
+        self.history
+            .as_ref()
+            .map_or(vec![], |history| history.dump_history())
+    }
+
+    /// Stops the run loop before executing any opcode with this mnemonic.
+    /// Unofficial opcodes are matched without their "*" prefix.
+    pub fn add_mnemonic_breakpoint(&mut self, mnemonic: &str) {
+        self.mnemonic_breakpoints
+            .insert(mnemonic.to_ascii_uppercase());
+    }
+
+    pub fn remove_mnemonic_breakpoint(&mut self, mnemonic: &str) {
+        self.mnemonic_breakpoints
+            .remove(&mnemonic.to_ascii_uppercase());
+    }
+
+    fn hits_mnemonic_breakpoint(&self, code: u8) -> bool {
+        assembly::OPCODES_MAP.get(&code).is_some_and(|opcode| {
+            self.mnemonic_breakpoints
+                .contains(opcode.mnemonic.trim_start_matches('*'))
+        })
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            a: self.register_a,
+            x: self.register_x,
+            y: self.register_y,
+            p: self.register_p.bits(),
+            pc: self.register_pc,
+            sp: self.register_sp,
+            cycles: self.total_cycles(),
+        }
+    }
+
+    /// Restores the registers only, the bus and its devices are untouched.
+    pub fn load_state(&mut self, state: CpuState) {
+        self.register_a = state.a;
+        self.register_x = state.x;
+        self.register_y = state.y;
+        self.register_p = CpuFlags::from_bits_truncate(state.p);
+        self.register_pc = state.pc;
+        self.register_sp = state.sp;
+        self.cycles_at_reset = self.bus.cycles().wrapping_sub(state.cycles);
+    }
+
+    /// Number of instructions executed since the last reset.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    /// CPU cycles elapsed since the last reset, the bus keeps counting from
+    /// power on.
+    pub fn total_cycles(&self) -> usize {
+        self.bus.cycles().wrapping_sub(self.cycles_at_reset)
+    }
+
+    /// Makes `run_with_callback` return before executing the next instruction,
+    /// meant to be called from its callback.
+    pub fn stop(&mut self) {
+        self.stop_requested = true;
+    }
+
     pub fn memory_read(&mut self, address: u16) -> u8 {
         self.bus.memory_read(address)
     }
@@ -98,6 +339,11 @@ impl<'a> CPU<'a> {
         self.bus.memory_write(address, value)
     }
 
+    /// Reads memory without the side effects of `memory_read`, see `BUS::peek`.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.bus.peek(address)
+    }
+
     pub fn memory_read_u16(&mut self, address: u16) -> u16 {
         self.bus.memory_read_u16(address)
     }
@@ -108,55 +354,15 @@ impl<'a> CPU<'a> {
 
     // returns (address, page_cross flag)
     pub fn get_absolute_address(&mut self, mode: &AddressingMode, address: u16) -> (u16, bool) {
-        match mode {
-            AddressingMode::ZeroPage => (self.memory_read(address) as u16, false),
-
-            AddressingMode::Absolute => (self.memory_read_u16(address), false),
-
-            AddressingMode::ZeroPageX => {
-                let index = self.memory_read(address);
-                let address = index.wrapping_add(self.register_x) as u16;
-                (address, false)
-            }
-            AddressingMode::ZeroPageY => {
-                let index = self.memory_read(address);
-                let address = index.wrapping_add(self.register_y) as u16;
-                (address, false)
-            }
-
-            AddressingMode::AbsoluteX => {
-                let base = self.memory_read_u16(address);
-                let address = base.wrapping_add(self.register_x as u16);
-                (address, page_cross(base, address))
-            }
-            AddressingMode::AbsoluteY => {
-                let base = self.memory_read_u16(address);
-                let address = base.wrapping_add(self.register_y as u16);
-                (address, page_cross(base, address))
-            }
-
-            AddressingMode::IndirectX => {
-                let base = self.memory_read(address);
-
-                let ptr: u8 = (base as u8).wrapping_add(self.register_x);
-                let lo = self.memory_read(ptr as u16);
-                let hi = self.memory_read(ptr.wrapping_add(1) as u16);
-                ((hi as u16) << 8 | (lo as u16), false)
-            }
-            AddressingMode::IndirectY => {
-                let base = self.memory_read(address);
-
-                let lo = self.memory_read(base as u16);
-                let hi = self.memory_read((base as u8).wrapping_add(1) as u16);
-                let deref_base = (hi as u16) << 8 | (lo as u16);
-                let deref = deref_base.wrapping_add(self.register_y as u16);
-                (deref, page_cross(deref, deref_base))
-            }
+        let (x, y) = (self.register_x, self.register_y);
+        resolve_address(mode, address, x, y, |address| self.memory_read(address))
+    }
 
-            _ => {
-                panic!("mode {:?} is not supported", mode);
-            }
-        }
+    /// Same as `get_absolute_address`, but the operand and pointer reads are
+    /// peeks, so tracing an instruction doesn't change what it does.
+    pub fn peek_absolute_address(&self, mode: &AddressingMode, address: u16) -> u16 {
+        let (x, y) = (self.register_x, self.register_y);
+        resolve_address(mode, address, x, y, |address| self.peek(address)).0
     }
 
     pub fn get_operand_address(&mut self, mode: &AddressingMode) -> (u16, bool) {
@@ -201,9 +407,20 @@ impl<'a> CPU<'a> {
         self.update_zero_and_negative_flags(self.register_a);
     }
 
-    /// note: ignoring decimal mode
-    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    fn decimal_active(&self) -> bool {
+        self.decimal_enabled && self.register_p.contains(CpuFlags::DECIMAL_MODE)
+    }
+
     fn add_to_register_a(&mut self, data: u8) {
+        if self.decimal_active() {
+            self.add_decimal_to_register_a(data);
+        } else {
+            self.add_binary_to_register_a(data);
+        }
+    }
+
+    /// http://www.righto.com/2012/12/the-6502-overflow-flag-explained.html
+    fn add_binary_to_register_a(&mut self, data: u8) {
         let sum = self.register_a as u16
             + data as u16
             + (if self.register_p.contains(CpuFlags::CARRY) {
@@ -231,8 +448,51 @@ impl<'a> CPU<'a> {
         self.set_register_a(result);
     }
 
+    // NMOS behaviour: Z comes from the binary sum, N and V from the sum
+    // before the high digit is adjusted
+    fn add_decimal_to_register_a(&mut self, data: u8) {
+        let a = self.register_a as u16;
+        let b = data as u16;
+        let carry = self.register_p.contains(CpuFlags::CARRY) as u16;
+
+        let mut low = (a & 0x0f) + (b & 0x0f) + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) + (b & 0xf0) + low;
+
+        self.register_p
+            .set(CpuFlags::ZERO, (a + b + carry) as u8 == 0);
+        self.register_p.set(CpuFlags::NEGATIVE, sum & 0x80 != 0);
+        self.register_p
+            .set(CpuFlags::OVERFLOW, !(a ^ b) & (a ^ sum) & 0x80 != 0);
+
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        self.register_p.set(CpuFlags::CARRY, sum > 0xff);
+        self.register_a = sum as u8;
+    }
+
     fn sub_from_register_a(&mut self, data: u8) {
-        self.add_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        let a = self.register_a as i16;
+        let b = data as i16;
+        let carry = self.register_p.contains(CpuFlags::CARRY) as i16;
+
+        // flags are the binary ones even in decimal mode
+        self.add_binary_to_register_a(((data as i8).wrapping_neg().wrapping_sub(1)) as u8);
+
+        if self.decimal_active() {
+            let mut low = (a & 0x0f) - (b & 0x0f) + carry - 1;
+            if low < 0 {
+                low = ((low - 0x06) & 0x0f) - 0x10;
+            }
+            let mut difference = (a & 0xf0) - (b & 0xf0) + low;
+            if difference < 0 {
+                difference -= 0x60;
+            }
+            self.register_a = difference as u8;
+        }
     }
 
     fn stack_pop(&mut self) -> u8 {
@@ -259,6 +519,21 @@ impl<'a> CPU<'a> {
         hi << 8 | lo
     }
 
+    // B and bit 5 only exist on the stack copy of P: bit 5 always reads as 1,
+    // B is set when pushed by PHP/BRK and clear when pushed by IRQ/NMI
+    fn stack_push_status(&mut self, break_flag: bool) {
+        let mut flags = self.register_p;
+        flags.set(CpuFlags::BREAK, break_flag);
+        flags.insert(CpuFlags::UNUSED);
+        self.stack_push(flags.bits());
+    }
+
+    fn stack_pop_status(&mut self) {
+        self.register_p.bits = self.stack_pop();
+        self.register_p.remove(CpuFlags::BREAK);
+        self.register_p.insert(CpuFlags::UNUSED);
+    }
+
     pub fn update_pc(&mut self, opcode: &&OpCode, pc_state: u16) {
         self.bus.tick(opcode.cycles);
 
@@ -267,7 +542,21 @@ impl<'a> CPU<'a> {
         }
     }
 
+    fn check_decimal_mode(&mut self) {
+        if self.warn_on_decimal
+            && !self.decimal_enabled
+            && self.register_p.contains(CpuFlags::DECIMAL_MODE)
+        {
+            self.decimal_warnings += 1;
+        }
+    }
+
+    pub fn decimal_warnings(&self) -> u64 {
+        self.decimal_warnings
+    }
+
     pub fn adc(&mut self, mode: &AddressingMode) {
+        self.check_decimal_mode();
         let (address, page_cross) = self.get_operand_address(mode);
         let value = self.memory_read(address);
         self.add_to_register_a(value);
@@ -540,10 +829,7 @@ impl<'a> CPU<'a> {
     }
 
     pub fn php(&mut self) {
-        let mut flags = self.register_p.clone();
-        flags.insert(CpuFlags::BREAK);
-        flags.insert(CpuFlags::UNUSED);
-        self.stack_push(flags.bits());
+        self.stack_push_status(true);
     }
 
     pub fn pla(&mut self) {
@@ -552,9 +838,7 @@ impl<'a> CPU<'a> {
     }
 
     pub fn plp(&mut self) {
-        self.register_p.bits = self.stack_pop();
-        self.register_p.remove(CpuFlags::BREAK);
-        self.register_p.insert(CpuFlags::UNUSED);
+        self.stack_pop_status();
     }
 
     pub fn rol_accumulator(&mut self) {
@@ -635,9 +919,7 @@ impl<'a> CPU<'a> {
     }
 
     pub fn rti(&mut self) {
-        self.register_p.bits = self.stack_pop();
-        self.register_p.remove(CpuFlags::BREAK);
-        self.register_p.insert(CpuFlags::UNUSED);
+        self.stack_pop_status();
 
         self.register_pc = self.stack_pop_u16();
     }
@@ -647,9 +929,10 @@ impl<'a> CPU<'a> {
     }
 
     pub fn sbc(&mut self, mode: &AddressingMode) {
+        self.check_decimal_mode();
         let (address, page_cross) = self.get_operand_address(&mode);
         let value = self.memory_read(address);
-        self.add_to_register_a(((value as i8).wrapping_neg().wrapping_sub(1)) as u8);
+        self.sub_from_register_a(value);
 
         if page_cross {
             self.bus.tick(1);
@@ -714,18 +997,28 @@ impl<'a> CPU<'a> {
 
     // unofficial opcodes
 
+    pub fn jam(&mut self) {
+        if self.halt_on_jam {
+            // stay on the opcode, the CPU never fetches again
+            self.register_pc = self.register_pc.wrapping_sub(1);
+            self.jammed = true;
+        }
+    }
+
     pub fn dcp(&mut self, mode: &AddressingMode) {
         let (address, _) = self.get_operand_address(mode);
         let mut value = self.memory_read(address);
 
-        value = value.wrapping_sub(value);
+        value = value.wrapping_sub(1);
 
         self.memory_write(address, value);
         if value <= self.register_a {
             self.register_p.insert(CpuFlags::CARRY);
+        } else {
+            self.register_p.remove(CpuFlags::CARRY);
         }
 
-        self.update_zero_and_negative_flags(value.wrapping_sub(value));
+        self.update_zero_and_negative_flags(self.register_a.wrapping_sub(value));
     }
 
     pub fn rla(&mut self, mode: &AddressingMode) {
@@ -839,10 +1132,14 @@ impl<'a> CPU<'a> {
     // all unofficial NOP'S are just {} in assembly code
 
     pub fn lax(&mut self, mode: &AddressingMode) {
-        let (address, _) = self.get_operand_address(mode);
+        let (address, page_cross) = self.get_operand_address(mode);
         let value = self.memory_read(address);
         self.set_register_a(value);
         self.register_x = self.register_a;
+
+        if page_cross {
+            self.bus.tick(1);
+        }
     }
 
     pub fn sax(&mut self, mode: &AddressingMode) {
@@ -969,24 +1266,80 @@ impl<'a> CPU<'a> {
         }
     }
 
+    /// Raises an NMI edge, serviced before the next instruction like one
+    /// coming from the PPU.
+    pub fn assert_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    /// Holds the IRQ line low until `clear_irq`, it is serviced before every
+    /// instruction while the interrupt disable flag is clear.
+    pub fn assert_irq(&mut self) {
+        self.irq_line = true;
+    }
+
+    pub fn clear_irq(&mut self) {
+        self.irq_line = false;
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        self.irq_line
+    }
+
+    // the byte after the opcode is padding, the handler returns past it
+    pub fn brk(&mut self) {
+        self.stack_push_u16(self.register_pc.wrapping_add(1));
+        self.stack_push_status(true);
+        self.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
+        self.register_pc = self.memory_read_u16(0xfffe);
+    }
+
+    pub fn interrupt_irq(&mut self) {
+        self.interrupt(IRQ);
+    }
+
     pub fn interrupt(&mut self, interrupt: Interrupt) {
         self.stack_push_u16(self.register_pc);
-        let mut flag = self.register_p.clone();
-        flag.set(CpuFlags::BREAK, interrupt.binary_flag_mask & 0b010000 == 1);
-        flag.set(CpuFlags::UNUSED, interrupt.binary_flag_mask & 0b100000 == 1);
-
-        self.stack_push(flag.bits);
+        self.stack_push_status(interrupt.binary_flag_mask & CpuFlags::BREAK.bits() != 0);
         self.register_p.insert(CpuFlags::INTERRUPT_DISABLE);
 
         self.bus.tick(interrupt.cpu_cycles);
         self.register_pc = self.memory_read_u16(interrupt.vector_address);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> RunSummary {
         self.load(program);
         self.reset();
         self.register_pc = 0x0600;
-        self.run()
+        self.brk_ends_run = true;
+        self.run();
+
+        let stop_reason = match self.breakpoint_hit {
+            Some(pc) => StopReason::Breakpoint(pc),
+            None if self.jammed => StopReason::Jammed,
+            None => StopReason::Break,
+        };
+
+        RunSummary {
+            instructions: self.instruction_count,
+            registers: self.save_state(),
+            stop_reason,
+        }
+    }
+
+    /// Runs whole instructions until at least `cycles` CPU cycles went by and
+    /// returns how many actually did, the last instruction can overshoot.
+    pub fn run_cycles(&mut self, cycles: usize) -> Result<usize, CpuError> {
+        let start = self.bus.cycles();
+        self.run_with_callback(|cpu| {
+            if cpu.bus.cycles() - start >= cycles {
+                cpu.stop();
+            }
+        })?;
+        if self.jammed {
+            return Err(CpuError::Jammed(self.register_pc));
+        }
+        Ok(self.bus.cycles() - start)
     }
 
     pub fn load(&mut self, program: Vec<u8>) {
@@ -1001,35 +1354,711 @@ impl<'a> CPU<'a> {
         self.register_y = 0;
         self.register_sp = STACK_RESET;
         self.register_p = CpuFlags::from_bits_truncate(0b100100);
+        self.jammed = false;
+        self.instruction_count = 0;
+        self.cycles_at_reset = self.bus.cycles();
 
         self.register_pc = self.memory_read_u16(0xFFFC);
     }
 
     pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+        self.run_with_callback(|_| {}).unwrap();
     }
 
-    pub fn run_with_callback<F>(&mut self, mut callback: F)
+    /// Executes one instruction, servicing a pending interrupt first, and
+    /// returns the cycles it took. Does nothing once the CPU is jammed.
+    pub fn step(&mut self) -> Result<u8, CpuError> {
+        if self.jammed {
+            return Ok(0);
+        }
+        let start = self.bus.cycles();
+        self.poll_interrupts();
+        self.program_ended = self.execute(&ASSEMBLER)?;
+        Ok((self.bus.cycles() - start) as u8)
+    }
+
+    fn poll_interrupts(&mut self) {
+        let nmi = self.bus.poll_nmi_status().is_some() || std::mem::take(&mut self.nmi_pending);
+        if nmi {
+            self.interrupt(NMI);
+        } else if (self.irq_line || self.bus.poll_irq_status())
+            && !self.register_p.contains(CpuFlags::INTERRUPT_DISABLE)
+        {
+            self.interrupt_irq();
+        }
+    }
+
+    // runs the instruction at the PC, true when it ends the program
+    fn execute(&mut self, assembler: &Assembler) -> Result<bool, CpuError> {
+        if self.history.is_some() {
+            let line = trace(self);
+            if let Some(history) = self.history.as_mut() {
+                history.record(line);
+            }
+        }
+
+        self.bus.set_current_pc(self.register_pc);
+
+        let pc = self.register_pc;
+        let code = self.memory_read(pc);
+        let expected = self
+            .cycle_validator
+            .as_ref()
+            .map(|validator| validator.expected_cycles(self, code));
+        let start = self.bus.cycles();
+        self.register_pc += 1;
+
+        let program_ends = assembler.interpret(self, code)?;
+        self.instruction_count += 1;
+
+        // BRK and JAM end the run without ticking, nothing to compare
+        if let (Some(expected), false) = (expected, program_ends || self.jammed) {
+            let actual = self.bus.cycles() - start;
+            if let Some(validator) = self.cycle_validator.as_mut() {
+                validator.check(pc, code, expected, actual);
+            }
+        }
+
+        Ok(program_ends)
+    }
+
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> Result<(), CpuError>
     where
         F: FnMut(&mut CPU),
     {
-        let assembler = Assembler::new();
+        // resuming from a breakpoint must not stop on the same instruction again
+        let mut resume_at = self.breakpoint_hit.take();
 
         loop {
-            if let Some(_nmi) = self.bus.poll_nmi_status() {
-                self.interrupt(NMI);
+            // interrupts are taken before the callback so it sees the handler
+            self.poll_interrupts();
+
+            if !self.mnemonic_breakpoints.is_empty() && resume_at != Some(self.register_pc) {
+                let code = self.peek(self.register_pc);
+                if self.hits_mnemonic_breakpoint(code) {
+                    self.breakpoint_hit = Some(self.register_pc);
+                    break;
+                }
             }
+            resume_at = None;
 
-            let code = self.memory_read(self.register_pc);
-            self.register_pc += 1;
-
-            let program_ends = assembler.interpret(self, code);
+            callback(self);
+            if self.stop_requested {
+                self.stop_requested = false;
+                break;
+            }
 
-            if program_ends {
+            // the interrupts were polled above, so `step` finds none left
+            self.step()?;
+            if std::mem::take(&mut self.program_ended) || self.jammed {
                 break;
-            } else {
-                callback(self);
             }
         }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::cartridge::{Mirroring, Rom};
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+
+    fn test_cpu() -> CPU<'static> {
+        CPU::new(
+            BUS::new(
+                test_rom(),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_uninitialized_ram_read_is_reported() {
+        let mut cpu = test_cpu();
+        cpu.bus.track_uninitialized_ram();
+        // LDA $10; STA $11; LDA $11; BRK
+        cpu.load_and_run(vec![0xa5, 0x10, 0x85, 0x11, 0xa5, 0x11, 0x00]);
+
+        let reads = cpu.bus.uninitialized_reads();
+        assert_eq!(reads.len(), 1);
+        assert_eq!(reads[0].pc, 0x0600);
+        assert_eq!(reads[0].address, 0x0010);
+    }
+
+    #[test]
+    fn test_mnemonic_breakpoint_pauses_before_jsr() {
+        let mut cpu = test_cpu();
+        cpu.add_mnemonic_breakpoint("jsr");
+        // LDX #$01; JSR $0608; BRK; NOP; INX; RTS
+        cpu.load_and_run(vec![
+            0xa2, 0x01, 0x20, 0x08, 0x06, 0x00, 0xea, 0xea, 0xe8, 0x60,
+        ]);
+
+        assert_eq!(cpu.breakpoint_hit, Some(0x0602));
+        assert_eq!(cpu.register_pc, 0x0602);
+        assert_eq!(cpu.register_x, 1);
+
+        cpu.run();
+        assert_eq!(cpu.breakpoint_hit, None);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    // pointer at $FF, high byte must come from $00 and not $0100
+    fn write_wrapping_pointer(cpu: &mut CPU) {
+        cpu.memory_write(0x00ff, 0x34);
+        cpu.memory_write(0x0000, 0x02);
+        cpu.memory_write(0x0100, 0x05);
+        cpu.memory_write(0x0234, 0x77);
+        cpu.memory_write(0x0235, 0x66);
+        cpu.memory_write(0x0534, 0x11);
+        cpu.memory_write(0x0535, 0x11);
+    }
+
+    #[test]
+    fn test_indirect_x_pointer_wraps_in_zero_page() {
+        let mut cpu = test_cpu();
+        write_wrapping_pointer(&mut cpu);
+        // LDX #$0F; LDA ($F0,X); BRK
+        cpu.load_and_run(vec![0xa2, 0x0f, 0xa1, 0xf0, 0x00]);
+
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn test_indirect_y_pointer_wraps_in_zero_page() {
+        let mut cpu = test_cpu();
+        write_wrapping_pointer(&mut cpu);
+        // LDY #$01; LDA ($FF),Y; BRK
+        cpu.load_and_run(vec![0xa0, 0x01, 0xb1, 0xff, 0x00]);
+
+        assert_eq!(cpu.register_a, 0x66);
+    }
+
+    #[test]
+    fn test_instruction_count_includes_loop_iterations() {
+        let mut cpu = test_cpu();
+        // LDX #$03; loop: DEX; BNE loop; BRK
+        cpu.load_and_run(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+
+        assert_eq!(cpu.instruction_count(), 1 + 3 * 2 + 1);
+
+        cpu.reset();
+        assert_eq!(cpu.instruction_count(), 0);
+    }
+
+    #[test]
+    fn test_total_cycles_restart_on_reset() {
+        let mut cpu = test_cpu();
+        // LDX #$03; loop: DEX; BNE loop; BRK
+        cpu.load_and_run(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+
+        // LDX, 3 DEX, 2 taken BNE, 1 not taken, the ending BRK isn't run
+        assert_eq!(cpu.total_cycles(), 2 + 3 * 2 + 2 * 3 + 2);
+        assert_eq!(cpu.total_cycles(), cpu.bus.cycles());
+
+        cpu.reset();
+        assert_eq!(cpu.total_cycles(), 0);
+        assert!(cpu.bus.cycles() > 0);
+    }
+
+    // plays a 17 byte DMC sample at the fastest rate while counting X down
+    // from 256, 8 times over
+    fn dmc_program_cycles(enable: u8) -> usize {
+        let mut cpu = test_cpu();
+        cpu.load_and_run(vec![
+            0xa9, 0x0f, 0x8d, 0x10, 0x40, // LDA #$0F; STA $4010
+            0xa9, 0x01, 0x8d, 0x13, 0x40, // LDA #$01; STA $4013
+            0xa9, enable, 0x8d, 0x15, 0x40, // LDA #enable; STA $4015
+            0xa0, 0x08, // LDY #$08
+            0xa2, 0x00, // outer: LDX #$00
+            0xca, 0xd0, 0xfd, // inner: DEX; BNE inner
+            0x88, 0xd0, 0xf8, // DEY; BNE outer
+            0x00,
+        ]);
+        cpu.total_cycles()
+    }
+
+    #[test]
+    fn test_dmc_fetches_stall_the_cpu() {
+        // the loop runs for ~10k cycles, long enough to fetch every byte
+        assert_eq!(dmc_program_cycles(0x10), dmc_program_cycles(0x00) + 17 * 4);
+    }
+
+    // starts a DMC sample, waits `delay` loops and reads the first controller
+    // 12 times, a DMC fetch halting the read on $4016 clocks it once more
+    fn joypad_reads_with_dmc(accurate_dmc_conflicts: bool, delay: u8) -> Vec<u8> {
+        let mut cpu = test_cpu();
+        cpu.bus.accurate_dmc_conflicts = accurate_dmc_conflicts;
+        cpu.load_and_run(vec![
+            0xa9, 0x0f, 0x8d, 0x10, 0x40, // LDA #$0F; STA $4010
+            0xa9, 0x01, 0x8d, 0x13, 0x40, // LDA #$01; STA $4013
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10; STA $4015
+            0xa2, delay, // LDX #delay
+            0xca, 0xd0, 0xfd, // wait: DEX; BNE wait
+            0xa9, 0x01, 0x8d, 0x16, 0x40, // LDA #$01; STA $4016
+            0xa9, 0x00, 0x8d, 0x16, 0x40, // LDA #$00; STA $4016
+            0xa0, 0x00, // LDY #$00
+            0xad, 0x16, 0x40, // read: LDA $4016
+            0x99, 0x10, 0x00, // STA $0010,Y
+            0xc8, 0xc0, 0x0c, 0xd0, 0xf5, // INY; CPY #$0C; BNE read
+            0x00,
+        ]);
+        (0..12).map(|i| cpu.memory_read(0x10 + i) & 1).collect()
+    }
+
+    #[test]
+    fn test_dmc_fetch_on_joypad_read_clocks_it_again() {
+        // no buttons are pressed, the controller reports 1 after 8 reads
+        assert_eq!(
+            joypad_reads_with_dmc(false, 35),
+            vec![0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1]
+        );
+        // this delay puts a sample fetch on a read of $4016
+        assert_eq!(
+            joypad_reads_with_dmc(true, 35),
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_run_cycles_stops_on_instruction_boundary() {
+        let mut cpu = test_cpu();
+        // loop: LDA $0010 (4 cycles); JMP loop (3 cycles)
+        cpu.load(vec![0xad, 0x10, 0x00, 0x4c, 0x00, 0x06]);
+        cpu.reset();
+        cpu.register_pc = 0x0600;
+
+        // 14 loops make 98 cycles, the next LDA crosses 100
+        assert_eq!(cpu.run_cycles(100).unwrap(), 102);
+        assert_eq!(cpu.instruction_count(), 29);
+        assert_eq!(cpu.register_pc, 0x0603);
+
+        assert_eq!(cpu.run_cycles(1).unwrap(), 3);
+        assert_eq!(cpu.register_pc, 0x0600);
+    }
+
+    #[test]
+    fn test_load_and_run_summary() {
+        let mut cpu = test_cpu();
+        // LDA #$05; TAX; INX; BRK
+        let summary = cpu.load_and_run(vec![0xa9, 0x05, 0xaa, 0xe8, 0x00]);
+
+        assert_eq!(summary.instructions, 4);
+        assert_eq!(summary.registers.a, 0x05);
+        assert_eq!(summary.registers.x, 0x06);
+        assert_eq!(summary.stop_reason, StopReason::Break);
+
+        // *JAM
+        let summary = cpu.load_and_run(vec![0x02]);
+        assert_eq!(summary.stop_reason, StopReason::Jammed);
+    }
+
+    #[test]
+    fn test_decimal_mode_warning() {
+        let mut cpu = test_cpu();
+        // ADC #$01; SED; ADC #$09; BRK
+        let program = vec![0x69, 0x01, 0xf8, 0x69, 0x09, 0x00];
+        cpu.load_and_run(program.clone());
+        assert_eq!(cpu.decimal_warnings(), 0);
+
+        cpu.warn_on_decimal = true;
+        cpu.load_and_run(program);
+        assert_eq!(cpu.decimal_warnings(), 1);
+        // still binary arithmetic
+        assert_eq!(cpu.register_a, 0x0a);
+    }
+
+    #[test]
+    fn test_php_pushes_break_and_unused() {
+        let mut cpu = test_cpu();
+        // PHP; PLA; BRK
+        cpu.load_and_run(vec![0x08, 0x68, 0x00]);
+
+        assert_eq!(cpu.register_a & 0b0011_0000, 0b0011_0000);
+    }
+
+    #[test]
+    fn test_php_plp_preserves_flags() {
+        let mut cpu = test_cpu();
+        // SEC; SEI; LDA #$80; PHP; CLC; CLI; LDA #$01; PLP; BRK
+        cpu.load_and_run(vec![
+            0x38, 0x78, 0xa9, 0x80, 0x08, 0x18, 0x58, 0xa9, 0x01, 0x28, 0x00,
+        ]);
+
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+        assert!(cpu.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert!(cpu.register_p.contains(CpuFlags::NEGATIVE));
+        assert!(cpu.register_p.contains(CpuFlags::UNUSED));
+        assert!(!cpu.register_p.contains(CpuFlags::BREAK));
+        assert!(!cpu.register_p.contains(CpuFlags::ZERO));
+    }
+
+    #[test]
+    fn test_plp_forces_unused_and_clears_break() {
+        let mut cpu = test_cpu();
+        // LDA #$10; PHA; PLP; BRK
+        cpu.load_and_run(vec![0xa9, 0x10, 0x48, 0x28, 0x00]);
+
+        assert_eq!(cpu.register_p.bits(), 0b0010_0000);
+    }
+
+    #[test]
+    fn test_jam_halts_cpu() {
+        let mut cpu = test_cpu();
+        // INX; JAM; INX; BRK
+        cpu.load_and_run(vec![0xe8, 0x02, 0xe8, 0x00]);
+
+        assert!(cpu.jammed);
+        assert_eq!(cpu.register_pc, 0x0601);
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_jam_as_nop_when_halting_disabled() {
+        let mut cpu = test_cpu();
+        cpu.halt_on_jam = false;
+        // INX; JAM; INX; BRK
+        cpu.load_and_run(vec![0xe8, 0x02, 0xe8, 0x00]);
+
+        assert!(!cpu.jammed);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_run_cycles_reports_a_jam() {
+        // INX; JAM at the reset vector
+        let mut prg = vec![0xea; 0x4000];
+        prg[0] = 0xe8;
+        prg[1] = 0x02;
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut cpu = CPU::new(
+            BUS::new(
+                Rom::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        cpu.reset();
+
+        assert_eq!(cpu.run_cycles(100), Err(CpuError::Jammed(0x8001)));
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    fn vectored_cpu() -> CPU<'static> {
+        let mut prg = vec![0xea; 0x4000];
+        // NMI at $8100, reset at $8000, IRQ at $8200
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0x81, 0x00, 0x80, 0x00, 0x82]);
+        let mut cpu = CPU::new(
+            BUS::new(
+                Rom::from_parts(prg, vec![0; 0x2000], 0, Mirroring::Horizontal),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        cpu.reset();
+        cpu
+    }
+
+    // services pending interrupts, then stops before the next instruction
+    fn poll_interrupts(cpu: &mut CPU) {
+        cpu.run_with_callback(|cpu| cpu.stop()).unwrap();
+    }
+
+    #[test]
+    fn test_asserted_nmi_vectors_through_fffa() {
+        let mut cpu = vectored_cpu();
+        cpu.assert_nmi();
+        poll_interrupts(&mut cpu);
+        assert_eq!(cpu.register_pc, 0x8100);
+
+        // an edge, only taken once
+        poll_interrupts(&mut cpu);
+        assert_eq!(cpu.register_pc, 0x8100);
+    }
+
+    #[test]
+    fn test_irq_line_honors_interrupt_disable() {
+        let mut cpu = vectored_cpu();
+        cpu.assert_irq();
+        poll_interrupts(&mut cpu);
+        assert_eq!(cpu.register_pc, 0x8000);
+
+        cpu.register_p.remove(CpuFlags::INTERRUPT_DISABLE);
+        let cycles = cpu.bus.cycles();
+        poll_interrupts(&mut cpu);
+        assert_eq!(cpu.register_pc, 0x8200);
+        assert!(cpu.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
+        assert_eq!(cpu.bus.cycles() - cycles, 7);
+
+        cpu.clear_irq();
+        assert!(!cpu.irq_asserted());
+    }
+
+    #[test]
+    fn test_apu_frame_irq_waits_for_cli() {
+        let mut cpu = vectored_cpu();
+        cpu.load(vec![
+            0xa0, 0x18, // LDY #$18
+            0xca, 0xd0, 0xfd, // DEX; BNE -3
+            0x88, 0xd0, 0xfa, // DEY; BNE -6, about 31000 cycles in all
+            0x58, // CLI
+            0x4c, 0x09, 0x06, // JMP $0609
+        ]);
+        cpu.register_pc = 0x0600;
+
+        let mut pending_at_cli = false;
+        cpu.run_with_callback(|cpu| {
+            assert!(cpu.register_pc < 0x8000 || cpu.register_pc == 0x8200);
+            if cpu.register_pc == 0x0608 {
+                pending_at_cli = cpu.bus.poll_irq_status();
+            }
+            if cpu.register_pc == 0x8200 {
+                cpu.stop();
+            }
+        })
+        .unwrap();
+
+        assert!(pending_at_cli);
+        assert_eq!(cpu.register_pc, 0x8200);
+        let pushed_pc = cpu.memory_read(STACK + cpu.register_sp.wrapping_add(2) as u16);
+        assert_eq!(pushed_pc, 0x09);
+    }
+
+    #[test]
+    fn test_dcp_decrements_then_compares() {
+        let mut cpu = test_cpu();
+        cpu.memory_write(0x10, 0x43);
+        // LDA #$42; DCP $10; BRK
+        cpu.load_and_run(vec![0xa9, 0x42, 0xc7, 0x10, 0x00]);
+        assert_eq!(cpu.memory_read(0x10), 0x42);
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+        assert!(cpu.register_p.contains(CpuFlags::ZERO));
+        assert!(!cpu.register_p.contains(CpuFlags::NEGATIVE));
+
+        cpu.memory_write(0x10, 0x80);
+        // SEC; LDA #$10; DCP $10; BRK
+        cpu.load_and_run(vec![0x38, 0xa9, 0x10, 0xc7, 0x10, 0x00]);
+        assert_eq!(cpu.memory_read(0x10), 0x7f);
+        assert!(!cpu.register_p.contains(CpuFlags::CARRY));
+        assert!(!cpu.register_p.contains(CpuFlags::ZERO));
+        assert!(cpu.register_p.contains(CpuFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_brk_vectors_through_fffe() {
+        let mut cpu = vectored_cpu();
+        cpu.load(vec![0x00, 0xff]);
+        cpu.register_pc = 0x0600;
+        cpu.register_p.remove(CpuFlags::INTERRUPT_DISABLE);
+        let start = cpu.bus.cycles();
+
+        cpu.run_with_callback(|cpu| {
+            if cpu.register_pc == 0x8200 {
+                cpu.stop();
+            }
+        })
+        .unwrap();
+
+        assert_eq!(cpu.register_pc, 0x8200);
+        assert_eq!(cpu.bus.cycles() - start, 7);
+        assert!(cpu.register_p.contains(CpuFlags::INTERRUPT_DISABLE));
+        let status = cpu.stack_pop();
+        assert_eq!(status, 0b0011_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x0602);
+    }
+
+    #[test]
+    fn test_nmi_pushes_unused_without_break() {
+        let mut cpu = test_cpu();
+        cpu.register_p = CpuFlags::from_bits_truncate(0b0000_0001);
+        cpu.interrupt(NMI);
+
+        let pushed = cpu.memory_read(STACK + cpu.register_sp.wrapping_add(1) as u16);
+        assert_eq!(pushed, 0b0010_0001);
+    }
+
+    fn indexed_read_cycles(program: Vec<u8>, x: u8) -> usize {
+        let mut cpu = test_cpu();
+        cpu.load(program);
+        cpu.register_pc = 0x0600;
+        cpu.register_x = x;
+        cpu.run_cycles(1).unwrap()
+    }
+
+    #[test]
+    fn test_page_cross_costs_a_cycle_on_reads() {
+        // LDA $00FF,X
+        assert_eq!(indexed_read_cycles(vec![0xbd, 0xff, 0x00], 0), 4);
+        assert_eq!(indexed_read_cycles(vec![0xbd, 0xff, 0x00], 1), 5);
+        // LAX $00FF,Y
+        let mut cpu = test_cpu();
+        cpu.load(vec![0xbf, 0xff, 0x00]);
+        cpu.register_pc = 0x0600;
+        cpu.register_y = 1;
+        assert_eq!(cpu.run_cycles(1).unwrap(), 5);
+    }
+
+    fn beq_cycles(at: u16, offset: u8, zero: bool) -> (usize, u16) {
+        let mut cpu = test_cpu();
+        cpu.memory_write(at, 0xf0);
+        cpu.memory_write(at + 1, offset);
+        cpu.register_pc = at;
+        cpu.register_p.set(CpuFlags::ZERO, zero);
+        let cycles = cpu.run_cycles(1).unwrap();
+        (cycles, cpu.register_pc)
+    }
+
+    #[test]
+    fn test_branch_cycle_costs() {
+        // not taken
+        assert_eq!(beq_cycles(0x0600, 0x10, false), (2, 0x0602));
+        // taken, target on the same page
+        assert_eq!(beq_cycles(0x0600, 0x10, true), (3, 0x0612));
+        // taken, target on the next page
+        assert_eq!(beq_cycles(0x06f0, 0x20, true), (4, 0x0712));
+        // taken backwards onto the previous page
+        assert_eq!(beq_cycles(0x0700, 0xf0, true), (4, 0x06f2));
+    }
+
+    #[test]
+    fn test_step_runs_one_instruction() {
+        let mut cpu = test_cpu();
+        // LDA #$05; LDA $00FF,X; BEQ +2
+        cpu.load(vec![0xa9, 0x05, 0xbd, 0xff, 0x00, 0xf0, 0x02]);
+        cpu.register_pc = 0x0600;
+        cpu.register_x = 1;
+
+        assert_eq!(cpu.step().unwrap(), 2);
+        assert_eq!(cpu.register_a, 0x05);
+        assert_eq!(cpu.register_pc, 0x0602);
+        assert_eq!(cpu.step().unwrap(), 5);
+        assert_eq!(cpu.register_pc, 0x0605);
+        // $0100 is zero, so the branch is taken
+        assert_eq!(cpu.step().unwrap(), 3);
+        assert_eq!(cpu.register_pc, 0x0609);
+        assert_eq!(cpu.instruction_count(), 3);
+    }
+
+    // CPU cycle at which the NMI handler at $8100 starts, running NOP; NOP;
+    // JMP $0600 until the first vblank
+    fn nmi_handler_cycle(nmi_delay: u8) -> usize {
+        let mut cpu = vectored_cpu();
+        cpu.load(vec![0xea, 0xea, 0x4c, 0x00, 0x06]);
+        cpu.register_pc = 0x0600;
+        cpu.bus.ppu_mut().nmi_delay = nmi_delay;
+        cpu.bus.memory_write(0x2000, 0b1000_0000);
+
+        let mut handler = 0;
+        cpu.run_with_callback(|cpu| {
+            if cpu.register_pc == 0x8100 {
+                handler = cpu.bus.cycles();
+                cpu.stop();
+            }
+        })
+        .unwrap();
+        handler
+    }
+
+    #[test]
+    fn test_nmi_is_taken_at_the_boundary_after_the_delay() {
+        // vblank is set 241 * 341 dots in, two thirds into cycle 27393, and
+        // the loop's next instructions end at 27395 and 27398
+        assert_eq!(nmi_handler_cycle(0), 27395 + NMI.cpu_cycles as usize);
+        assert_eq!(nmi_handler_cycle(1), 27395 + NMI.cpu_cycles as usize);
+        // two cycles of latency miss the NOP's boundary
+        assert_eq!(nmi_handler_cycle(2), 27398 + NMI.cpu_cycles as usize);
+    }
+
+    #[test]
+    fn test_step_services_pending_nmi_first() {
+        let mut cpu = vectored_cpu();
+        cpu.register_pc = 0x0600;
+        cpu.assert_nmi();
+
+        // the NMI sequence, then the NOP at the handler
+        assert_eq!(cpu.step().unwrap(), NMI.cpu_cycles + 2);
+        assert_eq!(cpu.register_pc, 0x8101);
+    }
+
+    fn decimal_cpu(a: u8, carry: bool) -> CPU<'static> {
+        let mut cpu = test_cpu();
+        cpu.decimal_enabled = true;
+        cpu.register_p.insert(CpuFlags::DECIMAL_MODE);
+        cpu.register_p.set(CpuFlags::CARRY, carry);
+        cpu.register_a = a;
+        cpu
+    }
+
+    #[test]
+    fn test_decimal_adc_carries_between_digits() {
+        let mut cpu = decimal_cpu(0x09, false);
+        cpu.add_to_register_a(0x01);
+        assert_eq!(cpu.register_a, 0x10);
+        assert!(!cpu.register_p.contains(CpuFlags::CARRY));
+
+        let mut cpu = decimal_cpu(0x99, false);
+        cpu.add_to_register_a(0x01);
+        assert_eq!(cpu.register_a, 0x00);
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+
+        let mut cpu = decimal_cpu(0x58, true);
+        cpu.add_to_register_a(0x46);
+        assert_eq!(cpu.register_a, 0x05);
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_decimal_sbc_borrows_between_digits() {
+        let mut cpu = decimal_cpu(0x46, true);
+        cpu.sub_from_register_a(0x12);
+        assert_eq!(cpu.register_a, 0x34);
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+
+        let mut cpu = decimal_cpu(0x10, true);
+        cpu.sub_from_register_a(0x01);
+        assert_eq!(cpu.register_a, 0x09);
+        assert!(cpu.register_p.contains(CpuFlags::CARRY));
+
+        let mut cpu = decimal_cpu(0x00, true);
+        cpu.sub_from_register_a(0x01);
+        assert_eq!(cpu.register_a, 0x99);
+        assert!(!cpu.register_p.contains(CpuFlags::CARRY));
+    }
+
+    #[test]
+    fn test_decimal_flag_ignored_unless_enabled() {
+        let mut cpu = test_cpu();
+        // SED; LDA #$09; CLC; ADC #$01; BRK
+        cpu.load_and_run(vec![0xf8, 0xa9, 0x09, 0x18, 0x69, 0x01, 0x00]);
+        assert_eq!(cpu.register_a, 0x0a);
+
+        let mut cpu = test_cpu();
+        cpu.decimal_enabled = true;
+        cpu.load_and_run(vec![0xf8, 0xa9, 0x09, 0x18, 0x69, 0x01, 0x00]);
+        assert_eq!(cpu.register_a, 0x10);
+    }
+
+    #[test]
+    fn test_state_survives_reset() {
+        let mut cpu = test_cpu();
+        // LDX #$03; loop: DEX; BNE loop; BRK
+        cpu.load_and_run(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x00]);
+        cpu.register_a = 0x12;
+        cpu.register_y = 0x34;
+        cpu.register_sp = 0xf0;
+        cpu.register_pc = 0x1234;
+        cpu.register_p = CpuFlags::from_bits_truncate(0b1100_0011);
+
+        let state = cpu.save_state();
+        cpu.reset();
+        assert_ne!(cpu.save_state(), state);
+
+        cpu.load_state(state.clone());
+        assert_eq!(cpu.save_state(), state);
+        assert_eq!(cpu.total_cycles(), 16);
     }
 }
@@ -0,0 +1,203 @@
+use super::cpu::{CpuFlags, CPU};
+
+// base cycles of every opcode, written out from the 6502 datasheet timings and
+// independent of the opcode definitions on purpose, 0 marks the JAM opcodes
+#[rustfmt::skip]
+const REFERENCE_CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
+
+// the extra cycles an instruction can take on top of its base count
+enum Penalty {
+    None,
+    // +1 when the indexed address lands on another page
+    AbsoluteX,
+    AbsoluteY,
+    IndirectY,
+    // +1 when taken, +1 more when the target is on another page
+    Branch,
+}
+
+fn penalty(code: u8) -> Penalty {
+    match code {
+        0x10 | 0x30 | 0x50 | 0x70 | 0x90 | 0xb0 | 0xd0 | 0xf0 => Penalty::Branch,
+        0x11 | 0x31 | 0x51 | 0x71 | 0xb1 | 0xb3 | 0xd1 | 0xf1 => Penalty::IndirectY,
+        0x19 | 0x39 | 0x59 | 0x79 | 0xb9 | 0xbb | 0xbe | 0xbf | 0xd9 | 0xf9 => Penalty::AbsoluteY,
+        0x1c | 0x1d | 0x3c | 0x3d | 0x5c | 0x5d | 0x7c | 0x7d | 0xbc | 0xbd | 0xdc | 0xdd
+        | 0xfc | 0xfd => Penalty::AbsoluteX,
+        _ => Penalty::None,
+    }
+}
+
+fn crosses_page(base: u16, index: u8) -> bool {
+    base & 0xff00 != base.wrapping_add(index as u16) & 0xff00
+}
+
+// bits 7-6 of a branch opcode pick the flag, bit 5 the value it must have
+fn branch_taken(cpu: &CPU, code: u8) -> bool {
+    let flag = match code >> 6 {
+        0 => CpuFlags::NEGATIVE,
+        1 => CpuFlags::OVERFLOW,
+        2 => CpuFlags::CARRY,
+        _ => CpuFlags::ZERO,
+    };
+    cpu.register_p.contains(flag) == (code & 0b0010_0000 != 0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleMismatch {
+    pub pc: u16,
+    pub code: u8,
+    pub expected: usize,
+    pub actual: usize,
+}
+
+/// Self-test for the CPU timing: every instruction's ticked cycles are
+/// compared with the reference table. Meant for tests, not for playing.
+pub struct CycleValidator {
+    table: [u8; 256],
+    mismatches: Vec<CycleMismatch>,
+}
+
+impl CycleValidator {
+    pub fn new() -> Self {
+        CycleValidator {
+            table: REFERENCE_CYCLES,
+            mismatches: vec![],
+        }
+    }
+
+    // evaluated before the instruction at the PC runs, operands are peeked
+    pub fn expected_cycles(&self, cpu: &CPU, code: u8) -> usize {
+        let pc = cpu.register_pc;
+        let operand = cpu.bus.peek(pc.wrapping_add(1));
+        let absolute = u16::from_le_bytes([operand, cpu.bus.peek(pc.wrapping_add(2))]);
+
+        let extra = match penalty(code) {
+            Penalty::None => 0,
+            Penalty::AbsoluteX => crosses_page(absolute, cpu.register_x) as usize,
+            Penalty::AbsoluteY => crosses_page(absolute, cpu.register_y) as usize,
+            Penalty::IndirectY => {
+                let pointer = u16::from_le_bytes([
+                    cpu.bus.peek(operand as u16),
+                    cpu.bus.peek(operand.wrapping_add(1) as u16),
+                ]);
+                crosses_page(pointer, cpu.register_y) as usize
+            }
+            Penalty::Branch if branch_taken(cpu, code) => {
+                let next = pc.wrapping_add(2);
+                let target = next.wrapping_add(operand as i8 as u16);
+                1 + (next & 0xff00 != target & 0xff00) as usize
+            }
+            Penalty::Branch => 0,
+        };
+        self.table[code as usize] as usize + extra
+    }
+
+    pub fn check(&mut self, pc: u16, code: u8, expected: usize, actual: usize) {
+        if expected != actual {
+            self.mismatches.push(CycleMismatch {
+                pc,
+                code,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    /// Every instruction whose cycles disagreed with the table, in the order
+    /// they ran. A run with correct timing leaves this empty.
+    pub fn mismatches(&self) -> &[CycleMismatch] {
+        &self.mismatches
+    }
+}
+
+impl Default for CycleValidator {
+    fn default() -> Self {
+        CycleValidator::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::bus::BUS;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+
+    fn run_validated(program: Vec<u8>, validator: CycleValidator) -> CPU<'static> {
+        let mut cpu = CPU::new(
+            BUS::new(
+                test_rom(),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        cpu.cycle_validator = Some(validator);
+        cpu.load_and_run(program);
+        cpu
+    }
+
+    #[test]
+    fn test_page_cross_penalties_match_reference() {
+        let cpu = run_validated(
+            vec![
+                0xa2, 0x01, // LDX #$01
+                0xbd, 0xff, 0x10, // LDA $10FF,X crosses
+                0xbd, 0x00, 0x10, // LDA $1000,X doesn't
+                0xa0, 0xff, // LDY #$FF
+                0xb9, 0x01, 0x10, // LDA $1001,Y crosses
+                0xd0, 0x00, // BNE +0, not taken (A is 0)
+                0xf0, 0x00, // BEQ +0, taken
+            ],
+            CycleValidator::new(),
+        );
+
+        let validator = cpu.cycle_validator.as_ref().unwrap();
+        assert_eq!(validator.mismatches(), &[]);
+    }
+
+    #[test]
+    fn test_seeded_mismatch_is_reported() {
+        let mut validator = CycleValidator::new();
+        // claim INX takes 3 cycles
+        validator.table[0xe8] = 3;
+        let cpu = run_validated(vec![0xe8, 0xe8, 0x00], validator);
+
+        let validator = cpu.cycle_validator.as_ref().unwrap();
+        assert_eq!(
+            validator.mismatches(),
+            &[
+                CycleMismatch {
+                    pc: 0x0600,
+                    code: 0xe8,
+                    expected: 3,
+                    actual: 2
+                },
+                CycleMismatch {
+                    pc: 0x0601,
+                    code: 0xe8,
+                    expected: 3,
+                    actual: 2
+                },
+            ]
+        );
+    }
+}
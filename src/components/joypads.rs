@@ -1,3 +1,9 @@
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+
+use super::apu::TvSystem;
+use crate::timing;
+
 bitflags! {
     pub struct JoypadButton: u8 {
         const RIGHT             = 0b1000_0000;
@@ -11,18 +17,58 @@ bitflags! {
     }
 }
 
+const BUTTON_COUNT: u8 = 8;
+const TURBO_RATES: std::ops::RangeInclusive<u32> = 1..=30;
+
+// WASD for the d-pad, space/E for A/B
+pub fn default_keymap() -> HashMap<Keycode, JoypadButton> {
+    let mut keymap = HashMap::new();
+    keymap.insert(Keycode::W, JoypadButton::UP);
+    keymap.insert(Keycode::A, JoypadButton::LEFT);
+    keymap.insert(Keycode::S, JoypadButton::DOWN);
+    keymap.insert(Keycode::D, JoypadButton::RIGHT);
+    keymap.insert(Keycode::Space, JoypadButton::BUTTON_A);
+    keymap.insert(Keycode::E, JoypadButton::BUTTON_B);
+    keymap.insert(Keycode::Return, JoypadButton::START);
+    keymap.insert(Keycode::Tab, JoypadButton::SELECT);
+    keymap
+}
+
 pub struct Joypad {
+    /// Keys the frontend presses this pad's buttons with.
+    pub keymap: HashMap<Keycode, JoypadButton>,
     strobe_mode: bool,
     button_index: u8,
     button_status: JoypadButton,
+    turbo_held: JoypadButton,
+    turbo_rate: u32,
+    turbo_frame: u32,
+    turbo_on: bool,
+    // frames per second the autofire phase is counted in
+    frame_rate: u32,
 }
 
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
+            keymap: default_keymap(),
             strobe_mode: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0b0000_0000),
+            turbo_held: JoypadButton::empty(),
+            turbo_rate: 30,
+            turbo_frame: 0,
+            turbo_on: true,
+            frame_rate: timing::NTSC_FRAME_RATE.round() as u32,
+        }
+    }
+
+    // buttons as the console sees them, turbo buttons only count while their phase is on
+    fn buttons(&self) -> JoypadButton {
+        if self.turbo_on {
+            self.button_status | self.turbo_held
+        } else {
+            self.button_status
         }
     }
 
@@ -35,20 +81,261 @@ impl Joypad {
     }
 
     pub fn read(&mut self) -> u8 {
-        if self.button_index > 7 {
-            return 1;
+        // while strobe is high the shift register keeps reloading, so every
+        // read reports button A and nothing advances
+        if self.strobe_mode {
+            return self.buttons().bits & JoypadButton::BUTTON_A.bits;
         }
 
-        let value = (self.button_status.bits & (1 << self.button_index)) >> self.button_index;
-
-        if !self.strobe_mode && self.button_index <= 7 {
-            self.button_index += 1;
+        // official pads shift in 1s once the 8 buttons are out, the index
+        // stops here so it can't wrap around however long the game keeps reading
+        if self.button_index >= BUTTON_COUNT {
+            return 1;
         }
 
+        let value = (self.buttons().bits & (1 << self.button_index)) >> self.button_index;
+        self.button_index += 1;
+
         value
     }
 
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
         self.button_status.set(button, pressed);
     }
+
+    // the button is moved to `key`, whatever key it had before is unbound
+    pub fn set_mapping(&mut self, key: Keycode, button: JoypadButton) {
+        self.clear_mapping(button);
+        self.keymap.insert(key, button);
+    }
+
+    pub fn clear_mapping(&mut self, button: JoypadButton) {
+        self.keymap.retain(|_, mapped| *mapped != button);
+    }
+
+    /// Presses or releases the button mapped to `key`, false if it is unmapped.
+    pub fn set_key_pressed_status(&mut self, key: Keycode, pressed: bool) -> bool {
+        match self.keymap.get(&key) {
+            Some(&button) => {
+                self.set_button_pressed_status(button, pressed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_turbo_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.turbo_held.set(button, pressed);
+    }
+
+    /// Autofire rate in taps per second, clamped to 1-30Hz.
+    pub fn set_turbo_rate(&mut self, hz: u32) {
+        self.turbo_rate = hz.clamp(*TURBO_RATES.start(), *TURBO_RATES.end());
+    }
+
+    pub fn turbo_rate(&self) -> u32 {
+        self.turbo_rate
+    }
+
+    /// Times autofire in the frames of `tv_system`, PAL shows 50 a second.
+    pub fn set_tv_system(&mut self, tv_system: TvSystem) {
+        self.frame_rate = tv_system.frame_rate().round() as u32;
+        self.turbo_frame %= self.frame_rate;
+    }
+
+    // advances the autofire phase, the frontend calls this once per frame
+    pub fn next_frame(&mut self) {
+        self.turbo_frame = (self.turbo_frame + 1) % self.frame_rate;
+        // each tap is a press half followed by a release half
+        self.turbo_on = (self.turbo_frame * self.turbo_rate * 2 / self.frame_rate) & 1 == 0;
+    }
+
+    pub fn release_all(&mut self) {
+        self.button_status = JoypadButton::empty();
+        self.turbo_held = JoypadButton::empty();
+    }
+}
+
+/// Lets a single keyboard drive either player, one at a time.
+#[derive(Default)]
+pub struct PlayerSelect {
+    active: usize,
+}
+
+impl PlayerSelect {
+    pub fn new() -> Self {
+        PlayerSelect { active: 0 }
+    }
+
+    // 0 for player 1, 1 for player 2
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    // the pad being left is released so it doesn't keep buttons held
+    pub fn toggle(&mut self, joypad1: &mut Joypad, joypad2: &mut Joypad) {
+        self.select(joypad1, joypad2).release_all();
+        self.active = 1 - self.active;
+    }
+
+    pub fn select<'a>(&self, joypad1: &'a mut Joypad, joypad2: &'a mut Joypad) -> &'a mut Joypad {
+        if self.active == 0 {
+            joypad1
+        } else {
+            joypad2
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strobe_high_reads_button_a_repeatedly() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+
+        joypad.write(1);
+        for _ in 0..10 {
+            assert_eq!(joypad.read(), 1);
+        }
+
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, false);
+        assert_eq!(joypad.read(), 0);
+    }
+
+    #[test]
+    fn test_strobe_low_reads_buttons_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        joypad.set_button_pressed_status(JoypadButton::START, true);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+
+        joypad.write(1);
+        joypad.read();
+        joypad.read();
+        joypad.write(0);
+
+        // A, B, Select, Start, Up, Down, Left, Right
+        let expected = [1, 0, 0, 1, 0, 0, 1, 0];
+        for bit in expected {
+            assert_eq!(joypad.read(), bit);
+        }
+        assert_eq!(joypad.read(), 1);
+    }
+
+    #[test]
+    fn test_reads_past_eighth_return_one() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        joypad.write(1);
+        joypad.write(0);
+
+        let reads: Vec<u8> = (0..12).map(|_| joypad.read()).collect();
+        assert_eq!(reads, vec![0, 1, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1]);
+
+        for _ in 0..1000 {
+            assert_eq!(joypad.read(), 1);
+        }
+
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.read(), 1);
+    }
+
+    fn turbo_taps_per_second(hz: u32) -> u32 {
+        turbo_taps_per_second_on(TvSystem::Ntsc, hz)
+    }
+
+    fn turbo_taps_per_second_on(tv_system: TvSystem, hz: u32) -> u32 {
+        let mut joypad = Joypad::new();
+        joypad.set_tv_system(tv_system);
+        joypad.set_turbo_rate(hz);
+        joypad.set_turbo_pressed_status(JoypadButton::BUTTON_B, true);
+
+        let mut taps = 0;
+        let mut was_pressed = true;
+        for _ in 0..joypad.frame_rate {
+            joypad.next_frame();
+            let pressed = joypad.buttons().contains(JoypadButton::BUTTON_B);
+            if pressed && !was_pressed {
+                taps += 1;
+            }
+            was_pressed = pressed;
+        }
+        taps
+    }
+
+    #[test]
+    fn test_turbo_toggles_at_configured_rate() {
+        assert_eq!(turbo_taps_per_second(10), 10);
+        assert_eq!(turbo_taps_per_second(15), 15);
+        assert_eq!(turbo_taps_per_second(30), 30);
+        assert!((6..=7).contains(&turbo_taps_per_second(7)));
+    }
+
+    #[test]
+    fn test_turbo_keeps_its_rate_on_pal() {
+        assert_eq!(turbo_taps_per_second_on(TvSystem::Pal, 10), 10);
+        assert_eq!(turbo_taps_per_second_on(TvSystem::Pal, 25), 25);
+    }
+
+    #[test]
+    fn test_turbo_rate_is_clamped() {
+        let mut joypad = Joypad::new();
+        joypad.set_turbo_rate(100);
+        assert_eq!(joypad.turbo_rate(), 30);
+        joypad.set_turbo_rate(0);
+        assert_eq!(joypad.turbo_rate(), 1);
+    }
+
+    #[test]
+    fn test_toggle_routes_presses_to_other_joypad() {
+        let mut joypad1 = Joypad::new();
+        let mut joypad2 = Joypad::new();
+        let mut players = PlayerSelect::new();
+
+        players
+            .select(&mut joypad1, &mut joypad2)
+            .set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        players.toggle(&mut joypad1, &mut joypad2);
+        assert_eq!(players.active(), 1);
+
+        players
+            .select(&mut joypad1, &mut joypad2)
+            .set_button_pressed_status(JoypadButton::START, true);
+
+        joypad1.write(1);
+        joypad2.write(1);
+        joypad1.write(0);
+        joypad2.write(0);
+
+        // joypad 1 was released when switching away
+        assert_eq!(joypad1.read(), 0);
+        assert_eq!(joypad2.read(), 0);
+        assert_eq!(joypad2.read(), 0);
+        assert_eq!(joypad2.read(), 0);
+        assert_eq!(joypad2.read(), 1);
+    }
+
+    #[test]
+    fn test_remapped_key_presses_button() {
+        let mut joypad = Joypad::new();
+        joypad.set_mapping(Keycode::Up, JoypadButton::UP);
+
+        // W no longer belongs to UP
+        assert!(!joypad.set_key_pressed_status(Keycode::W, true));
+        assert!(joypad.set_key_pressed_status(Keycode::Up, true));
+        joypad.write(0);
+        let buttons: Vec<u8> = (0..8).map(|_| joypad.read()).collect();
+        assert_eq!(buttons, vec![0, 0, 0, 0, 1, 0, 0, 0]);
+
+        joypad.clear_mapping(JoypadButton::UP);
+        assert!(!joypad.set_key_pressed_status(Keycode::Up, false));
+        assert_eq!(joypad.keymap.len(), 7);
+    }
 }
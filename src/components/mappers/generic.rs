@@ -0,0 +1,442 @@
+use super::{Mapper, PrgRam};
+use crate::components::cartridge::Mirroring;
+
+/// Bank registers of a discrete mapper: the bank mapped into each PRG window
+/// slot (`0x8000..=0xFFFF` split by `prg_bank_size`) and each CHR slot
+/// (`0x0000..=0x1FFF` split by `chr_bank_size`).
+pub struct Banks {
+    pub prg: Vec<usize>,
+    pub chr: Vec<usize>,
+    pub mirroring: Mirroring,
+}
+
+// updates the bank registers on a CPU write to 0x8000..=0xFFFF
+pub type BankSelect = fn(banks: &mut Banks, address: u16, data: u8);
+
+#[derive(Clone, Copy)]
+pub struct BankedConfig {
+    pub prg_bank_size: usize,
+    pub chr_bank_size: usize,
+    pub bank_select: BankSelect,
+    /// The board doesn't disable the ROM on writes, so the value latched is
+    /// the written byte ANDed with the PRG byte at that address.
+    pub bus_conflicts: bool,
+}
+
+pub const NROM: BankedConfig = BankedConfig {
+    prg_bank_size: 0x4000,
+    chr_bank_size: 0x2000,
+    bank_select: |_banks, _address, _data| {},
+    bus_conflicts: false,
+};
+
+// 16KB switchable at 0x8000, last 16KB fixed at 0xC000. Only some UxROM
+// boards have bus conflicts, and the header can't tell which
+pub const UXROM: BankedConfig = BankedConfig {
+    prg_bank_size: 0x4000,
+    chr_bank_size: 0x2000,
+    bank_select: |banks, _address, data| banks.prg[0] = data as usize,
+    bus_conflicts: false,
+};
+
+// 8KB switchable CHR, PRG as NROM
+pub const CNROM: BankedConfig = BankedConfig {
+    prg_bank_size: 0x4000,
+    chr_bank_size: 0x2000,
+    bank_select: |banks, _address, data| banks.chr[0] = (data & 0b11) as usize,
+    bus_conflicts: true,
+};
+
+// 32KB switchable PRG, bit 4 selects the single-screen nametable
+pub const AXROM: BankedConfig = BankedConfig {
+    prg_bank_size: 0x8000,
+    chr_bank_size: 0x2000,
+    bank_select: |banks, _address, data| {
+        banks.prg[0] = (data & 0b111) as usize;
+        banks.mirroring = if data & 0b1_0000 != 0 {
+            Mirroring::SingleScreenUpper
+        } else {
+            Mirroring::SingleScreenLower
+        };
+    },
+    bus_conflicts: false,
+};
+
+// 32KB PRG in bits 4-5, 8KB CHR in bits 0-1
+pub const GXROM: BankedConfig = BankedConfig {
+    prg_bank_size: 0x8000,
+    chr_bank_size: 0x2000,
+    bank_select: |banks, _address, data| {
+        banks.prg[0] = ((data >> 4) & 0b11) as usize;
+        banks.chr[0] = (data & 0b11) as usize;
+    },
+    bus_conflicts: false,
+};
+
+pub struct GenericBankedMapper {
+    config: BankedConfig,
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: bool,
+    banks: Banks,
+    // discrete boards have no protection, so it is always enabled
+    prg_ram: PrgRam,
+}
+
+fn bank_count(len: usize, bank_size: usize) -> usize {
+    (len / bank_size).max(1)
+}
+
+const MIRRORINGS: [Mirroring; 5] = [
+    Mirroring::Vertical,
+    Mirroring::Horizontal,
+    Mirroring::FourScreen,
+    Mirroring::SingleScreenLower,
+    Mirroring::SingleScreenUpper,
+];
+
+impl GenericBankedMapper {
+    // every slot starts on the bank with its own index, except the last PRG
+    // slot which is fixed to the last bank as most boards do at power on
+    pub fn new(
+        config: BankedConfig,
+        prg_rom: Vec<u8>,
+        chr_rom: Vec<u8>,
+        mirroring: Mirroring,
+    ) -> Self {
+        let prg_slots = 0x8000 / config.prg_bank_size;
+        let chr_slots = 0x2000 / config.chr_bank_size;
+        let last_prg_bank = bank_count(prg_rom.len(), config.prg_bank_size) - 1;
+
+        let mut prg: Vec<usize> = (0..prg_slots).collect();
+        prg[prg_slots - 1] = last_prg_bank;
+
+        GenericBankedMapper {
+            config,
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            banks: Banks {
+                prg,
+                chr: (0..chr_slots).collect(),
+                mirroring,
+            },
+            prg_ram: PrgRam::new(),
+        }
+    }
+
+    // makes `chr_rom` writable through `ppu_write`
+    pub fn with_chr_ram(mut self, chr_ram: bool) -> Self {
+        self.chr_ram = chr_ram;
+        self
+    }
+
+    pub fn banks(&self) -> &Banks {
+        &self.banks
+    }
+
+    // images smaller than a bank (e.g. 8KB PRG) are mirrored across it
+    fn prg_offset(&self, address: u16) -> usize {
+        let size = self.config.prg_bank_size;
+        let window = (address - 0x8000) as usize;
+        let bank = self.banks.prg[window / size] % bank_count(self.prg_rom.len(), size);
+        (bank * size + window % size) % self.prg_rom.len().max(1)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let size = self.config.chr_bank_size;
+        let window = address as usize;
+        let bank = self.banks.chr[window / size] % bank_count(self.chr_rom.len(), size);
+        (bank * size + window % size) % self.chr_rom.len().max(1)
+    }
+}
+
+impl Mapper for GenericBankedMapper {
+    fn cpu_read(&self, address: u16) -> u8 {
+        self.prg_rom[self.prg_offset(address)]
+    }
+
+    fn cpu_write(&mut self, address: u16, data: u8) {
+        let data = if self.config.bus_conflicts {
+            data & self.prg_rom[self.prg_offset(address)]
+        } else {
+            data
+        };
+        (self.config.bank_select)(&mut self.banks, address, data);
+    }
+
+    // CHR smaller than the pattern tables is mirrored, a board without any reads 0
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr_rom
+            .get(self.chr_offset(address))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        self.chr_ram && !self.chr_rom.is_empty()
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) {
+        if self.has_chr_ram() {
+            let offset = self.chr_offset(address);
+            self.chr_rom[offset] = data;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.banks.mirroring.clone()
+    }
+
+    fn prg_ram_read(&self, address: u16) -> Option<u8> {
+        self.prg_ram.read(address)
+    }
+
+    fn prg_ram_write(&mut self, address: u16, data: u8) {
+        self.prg_ram.write(address, data);
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+
+    // one byte per PRG slot, one per CHR slot, the mirroring, PRG-RAM, then
+    // the CHR-RAM contents when the board has it
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.banks.prg.iter().map(|&bank| bank as u8).collect();
+        bytes.extend(self.banks.chr.iter().map(|&bank| bank as u8));
+        let mirroring = MIRRORINGS
+            .iter()
+            .position(|mirroring| *mirroring == self.banks.mirroring)
+            .unwrap();
+        bytes.push(mirroring as u8);
+        bytes.extend(self.prg_ram.serialize());
+        if self.chr_ram {
+            bytes.extend_from_slice(&self.chr_rom);
+        }
+        bytes
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let prg_slots = self.banks.prg.len();
+        let chr_slots = self.banks.chr.len();
+        let registers = prg_slots + chr_slots + 1;
+        let chr_ram = if self.chr_ram { self.chr_rom.len() } else { 0 };
+        if data.len() < registers + chr_ram {
+            return Err(format!("Mapper state has {} bytes", data.len()));
+        }
+
+        let mirroring = match MIRRORINGS.get(data[registers - 1] as usize) {
+            Some(mirroring) => mirroring.clone(),
+            None => return Err(format!("Invalid mirroring {}", data[registers - 1])),
+        };
+        let (prg_ram, chr) = data[registers..].split_at(data.len() - registers - chr_ram);
+        self.prg_ram.deserialize(prg_ram)?;
+        self.chr_rom[..chr_ram].copy_from_slice(chr);
+
+        self.banks.prg = data[..prg_slots]
+            .iter()
+            .map(|&bank| bank as usize)
+            .collect();
+        self.banks.chr = data[prg_slots..registers - 1]
+            .iter()
+            .map(|&bank| bank as usize)
+            .collect();
+        self.banks.mirroring = mirroring;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every 16KB PRG bank / 8KB CHR bank is filled with its own index
+    fn numbered_banks(count: usize, size: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank as u8; size]).collect()
+    }
+
+    #[test]
+    fn test_nrom_128_mirrors_upper_bank() {
+        let mapper = GenericBankedMapper::new(
+            NROM,
+            numbered_banks(1, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Horizontal,
+        );
+
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 0);
+    }
+
+    #[test]
+    fn test_8k_prg_mirrors_across_window() {
+        let mut prg = vec![0; 0x2000];
+        prg[0x0000] = 0x11;
+        prg[0x1FFF] = 0x22;
+        let mapper = GenericBankedMapper::new(NROM, prg, vec![0; 0x2000], Mirroring::Horizontal);
+
+        for base in [0x8000, 0xA000, 0xC000, 0xE000] {
+            assert_eq!(mapper.cpu_read(base), 0x11);
+            assert_eq!(mapper.cpu_read(base + 0x1FFF), 0x22);
+        }
+    }
+
+    #[test]
+    fn test_uxrom_switches_lower_bank() {
+        let mut mapper = GenericBankedMapper::new(
+            UXROM,
+            numbered_banks(4, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Vertical,
+        );
+
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+
+        mapper.cpu_write(0x8000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+        assert_eq!(mapper.cpu_read(0xBFFF), 2);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+
+        mapper.cpu_write(0xFFFF, 1);
+        assert_eq!(mapper.cpu_read(0x8000), 1);
+        assert_eq!(mapper.cpu_read(0xFFFF), 3);
+    }
+
+    #[test]
+    fn test_cnrom_switches_chr_bank() {
+        // CNROM has bus conflicts, the byte written over lets every bit through
+        let mut prg = numbered_banks(2, 0x4000);
+        prg[0x0000] = 0xff;
+        let mut mapper =
+            GenericBankedMapper::new(CNROM, prg, numbered_banks(4, 0x2000), Mirroring::Vertical);
+
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 3);
+        assert_eq!(mapper.ppu_read(0x1FFF), 3);
+        assert_eq!(mapper.cpu_read(0xC000), 1);
+    }
+
+    #[test]
+    fn test_bus_conflict_ands_with_rom_byte() {
+        let mut prg = numbered_banks(2, 0x4000);
+        prg[0x0010] = 0b0000_0110;
+        prg[0x4020] = 0xff;
+        let mut mapper =
+            GenericBankedMapper::new(CNROM, prg, numbered_banks(4, 0x2000), Mirroring::Vertical);
+
+        // 3 & 6 selects bank 2
+        mapper.cpu_write(0x8010, 0b0000_0011);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+
+        mapper.cpu_write(0xC020, 1);
+        assert_eq!(mapper.ppu_read(0x0000), 1);
+
+        // a zero byte under the write drops it entirely
+        mapper.cpu_write(0x8000, 3);
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+    }
+
+    #[test]
+    fn test_without_bus_conflicts_the_written_value_is_latched() {
+        let mut mapper = GenericBankedMapper::new(
+            UXROM,
+            numbered_banks(4, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Vertical,
+        );
+        // the ROM byte under the write is 0
+        mapper.cpu_write(0x8010, 2);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+    }
+
+    #[test]
+    fn test_axrom_selects_32k_bank_and_single_screen() {
+        let mut mapper = GenericBankedMapper::new(
+            AXROM,
+            numbered_banks(8, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Horizontal,
+        );
+
+        mapper.cpu_write(0x8000, 0b1_0010);
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+
+        mapper.cpu_write(0x8000, 0b0_0001);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenLower);
+    }
+
+    #[test]
+    fn test_gxrom_selects_prg_and_chr() {
+        let mut mapper = GenericBankedMapper::new(
+            GXROM,
+            numbered_banks(4, 0x4000),
+            numbered_banks(4, 0x2000),
+            Mirroring::Horizontal,
+        );
+
+        mapper.cpu_write(0x8000, 0b01_0010);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+    }
+
+    #[test]
+    fn test_serialize_restores_banks_and_mirroring() {
+        let mut mapper = GenericBankedMapper::new(
+            AXROM,
+            numbered_banks(8, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Horizontal,
+        );
+
+        mapper.cpu_write(0x8000, 0b1_0010);
+        mapper.prg_ram_write(0x6000, 0x42);
+        let state = mapper.serialize();
+
+        mapper.cpu_write(0x8000, 0b0_0001);
+        mapper.prg_ram_write(0x6000, 0);
+        mapper.deserialize(&state).unwrap();
+
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.mirroring(), Mirroring::SingleScreenUpper);
+        assert_eq!(mapper.prg_ram_read(0x6000), Some(0x42));
+        assert!(mapper.deserialize(&state[..2]).is_err());
+    }
+
+    #[test]
+    fn test_chr_ram_is_writable_and_saved() {
+        let mut mapper = GenericBankedMapper::new(
+            NROM,
+            numbered_banks(1, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Vertical,
+        )
+        .with_chr_ram(true);
+
+        mapper.ppu_write(0x1234, 0x77);
+        assert_eq!(mapper.ppu_read(0x1234), 0x77);
+        let state = mapper.serialize();
+
+        mapper.ppu_write(0x1234, 0);
+        mapper.deserialize(&state).unwrap();
+        assert_eq!(mapper.ppu_read(0x1234), 0x77);
+    }
+
+    #[test]
+    fn test_chr_rom_ignores_writes() {
+        let mut mapper = GenericBankedMapper::new(
+            NROM,
+            numbered_banks(1, 0x4000),
+            vec![0; 0x2000],
+            Mirroring::Vertical,
+        );
+
+        mapper.ppu_write(0x1234, 0x77);
+        assert_eq!(mapper.ppu_read(0x1234), 0);
+    }
+}
@@ -0,0 +1,315 @@
+use super::{Mapper, PrgRam};
+use crate::components::cartridge::Mirroring;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// PRG mode 3 (switch 0x8000, fix the last bank at 0xC000) is the power on state
+const CONTROL_RESET: u8 = 0b0_1100;
+
+/// MMC1 (SxROM). The registers are loaded one bit at a time through a serial
+/// shift register, bits 13-14 of the fifth write's address pick the register.
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: bool,
+    shift: u8,
+    shift_count: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+    prg_ram: PrgRam,
+}
+
+fn bank_count(len: usize, bank_size: usize) -> usize {
+    (len / bank_size).max(1)
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Self {
+        Mmc1 {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            shift: 0,
+            shift_count: 0,
+            control: CONTROL_RESET,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+            prg_ram: PrgRam::new(),
+        }
+    }
+
+    // makes `chr_rom` writable through `ppu_write`
+    pub fn with_chr_ram(mut self, chr_ram: bool) -> Self {
+        self.chr_ram = chr_ram;
+        self
+    }
+
+    fn load_register(&mut self, address: u16, value: u8) {
+        match (address >> 13) & 0b11 {
+            0 => self.control = value,
+            1 => self.chr_bank0 = value,
+            2 => self.chr_bank1 = value,
+            _ => {
+                self.prg_bank = value;
+                // bit 4 disables the PRG-RAM on MMC1B and later
+                self.prg_ram.enabled = value & 0b1_0000 == 0;
+            }
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let window = (address - 0x8000) as usize;
+        let slot = window / PRG_BANK_SIZE;
+        let bank = self.prg_bank as usize & 0b1111;
+        let last = bank_count(self.prg_rom.len(), PRG_BANK_SIZE) - 1;
+
+        let bank = match ((self.control >> 2) & 0b11, slot) {
+            // 32KB mode ignores the low bit of the bank number
+            (0 | 1, _) => (bank & !1) + slot,
+            (2, 0) => 0,
+            (2, _) => bank,
+            (_, 0) => bank,
+            (_, _) => last,
+        };
+        let bank = bank % bank_count(self.prg_rom.len(), PRG_BANK_SIZE);
+        (bank * PRG_BANK_SIZE + window % PRG_BANK_SIZE) % self.prg_rom.len().max(1)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let window = address as usize;
+        let slot = window / CHR_BANK_SIZE;
+
+        let bank = if self.control & 0b1_0000 == 0 {
+            // 8KB mode ignores the low bit of CHR bank 0
+            (self.chr_bank0 as usize & !1) + slot
+        } else if slot == 0 {
+            self.chr_bank0 as usize
+        } else {
+            self.chr_bank1 as usize
+        };
+        let bank = bank % bank_count(self.chr_rom.len(), CHR_BANK_SIZE);
+        (bank * CHR_BANK_SIZE + window % CHR_BANK_SIZE) % self.chr_rom.len().max(1)
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, address: u16) -> u8 {
+        self.prg_rom[self.prg_offset(address)]
+    }
+
+    // bit 7 resets the shift register, otherwise bit 0 is shifted in from the
+    // top and the fifth write lands in a register
+    fn cpu_write(&mut self, address: u16, data: u8) {
+        if data & 0b1000_0000 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= CONTROL_RESET;
+            return;
+        }
+
+        self.shift = (self.shift >> 1) | ((data & 1) << 4);
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            self.load_register(address, self.shift);
+            self.shift = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr_rom
+            .get(self.chr_offset(address))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        self.chr_ram && !self.chr_rom.is_empty()
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) {
+        if self.has_chr_ram() {
+            let offset = self.chr_offset(address);
+            self.chr_rom[offset] = data;
+        }
+    }
+
+    // the board always drives mirroring, the header setting is ignored
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_ram_read(&self, address: u16) -> Option<u8> {
+        self.prg_ram.read(address)
+    }
+
+    fn prg_ram_write(&mut self, address: u16, data: u8) {
+        self.prg_ram.write(address, data);
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+
+    // shift register, the four registers, PRG-RAM, then CHR-RAM when present
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            self.shift,
+            self.shift_count,
+            self.control,
+            self.chr_bank0,
+            self.chr_bank1,
+            self.prg_bank,
+        ];
+        bytes.extend(self.prg_ram.serialize());
+        if self.chr_ram {
+            bytes.extend_from_slice(&self.chr_rom);
+        }
+        bytes
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let chr_ram = if self.chr_ram { self.chr_rom.len() } else { 0 };
+        if data.len() < 6 + chr_ram {
+            return Err(format!("Mapper state has {} bytes", data.len()));
+        }
+
+        let (prg_ram, chr) = data[6..].split_at(data.len() - 6 - chr_ram);
+        self.prg_ram.deserialize(prg_ram)?;
+        self.chr_rom[..chr_ram].copy_from_slice(chr);
+
+        self.shift = data[0];
+        self.shift_count = data[1];
+        self.control = data[2];
+        self.chr_bank0 = data[3];
+        self.chr_bank1 = data[4];
+        self.prg_bank = data[5];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every bank is filled with its own index
+    fn numbered_banks(count: usize, size: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank as u8; size]).collect()
+    }
+
+    fn mmc1() -> Mmc1 {
+        Mmc1::new(
+            numbered_banks(8, PRG_BANK_SIZE),
+            numbered_banks(8, CHR_BANK_SIZE),
+        )
+    }
+
+    // five writes, low bit first
+    fn write_register(mapper: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.cpu_write(address, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_five_writes_load_prg_bank() {
+        let mut mapper = mmc1();
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+
+        for bit in [1, 0, 1, 0] {
+            mapper.cpu_write(0xE000, bit);
+        }
+        // four writes in, nothing is latched yet
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+
+        mapper.cpu_write(0xE000, 0);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.cpu_read(0xBFFF), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+    }
+
+    #[test]
+    fn test_reset_bit_clears_shift_and_sets_prg_mode() {
+        let mut mapper = mmc1();
+        // PRG mode 2 fixes the first bank at 0x8000
+        write_register(&mut mapper, 0x8000, 0b0_1000);
+        write_register(&mut mapper, 0xE000, 3);
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0xE000, 1);
+        mapper.cpu_write(0x8000, 0x80);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+
+        // the partial value was dropped, five more writes start from scratch
+        write_register(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.cpu_read(0x8000), 2);
+    }
+
+    #[test]
+    fn test_32k_prg_mode_ignores_low_bit() {
+        let mut mapper = mmc1();
+        write_register(&mut mapper, 0x8000, 0b0_0000);
+        write_register(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 5);
+    }
+
+    #[test]
+    fn test_chr_modes() {
+        let mut mapper = mmc1();
+        write_register(&mut mapper, 0xA000, 3);
+        write_register(&mut mapper, 0xC000, 6);
+        assert_eq!(mapper.ppu_read(0x0000), 2);
+        assert_eq!(mapper.ppu_read(0x1000), 3);
+
+        write_register(&mut mapper, 0x8000, CONTROL_RESET | 0b1_0000);
+        assert_eq!(mapper.ppu_read(0x0000), 3);
+        assert_eq!(mapper.ppu_read(0x1FFF), 6);
+    }
+
+    #[test]
+    fn test_control_sets_mirroring() {
+        let mut mapper = mmc1();
+        for (bits, mirroring) in [
+            (0, Mirroring::SingleScreenLower),
+            (1, Mirroring::SingleScreenUpper),
+            (2, Mirroring::Vertical),
+            (3, Mirroring::Horizontal),
+        ] {
+            write_register(&mut mapper, 0x9FFF, CONTROL_RESET | bits);
+            assert_eq!(mapper.mirroring(), mirroring);
+        }
+    }
+
+    #[test]
+    fn test_serialize_restores_registers() {
+        let mut mapper = mmc1().with_chr_ram(true);
+        write_register(&mut mapper, 0xE000, 4);
+        mapper.cpu_write(0x8000, 1);
+        mapper.ppu_write(0x0010, 0x99);
+        let state = mapper.serialize();
+
+        mapper.cpu_write(0x8000, 0x80);
+        write_register(&mut mapper, 0xE000, 1);
+        mapper.ppu_write(0x0010, 0);
+        mapper.deserialize(&state).unwrap();
+
+        assert_eq!(mapper.cpu_read(0x8000), 4);
+        assert_eq!(mapper.shift_count, 1);
+        assert_eq!(mapper.ppu_read(0x0010), 0x99);
+        assert!(mapper.deserialize(&state[..4]).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use super::{Mapper, PrgRam};
+use crate::components::cartridge::Mirroring;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+/// MMC3 (TxROM). `$8000` picks which of the eight bank registers `$8001`
+/// writes, and a counter clocked once per scanline raises the IRQ.
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    chr_ram: bool,
+    bank_select: u8,
+    // R0-R1 are 2KB CHR banks, R2-R5 1KB CHR banks, R6-R7 8KB PRG banks
+    registers: [u8; 8],
+    vertical: bool,
+    four_screen: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    prg_ram: PrgRam,
+}
+
+fn bank_count(len: usize, bank_size: usize) -> usize {
+    (len / bank_size).max(1)
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Mmc3 {
+            prg_rom,
+            chr_rom,
+            chr_ram: false,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            vertical: mirroring == Mirroring::Vertical,
+            four_screen: mirroring == Mirroring::FourScreen,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            prg_ram: PrgRam::new(),
+        }
+    }
+
+    // makes `chr_rom` writable through `ppu_write`
+    pub fn with_chr_ram(mut self, chr_ram: bool) -> Self {
+        self.chr_ram = chr_ram;
+        self
+    }
+
+    // bit 6 of the bank select swaps 0x8000 with the fixed second to last bank
+    fn prg_offset(&self, address: u16) -> usize {
+        let window = (address - 0x8000) as usize;
+        let count = bank_count(self.prg_rom.len(), PRG_BANK_SIZE);
+        let second_last = count.saturating_sub(2);
+        let swapped = self.bank_select & 0b0100_0000 != 0;
+
+        let bank = match (window / PRG_BANK_SIZE, swapped) {
+            (0, false) | (2, true) => self.registers[6] as usize & 0b11_1111,
+            (0, true) | (2, false) => second_last,
+            (1, _) => self.registers[7] as usize & 0b11_1111,
+            (_, _) => count - 1,
+        };
+        (bank % count * PRG_BANK_SIZE + window % PRG_BANK_SIZE) % self.prg_rom.len().max(1)
+    }
+
+    // bit 7 of the bank select swaps the 2KB and 1KB halves of the pattern tables
+    fn chr_offset(&self, address: u16) -> usize {
+        let window = if self.bank_select & 0b1000_0000 != 0 {
+            address as usize ^ 0x1000
+        } else {
+            address as usize
+        };
+
+        let bank = match window / CHR_BANK_SIZE {
+            slot @ 0..=3 => (self.registers[slot / 2] as usize & !1) | (slot & 1),
+            slot => self.registers[slot - 2] as usize,
+        };
+        let bank = bank % bank_count(self.chr_rom.len(), CHR_BANK_SIZE);
+        (bank * CHR_BANK_SIZE + window % CHR_BANK_SIZE) % self.chr_rom.len().max(1)
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, address: u16) -> u8 {
+        self.prg_rom[self.prg_offset(address)]
+    }
+
+    // registers are decoded from the 8KB region and the low address bit
+    fn cpu_write(&mut self, address: u16, data: u8) {
+        match address & 0xE001 {
+            0x8000 => self.bank_select = data,
+            0x8001 => self.registers[(self.bank_select & 0b111) as usize] = data,
+            0xA000 => self.vertical = data & 1 == 0,
+            0xA001 => self.prg_ram.enabled = data & 0b1000_0000 != 0,
+            0xC000 => self.irq_latch = data,
+            0xC001 => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            0xE000 => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            _ => self.irq_enabled = true,
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr_rom
+            .get(self.chr_offset(address))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        self.chr_ram && !self.chr_rom.is_empty()
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) {
+        if self.has_chr_ram() {
+            let offset = self.chr_offset(address);
+            self.chr_rom[offset] = data;
+        }
+    }
+
+    // boards wired for four screen VRAM ignore the mirroring register
+    fn mirroring(&self) -> Mirroring {
+        match (self.four_screen, self.vertical) {
+            (true, _) => Mirroring::FourScreen,
+            (false, true) => Mirroring::Vertical,
+            (false, false) => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_ram_read(&self, address: u16) -> Option<u8> {
+        self.prg_ram.read(address)
+    }
+
+    fn prg_ram_write(&mut self, address: u16, data: u8) {
+        self.prg_ram.write(address, data);
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut PrgRam> {
+        Some(&mut self.prg_ram)
+    }
+
+    // the real counter is clocked by A12 rising as the PPU moves from
+    // background to sprite fetches, which happens once per rendered line
+    fn clock_scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    // bank select, the eight registers, mirroring, the IRQ state, PRG-RAM,
+    // then CHR-RAM when present
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![self.bank_select];
+        bytes.extend_from_slice(&self.registers);
+        bytes.extend([
+            self.vertical as u8,
+            self.irq_latch,
+            self.irq_counter,
+            self.irq_reload as u8,
+            self.irq_enabled as u8,
+            self.irq_pending as u8,
+        ]);
+        bytes.extend(self.prg_ram.serialize());
+        if self.chr_ram {
+            bytes.extend_from_slice(&self.chr_rom);
+        }
+        bytes
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        let chr_ram = if self.chr_ram { self.chr_rom.len() } else { 0 };
+        if data.len() < 15 + chr_ram {
+            return Err(format!("Mapper state has {} bytes", data.len()));
+        }
+
+        let (prg_ram, chr) = data[15..].split_at(data.len() - 15 - chr_ram);
+        self.prg_ram.deserialize(prg_ram)?;
+        self.chr_rom[..chr_ram].copy_from_slice(chr);
+
+        self.bank_select = data[0];
+        self.registers.copy_from_slice(&data[1..9]);
+        self.vertical = data[9] != 0;
+        self.irq_latch = data[10];
+        self.irq_counter = data[11];
+        self.irq_reload = data[12] != 0;
+        self.irq_enabled = data[13] != 0;
+        self.irq_pending = data[14] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // every bank is filled with its own index
+    fn numbered_banks(count: usize, size: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank as u8; size]).collect()
+    }
+
+    fn mmc3() -> Mmc3 {
+        Mmc3::new(
+            numbered_banks(16, PRG_BANK_SIZE),
+            numbered_banks(32, CHR_BANK_SIZE),
+            Mirroring::Vertical,
+        )
+    }
+
+    fn write_bank(mapper: &mut Mmc3, select: u8, bank: u8) {
+        mapper.cpu_write(0x8000, select);
+        mapper.cpu_write(0x8001, bank);
+    }
+
+    #[test]
+    fn test_prg_modes() {
+        let mut mapper = mmc3();
+        write_bank(&mut mapper, 6, 3);
+        write_bank(&mut mapper, 7, 4);
+        assert_eq!(mapper.cpu_read(0x8000), 3);
+        assert_eq!(mapper.cpu_read(0xA000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 14);
+        assert_eq!(mapper.cpu_read(0xE000), 15);
+
+        mapper.cpu_write(0x8000, 0b0100_0000);
+        assert_eq!(mapper.cpu_read(0x8000), 14);
+        assert_eq!(mapper.cpu_read(0xA000), 4);
+        assert_eq!(mapper.cpu_read(0xC000), 3);
+        assert_eq!(mapper.cpu_read(0xFFFF), 15);
+    }
+
+    #[test]
+    fn test_chr_modes() {
+        let mut mapper = mmc3();
+        write_bank(&mut mapper, 0, 9);
+        write_bank(&mut mapper, 5, 20);
+        // the 2KB registers ignore their low bit
+        assert_eq!(mapper.ppu_read(0x0000), 8);
+        assert_eq!(mapper.ppu_read(0x0400), 9);
+        assert_eq!(mapper.ppu_read(0x1C00), 20);
+
+        mapper.cpu_write(0x8000, 0b1000_0000);
+        assert_eq!(mapper.ppu_read(0x1000), 8);
+        assert_eq!(mapper.ppu_read(0x1400), 9);
+        assert_eq!(mapper.ppu_read(0x0C00), 20);
+    }
+
+    #[test]
+    fn test_mirroring_register() {
+        let mut mapper = mmc3();
+        mapper.cpu_write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        mapper.cpu_write(0xA000, 0);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+
+        let mut mapper = Mmc3::new(vec![0; 0x8000], vec![], Mirroring::FourScreen);
+        mapper.cpu_write(0xA000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_irq_counts_down_from_latch() {
+        let mut mapper = mmc3();
+        mapper.cpu_write(0xC000, 3);
+        mapper.cpu_write(0xC001, 0);
+        mapper.cpu_write(0xE001, 0);
+
+        // the first clock reloads the counter, three more reach zero
+        for _ in 0..3 {
+            mapper.clock_scanline();
+            assert!(!mapper.irq_pending());
+        }
+        mapper.clock_scanline();
+        assert!(mapper.irq_pending());
+
+        // acknowledged and disabled by $E000
+        mapper.cpu_write(0xE000, 0);
+        assert!(!mapper.irq_pending());
+        for _ in 0..8 {
+            mapper.clock_scanline();
+        }
+        assert!(!mapper.irq_pending());
+    }
+
+    #[test]
+    fn test_serialize_restores_banks_and_irq() {
+        let mut mapper = mmc3();
+        write_bank(&mut mapper, 6, 5);
+        mapper.cpu_write(0xC000, 7);
+        mapper.cpu_write(0xE001, 0);
+        mapper.clock_scanline();
+        let state = mapper.serialize();
+
+        write_bank(&mut mapper, 6, 1);
+        mapper.cpu_write(0xE000, 0);
+        mapper.deserialize(&state).unwrap();
+
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.irq_counter, 7);
+        assert!(mapper.irq_enabled);
+        assert!(mapper.deserialize(&state[..9]).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+pub mod generic;
+pub mod mmc1;
+pub mod mmc3;
+pub mod nrom;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::cartridge::{Mirroring, Rom};
+use generic::GenericBankedMapper;
+use mmc1::Mmc1;
+use mmc3::Mmc3;
+use nrom::Nrom;
+
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Cartridge hardware sitting between the consoles and the PRG/CHR memory.
+/// CPU addresses are in `0x8000..=0xFFFF`, PPU addresses in `0x0000..=0x1FFF`.
+pub trait Mapper {
+    fn cpu_read(&self, address: u16) -> u8;
+    fn cpu_write(&mut self, address: u16, data: u8);
+    fn ppu_read(&self, address: u16) -> u8;
+    fn ppu_write(&mut self, address: u16, data: u8);
+    fn mirroring(&self) -> Mirroring;
+
+    // true when `ppu_write` lands in CHR-RAM, CHR-ROM boards ignore it
+    fn has_chr_ram(&self) -> bool {
+        false
+    }
+
+    // PRG-RAM at 0x6000..=0x7FFF, None when the board has none or it is disabled
+    fn prg_ram_read(&self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn prg_ram_write(&mut self, _address: u16, _data: u8) {}
+
+    // the whole PRG-RAM, regardless of the enable bit, for battery saves
+    fn prg_ram(&mut self) -> Option<&mut PrgRam> {
+        None
+    }
+
+    // called once per rendered scanline, for boards that count them
+    fn clock_scanline(&mut self) {}
+
+    // the cartridge side of the CPU IRQ line
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // bank registers and on-board RAM, everything a save state needs
+    fn serialize(&self) -> Vec<u8>;
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String>;
+}
+
+/// 8KB of work RAM at 0x6000, with the enable bit boards use for write
+/// protection. Disabled RAM reads as open bus and ignores writes.
+pub struct PrgRam {
+    data: Vec<u8>,
+    pub enabled: bool,
+}
+
+impl PrgRam {
+    pub fn new() -> Self {
+        PrgRam {
+            data: vec![0; PRG_RAM_SIZE],
+            enabled: true,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> Option<u8> {
+        if !self.enabled {
+            return None;
+        }
+        Some(self.data[(address - 0x6000) as usize % PRG_RAM_SIZE])
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        if self.enabled {
+            self.data[(address - 0x6000) as usize % PRG_RAM_SIZE] = data;
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != PRG_RAM_SIZE {
+            return Err(format!("PRG-RAM image has {} bytes", data.len()));
+        }
+        self.data.copy_from_slice(data);
+        Ok(())
+    }
+
+    // the enable bit followed by the RAM contents
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = vec![self.enabled as u8];
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    pub fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() != PRG_RAM_SIZE + 1 {
+            return Err(format!("PRG-RAM state has {} bytes", data.len()));
+        }
+        self.enabled = data[0] != 0;
+        self.data.copy_from_slice(&data[1..]);
+        Ok(())
+    }
+}
+
+impl Default for PrgRam {
+    fn default() -> Self {
+        PrgRam::new()
+    }
+}
+
+// the bus talks to the mapper for PRG, the PPU for CHR and mirroring
+pub type SharedMapper = Rc<RefCell<dyn Mapper>>;
+
+type Constructor = fn(Rom) -> SharedMapper;
+
+fn banked(config: generic::BankedConfig, rom: Rom) -> SharedMapper {
+    let chr_ram = rom.has_chr_ram();
+    Rc::new(RefCell::new(
+        GenericBankedMapper::new(config, rom.prg_rom, rom.chr_rom, rom.screen_mirroring)
+            .with_chr_ram(chr_ram),
+    ))
+}
+
+fn nrom(rom: Rom) -> SharedMapper {
+    let chr_ram = rom.has_chr_ram();
+    Rc::new(RefCell::new(
+        Nrom::new(rom.prg_rom, rom.chr_rom, rom.screen_mirroring).with_chr_ram(chr_ram),
+    ))
+}
+
+fn mmc1(rom: Rom) -> SharedMapper {
+    let chr_ram = rom.has_chr_ram();
+    Rc::new(RefCell::new(
+        Mmc1::new(rom.prg_rom, rom.chr_rom).with_chr_ram(chr_ram),
+    ))
+}
+
+fn mmc3(rom: Rom) -> SharedMapper {
+    let chr_ram = rom.has_chr_ram();
+    Rc::new(RefCell::new(
+        Mmc3::new(rom.prg_rom, rom.chr_rom, rom.screen_mirroring).with_chr_ram(chr_ram),
+    ))
+}
+
+// every mapper number this build implements, `create` and
+// `supported_mappers` both read from here
+const BOARDS: &[(u8, Constructor)] = &[
+    (0, nrom),
+    (1, mmc1),
+    (2, |rom| banked(generic::UXROM, rom)),
+    (3, |rom| banked(generic::CNROM, rom)),
+    (4, mmc3),
+    (7, |rom| banked(generic::AXROM, rom)),
+    (66, |rom| banked(generic::GXROM, rom)),
+];
+
+lazy_static! {
+    static ref SUPPORTED_MAPPERS: Vec<u8> = BOARDS.iter().map(|(number, _)| *number).collect();
+}
+
+pub fn supported_mappers() -> &'static [u8] {
+    &SUPPORTED_MAPPERS
+}
+
+pub fn create(rom: Rom) -> Result<SharedMapper, String> {
+    match BOARDS.iter().find(|(number, _)| *number == rom.mapper) {
+        Some((_, constructor)) => Ok(constructor(rom)),
+        None => Err(format!("Mapper {} is not supported", rom.mapper)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rom_with_mapper(mapper: u8) -> Rom {
+        Rom::from_parts(
+            vec![0; 0x8000],
+            vec![0; 0x2000],
+            mapper,
+            Mirroring::Horizontal,
+        )
+    }
+
+    #[test]
+    fn test_prg_ram_writes_are_dropped_while_disabled() {
+        let mut ram = PrgRam::new();
+        ram.write(0x6000, 0x11);
+        assert_eq!(ram.read(0x6000), Some(0x11));
+
+        ram.enabled = false;
+        ram.write(0x6000, 0x22);
+        ram.write(0x7FFF, 0x33);
+        assert_eq!(ram.read(0x6000), None);
+
+        ram.enabled = true;
+        assert_eq!(ram.read(0x6000), Some(0x11));
+        assert_eq!(ram.read(0x7FFF), Some(0x00));
+        ram.write(0x7FFF, 0x33);
+        assert_eq!(ram.read(0x7FFF), Some(0x33));
+    }
+
+    #[test]
+    fn test_supported_mappers_all_construct() {
+        for &mapper in supported_mappers() {
+            assert!(create(rom_with_mapper(mapper)).is_ok(), "mapper {}", mapper);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_mapper_is_an_error() {
+        assert!(!supported_mappers().contains(&255));
+        match create(rom_with_mapper(255)) {
+            Err(error) => assert_eq!(error, "Mapper 255 is not supported"),
+            Ok(_) => panic!("mapper 255 should not be supported"),
+        }
+    }
+
+    #[test]
+    fn test_uxrom_switches_prg_over_chr_ram() {
+        let prg = (0..8).flat_map(|bank| vec![bank as u8; 0x4000]).collect();
+        let mapper = create(Rom::from_parts(prg, vec![], 2, Mirroring::Vertical)).unwrap();
+        let mut mapper = mapper.borrow_mut();
+
+        // writes to the fixed last bank select as well
+        mapper.cpu_write(0xC000, 5);
+        assert_eq!(mapper.cpu_read(0x8000), 5);
+        assert_eq!(mapper.cpu_read(0xBFFF), 5);
+        assert_eq!(mapper.cpu_read(0xC000), 7);
+
+        mapper.ppu_write(0x0800, 0x3c);
+        assert_eq!(mapper.ppu_read(0x0800), 0x3c);
+    }
+}
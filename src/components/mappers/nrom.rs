@@ -0,0 +1,133 @@
+use super::generic::{GenericBankedMapper, NROM};
+use super::{Mapper, PrgRam};
+use crate::components::cartridge::Mirroring;
+
+/// NROM, the board without bank switching and the mapper 0 the bus used to
+/// hard code. 16KB of PRG is mirrored into both halves of `0x8000..=0xFFFF`
+/// and CHR is read as is, which is the generic banked mapper with nothing to
+/// select.
+pub struct Nrom(GenericBankedMapper);
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        Nrom(GenericBankedMapper::new(NROM, prg_rom, chr_rom, mirroring))
+    }
+
+    // makes `chr_rom` writable through `ppu_write`
+    pub fn with_chr_ram(self, chr_ram: bool) -> Self {
+        Nrom(self.0.with_chr_ram(chr_ram))
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, address: u16) -> u8 {
+        self.0.cpu_read(address)
+    }
+
+    fn cpu_write(&mut self, address: u16, data: u8) {
+        self.0.cpu_write(address, data);
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.0.ppu_read(address)
+    }
+
+    fn ppu_write(&mut self, address: u16, data: u8) {
+        self.0.ppu_write(address, data);
+    }
+
+    fn has_chr_ram(&self) -> bool {
+        self.0.has_chr_ram()
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.0.mirroring()
+    }
+
+    fn prg_ram_read(&self, address: u16) -> Option<u8> {
+        self.0.prg_ram_read(address)
+    }
+
+    fn prg_ram_write(&mut self, address: u16, data: u8) {
+        self.0.prg_ram_write(address, data);
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut PrgRam> {
+        self.0.prg_ram()
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        self.0.serialize()
+    }
+
+    fn deserialize(&mut self, data: &[u8]) -> Result<(), String> {
+        self.0.deserialize(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_16k_prg_is_mirrored_and_chr_read_directly() {
+        let mut prg = vec![0; 0x4000];
+        prg[0x0000] = 0x11;
+        prg[0x3FFF] = 0x22;
+        let chr = (0..0x2000).map(|offset| (offset / 0x400) as u8).collect();
+        let mut mapper = Nrom::new(prg, chr, Mirroring::Horizontal);
+
+        assert_eq!(mapper.cpu_read(0x8000), 0x11);
+        assert_eq!(mapper.cpu_read(0xC000), 0x11);
+        assert_eq!(mapper.cpu_read(0xBFFF), 0x22);
+        assert_eq!(mapper.cpu_read(0xFFFF), 0x22);
+        assert_eq!(mapper.ppu_read(0x0000), 0);
+        assert_eq!(mapper.ppu_read(0x1FFF), 7);
+
+        // nothing to switch, and CHR-ROM ignores writes
+        mapper.cpu_write(0x8000, 0xff);
+        mapper.ppu_write(0x1FFF, 0xff);
+        assert_eq!(mapper.cpu_read(0xC000), 0x11);
+        assert_eq!(mapper.ppu_read(0x1FFF), 7);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+    }
+
+    // the PRG read the bus did itself before there were mappers
+    fn hard_coded_prg_read(prg: &[u8], mut address: u16) -> u8 {
+        address -= 0x8000;
+        if prg.len() == 0x4000 && address >= 0x4000 {
+            address %= 0x4000;
+        }
+        prg[address as usize]
+    }
+
+    #[test]
+    fn test_matches_the_hard_coded_bus_reads() {
+        for size in [0x4000, 0x8000] {
+            let prg: Vec<u8> = (0..size).map(|offset| (offset * 7 / 3) as u8).collect();
+            let chr: Vec<u8> = (0..0x2000).map(|offset| (offset * 5 / 2) as u8).collect();
+            let mapper = Nrom::new(prg.clone(), chr.clone(), Mirroring::Vertical);
+
+            for address in 0x8000..=0xFFFF {
+                assert_eq!(
+                    mapper.cpu_read(address),
+                    hard_coded_prg_read(&prg, address),
+                    "{:04x}",
+                    address
+                );
+            }
+            for address in 0..0x2000 {
+                assert_eq!(mapper.ppu_read(address), chr[address as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_32k_prg_is_read_directly() {
+        let prg = (0..0x8000).map(|offset| (offset / 0x4000) as u8).collect();
+        let mapper = Nrom::new(prg, vec![0; 0x2000], Mirroring::Vertical);
+
+        assert_eq!(mapper.cpu_read(0x8000), 0);
+        assert_eq!(mapper.cpu_read(0xC000), 1);
+    }
+}
@@ -3,4 +3,8 @@ pub mod cartridge;
 pub mod bus;
 pub mod assembly;
 pub mod cpu;
-pub mod joypads;
\ No newline at end of file
+pub mod joypads;
+pub mod mappers;
+pub mod state;
+pub mod apu;
+pub mod cycle_validator;
@@ -1,59 +1,134 @@
-use super::cartridge::Mirroring;
+use std::cell::RefCell;
+use std::rc::Rc;
 
+use super::cartridge::Mirroring;
+use super::mappers::generic::{GenericBankedMapper, NROM};
+use super::mappers::SharedMapper;
+use crate::render::{self, Frame};
+use crate::timing::{DOTS_PER_SCANLINE, NTSC_SCANLINES};
+
+// the internal registers from the nesdev "loopy" model: `v` is the VRAM
+// address, `t` the address being assembled by $2000/$2005/$2006 writes,
+// `fine_x` the pixel scroll within a tile, and one write toggle shared by
+// $2005 and $2006
+//
+// yyy NN YYYYY XXXXX
+// ||| || ||||| +++++-- coarse X scroll
+// ||| || +++++-------- coarse Y scroll
+// ||| ++-------------- nametable select
+// +++----------------- fine Y scroll
 pub struct AddressRegister {
-    low: u8,
-    high: u8,
+    v: u16,
+    t: u16,
+    fine_x: u8,
     high_pointer: bool,
 }
 
 impl AddressRegister {
     pub fn new() -> Self {
         AddressRegister {
-            high: 0,
-            low: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
             high_pointer: true,
         }
     }
 
-    fn set(&mut self, data: u16) {
-        self.high = (data >> 8) as u8;
-        self.low = (data & 0xff) as u8;
-    }
-
+    // $2006, the high byte only reaches `v` once the low byte is written
     pub fn update(&mut self, data: u8) {
         if self.high_pointer {
-            self.high = data;
+            self.t = (self.t & 0x00ff) | ((data as u16 & 0x3f) << 8);
         } else {
-            self.low = data;
+            self.t = (self.t & 0xff00) | data as u16;
+            self.v = self.t;
         }
 
-        if self.get() > 0x3fff {
-            //mirror down addr above 0x3fff
-            self.set(self.get() & 0b11111111111111);
+        self.high_pointer = !self.high_pointer;
+    }
+
+    // $2005, X then Y
+    pub fn write_scroll(&mut self, data: u8) {
+        if self.high_pointer {
+            self.t = (self.t & !0x001f) | (data as u16 >> 3);
+            self.fine_x = data & 0b111;
+        } else {
+            self.t =
+                (self.t & !0x73e0) | ((data as u16 & 0b111) << 12) | ((data as u16 & 0xf8) << 2);
         }
 
         self.high_pointer = !self.high_pointer;
     }
 
-    pub fn increment(&mut self, inc: u8) {
-        let low = self.low;
-        self.low = self.low.wrapping_add(inc);
-
-        if low > self.low {
-            self.high = self.high.wrapping_add(1);
-        }
+    // the nametable bits of $2000
+    pub fn set_nametable(&mut self, nametable: u8) {
+        self.t = (self.t & !0x0c00) | ((nametable as u16 & 0b11) << 10);
+    }
 
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b11111111111111); //mirror down addr above 0x3fff
-        }
+    pub fn increment(&mut self, inc: u8) {
+        self.v = self.v.wrapping_add(inc as u16) & 0x7fff;
     }
 
     pub fn reset_latch(&mut self) {
         self.high_pointer = true;
     }
 
+    // the fine Y bits of `v` don't make it onto the 14-bit address bus
     pub fn get(&self) -> u16 {
-        ((self.high as u16) << 8) | (self.low as u16)
+        self.v & 0x3fff
+    }
+
+    pub fn temporary(&self) -> u16 {
+        self.t
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.fine_x
+    }
+
+    /// Horizontal scroll in pixels within the nametable `t` selects.
+    pub fn scroll_x(&self) -> u8 {
+        ((self.t & 0x001f) as u8) << 3 | self.fine_x
+    }
+
+    /// Vertical scroll in pixels within the nametable `t` selects.
+    pub fn scroll_y(&self) -> u8 {
+        ((self.t >> 5 & 0x1f) as u8) << 3 | (self.t >> 12 & 0b111) as u8
+    }
+
+    // dot 257 of every rendered line
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041f) | (self.t & 0x041f);
+    }
+
+    // the pre-render line
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7be0) | (self.t & 0x7be0);
+    }
+
+    // down one pixel, past the last tile row into the other nametable
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let coarse_y = match self.v >> 5 & 0x1f {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.v = (self.v & !0x03e0) | coarse_y << 5;
+    }
+
+    // where the next pixel comes from in the 512x480 plane of nametables
+    fn render_scroll(&self) -> (usize, usize) {
+        let v = self.v as usize;
+        let x = (v >> 10 & 1) * 256 + (v & 0x1f) * 8 + self.fine_x as usize;
+        let y = (v >> 11 & 1) * 240 + (v >> 5 & 0x1f) * 8 + (v >> 12 & 0b111);
+        (x, y)
     }
 }
 
@@ -135,7 +210,7 @@ impl ControlRegister {
     }
 
     pub fn master_slave_select(&self) -> u8 {
-        if !self.contains(ControlRegister::SPRITE_SIZE) {
+        if !self.contains(ControlRegister::MASTER_SLAVE_SELECT) {
             0
         } else {
             1
@@ -177,10 +252,18 @@ bitflags! {
     }
 }
 
-pub enum Color {
-    Red,
-    Green,
-    Blue,
+/// The color emphasis bits of PPUMASK.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Emphasis {
+    pub red: bool,
+    pub green: bool,
+    pub blue: bool,
+}
+
+impl Emphasis {
+    pub fn any(&self) -> bool {
+        self.red || self.green || self.blue
+    }
 }
 
 impl MaskRegister {
@@ -208,19 +291,12 @@ impl MaskRegister {
         self.contains(MaskRegister::SHOW_SPRITES)
     }
 
-    pub fn emphasise(&self) -> Vec<Color> {
-        let mut result = Vec::<Color>::new();
-        if self.contains(MaskRegister::EMPHASISE_RED) {
-            result.push(Color::Red);
+    pub fn emphasise(&self) -> Emphasis {
+        Emphasis {
+            red: self.contains(MaskRegister::EMPHASISE_RED),
+            green: self.contains(MaskRegister::EMPHASISE_GREEN),
+            blue: self.contains(MaskRegister::EMPHASISE_BLUE),
         }
-        if self.contains(MaskRegister::EMPHASISE_BLUE) {
-            result.push(Color::Blue);
-        }
-        if self.contains(MaskRegister::EMPHASISE_GREEN) {
-            result.push(Color::Green);
-        }
-
-        result
     }
 
     pub fn update(&mut self, data: u8) {
@@ -228,35 +304,6 @@ impl MaskRegister {
     }
 }
 
-pub struct ScrollRegister {
-    pub scroll_x: u8,
-    pub scroll_y: u8,
-    pub latch: bool,
-}
-
-impl ScrollRegister {
-    pub fn new() -> Self {
-        ScrollRegister {
-            scroll_x: 0,
-            scroll_y: 0,
-            latch: false,
-        }
-    }
-
-    pub fn write(&mut self, data: u8) {
-        if !self.latch {
-            self.scroll_x = data;
-        } else {
-            self.scroll_y = data;
-        }
-        self.latch = !self.latch;
-    }
-
-    pub fn reset_latch(&mut self) {
-        self.latch = false;
-    }
-}
-
 bitflags! {
 
     // 7  bit  0
@@ -316,18 +363,78 @@ impl StatusRegister {
         self.contains(StatusRegister::VBLANK_STARTED)
     }
 
+    pub fn is_sprite_zero_hit(&self) -> bool {
+        self.contains(StatusRegister::SPRITE_ZERO_HIT)
+    }
+
     pub fn snapshot(&self) -> u8 {
         self.bits
     }
 }
 
+// $3F20-$3FFF repeat the 32 palette entries, and $3F10/$3F14/$3F18/$3F1C
+// are mirrors of $3F00/$3F04/$3F08/$3F0C
+fn palette_index(address: u16) -> usize {
+    let index = (address & 0x1f) as usize;
+    if index >= 0x10 && index & 0b11 == 0 {
+        index - 0x10
+    } else {
+        index
+    }
+}
+
+// 16 bytes per region: one CHR tile, or half a nametable row
+const DIRTY_REGION_SIZE: usize = 16;
+
+/// One bit per 16 byte region, set by writes and cleared by whoever redraws.
+pub struct DirtyMap {
+    bits: Vec<u64>,
+    regions: usize,
+}
+
+impl DirtyMap {
+    // everything starts dirty so the first frame is drawn in full
+    pub fn new(bytes: usize) -> Self {
+        let regions = bytes / DIRTY_REGION_SIZE;
+        let mut map = DirtyMap {
+            bits: vec![0; regions.div_ceil(64)],
+            regions,
+        };
+        map.mark_all();
+        map
+    }
+
+    pub fn mark(&mut self, offset: usize) {
+        let region = offset / DIRTY_REGION_SIZE;
+        self.bits[region / 64] |= 1 << (region % 64);
+    }
+
+    pub fn mark_all(&mut self) {
+        for region in 0..self.regions {
+            self.bits[region / 64] |= 1 << (region % 64);
+        }
+    }
+
+    pub fn is_dirty(&self, region: usize) -> bool {
+        self.bits[region / 64] & (1 << (region % 64)) != 0
+    }
+
+    pub fn dirty_regions(&self) -> Vec<usize> {
+        (0..self.regions)
+            .filter(|&region| self.is_dirty(region))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+}
+
 pub struct PPU {
-    pub chr_rom: Vec<u8>,
-    pub mirroring: Mirroring,
+    pub mapper: SharedMapper,
     pub control: ControlRegister,
     pub mask: MaskRegister,
     pub status: StatusRegister,
-    pub scroll: ScrollRegister,
     pub address: AddressRegister,
     pub vram: [u8; 2048],
 
@@ -340,6 +447,38 @@ pub struct PPU {
     pub scanline: u16,
     cycles: usize,
     pub nmi_interrupt: Option<u8>,
+    /// CPU cycles between vblank being set and the NMI line going low.
+    pub nmi_delay: u8,
+    // PPU cycles left before a pending vblank NMI is raised
+    nmi_countdown: Option<usize>,
+    /// Draw background and sprites even when the mask register disables them.
+    pub force_render: bool,
+    /// When off, $2007 returns the addressed byte right away instead of the
+    /// previous one. Not like hardware, it only makes tests easier to read.
+    pub buffered_reads: bool,
+    /// CHR-RAM tiles written through $2007, all of them after a mapper write
+    /// since it may have switched banks.
+    pub chr_dirty: DirtyMap,
+    /// VRAM regions written through $2007, indexed after mirroring.
+    pub vram_dirty: DirtyMap,
+    // drawn line by line once enabled, see `enable_scanline_rendering`
+    frame: Option<Frame>,
+    scanline_scroll: [(usize, usize); 240],
+    // where sprite 0 hits on the current line, found when the line begins
+    sprite_zero_dot: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpuState {
+    pub control: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_address: u8,
+    pub scanline: u16,
+    pub cycles: usize,
+    pub vram: [u8; 2048],
+    pub oam_data: [u8; 256],
+    pub palette_table: [u8; 32],
 }
 
 impl PPU {
@@ -347,15 +486,19 @@ impl PPU {
         PPU::new(vec![0; 2048], Mirroring::Horizontal)
     }
 
+    // standalone PPU over a fixed CHR, as on an NROM board
     pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+        let mapper = GenericBankedMapper::new(NROM, vec![], chr_rom, mirroring);
+        PPU::new_with_mapper(Rc::new(RefCell::new(mapper)))
+    }
+
+    pub fn new_with_mapper(mapper: SharedMapper) -> Self {
         PPU {
-            chr_rom: chr_rom,
-            mirroring: mirroring,
+            mapper,
             control: ControlRegister::new(),
             mask: MaskRegister::new(),
             status: StatusRegister::new(),
             oam_address: 0,
-            scroll: ScrollRegister::new(),
             address: AddressRegister::new(),
             vram: [0; 2048],
             oam_data: [0; 64 * 4],
@@ -365,6 +508,15 @@ impl PPU {
             cycles: 0,
             scanline: 0,
             nmi_interrupt: None,
+            nmi_delay: 1,
+            nmi_countdown: None,
+            force_render: false,
+            buffered_reads: true,
+            chr_dirty: DirtyMap::new(0x2000),
+            vram_dirty: DirtyMap::new(2048),
+            frame: None,
+            scanline_scroll: [(0, 0); 240],
+            sprite_zero_dot: None,
         }
     }
 
@@ -379,7 +531,9 @@ impl PPU {
         let mirrored_vram = address & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        match (self.mapper.borrow().mirroring(), name_table) {
+            (Mirroring::SingleScreenLower, _) => vram_index % 0x400,
+            (Mirroring::SingleScreenUpper, _) => 0x400 + vram_index % 0x400,
             (Mirroring::Vertical, 2) | (Mirroring::Vertical, 3) => vram_index - 0x800,
             (Mirroring::Horizontal, 2) => vram_index - 0x400,
             (Mirroring::Horizontal, 1) => vram_index - 0x400,
@@ -388,36 +542,221 @@ impl PPU {
         }
     }
 
+    /// Formats the 32x30 tile indices of nametable `which` (0-3) as hex rows,
+    /// going through the cartridge mirroring.
+    pub fn dump_nametable(&self, which: u8) -> String {
+        let base = 0x2000 + (which as u16 & 0b11) * 0x400;
+        let mut dump = String::new();
+
+        for row in 0..30 {
+            let line: Vec<String> = (0..32)
+                .map(|column| {
+                    let address = self.mirror_vram_address(base + row * 32 + column);
+                    format!("{:02x}", self.vram[address as usize])
+                })
+                .collect();
+            dump.push_str(&line.join(" "));
+            dump.push('\n');
+        }
+
+        dump
+    }
+
+    pub fn read_chr(&self, address: u16) -> u8 {
+        self.mapper.borrow().ppu_read(address)
+    }
+
+    // the 16 bytes of the pattern starting at `address`
+    pub fn chr_tile(&self, address: u16) -> [u8; 16] {
+        let mapper = self.mapper.borrow();
+        let mut tile = [0; 16];
+        for (i, byte) in tile.iter_mut().enumerate() {
+            *byte = mapper.ppu_read(address + i as u16);
+        }
+        tile
+    }
+
     fn increment_vram_address(&mut self) {
         self.address
             .increment(self.control.vram_address_increment());
     }
 
+    fn advance_nmi_countdown(&mut self, cycles: usize) {
+        if let Some(remaining) = self.nmi_countdown {
+            if remaining <= cycles {
+                self.nmi_countdown = None;
+                self.nmi_interrupt = Some(1);
+            } else {
+                self.nmi_countdown = Some(remaining - cycles);
+            }
+        }
+    }
+
     pub fn tick(&mut self, cycles: u8) -> bool {
+        self.advance_nmi_countdown(cycles as usize);
+
         self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            self.cycles = self.cycles - 341;
+        if !self.status.is_sprite_zero_hit()
+            && self.sprite_zero_dot.is_some_and(|dot| self.cycles >= dot)
+        {
+            self.status.set_sprite_zero_hit(true);
+        }
+
+        if self.cycles >= DOTS_PER_SCANLINE {
+            self.cycles -= DOTS_PER_SCANLINE;
+            if self.scanline < 240 && self.rendering_enabled() {
+                self.address.increment_y();
+            }
             self.scanline += 1;
+            self.begin_scanline();
 
             if self.scanline == 241 {
                 self.status.set_vblank_status(true);
-                self.status.set_sprite_zero_hit(false);
                 if self.control.generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
+                    // the cycles past the end of the scanline already count
+                    self.nmi_countdown = Some(self.nmi_delay as usize * 3);
+                    self.advance_nmi_countdown(self.cycles);
                 }
             }
 
-            if self.scanline >= 262 {
+            // the pre-render line
+            if self.scanline == NTSC_SCANLINES - 1 {
+                self.status.set_sprite_zero_hit(false);
+                self.status.set_sprite_overflow(false);
+            }
+
+            if self.scanline >= NTSC_SCANLINES {
                 self.scanline = 0;
                 self.nmi_interrupt = None;
-                self.status.set_sprite_zero_hit(false);
+                self.nmi_countdown = None;
                 self.status.reset_vblank_status();
+                self.begin_scanline();
                 return true;
             }
         }
         return false;
     }
 
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask.show_background() || self.mask.show_sprites()
+    }
+
+    // latches the scroll the line is drawn with and evaluates its sprites
+    fn begin_scanline(&mut self) {
+        self.sprite_zero_dot = None;
+        if !self.rendering_enabled() {
+            return;
+        }
+        if self.scanline == NTSC_SCANLINES - 1 {
+            self.address.copy_vertical();
+        }
+        if self.scanline < 240 {
+            self.address.copy_horizontal();
+            self.scanline_scroll[self.scanline as usize] = self.address.render_scroll();
+            self.evaluate_sprites();
+            self.sprite_zero_dot = self.sprite_zero_hit_dot(self.scanline as usize);
+        }
+    }
+
+    /// Keeps a frame inside the PPU that `render_scanline` draws into as the
+    /// lines go by, so scroll changes in the middle of a frame show up.
+    pub fn enable_scanline_rendering(&mut self) {
+        self.frame = Some(Frame::new());
+    }
+
+    pub fn frame(&self) -> Option<&Frame> {
+        self.frame.as_ref()
+    }
+
+    /// Draws visible `line` with the scroll latched when it began. Does
+    /// nothing unless scanline rendering is enabled.
+    pub fn render_scanline(&mut self, line: u16) {
+        if line >= 240 {
+            return;
+        }
+        if let Some(mut frame) = self.frame.take() {
+            let scroll = self.scanline_scroll[line as usize];
+            render::render_scanline(self, &mut frame, line as usize, scroll);
+            self.frame = Some(frame);
+        }
+    }
+
+    // sets the overflow flag when more than 8 sprites are on the new line,
+    // without the hardware's buggy search past the eighth sprite
+    fn evaluate_sprites(&mut self) {
+        let line = self.scanline as usize;
+        let height = self.control.sprite_size() as usize;
+        let in_range = self
+            .oam_data
+            .chunks(4)
+            .filter(|sprite| (sprite[0] as usize..sprite[0] as usize + height).contains(&line))
+            .count();
+        if in_range > 8 {
+            self.status.set_sprite_overflow(true);
+        }
+    }
+
+    // the dot of `scanline` where an opaque pixel of sprite 0 first lands on
+    // an opaque background pixel, drawn the way `render` draws the frame
+    fn sprite_zero_hit_dot(&self, scanline: usize) -> Option<usize> {
+        if !self.mask.show_background() || !self.mask.show_sprites() {
+            return None;
+        }
+        let sprite_y = self.oam_data[0] as usize;
+        let height = self.control.sprite_size() as usize;
+        if scanline >= 240 || scanline < sprite_y || scanline >= sprite_y + height {
+            return None;
+        }
+
+        let attributes = self.oam_data[2];
+        let flip_horizontal = attributes >> 6 & 1 == 1;
+        let mut row = scanline - sprite_y;
+        if attributes >> 7 & 1 == 1 {
+            row = height - 1 - row;
+        }
+        let tile = self.oam_data[1] as u16;
+        let (bank, tile) = if height == 16 {
+            ((tile & 1) * 0x1000, (tile & 0xfe) + row as u16 / 8)
+        } else {
+            (self.control.sprt_pattern_address(), tile)
+        };
+        let address = bank + tile * 16 + (row % 8) as u16;
+        let (low, high) = {
+            let mapper = self.mapper.borrow();
+            (mapper.ppu_read(address), mapper.ppu_read(address + 8))
+        };
+
+        let clip_left = !self.mask.leftmost_8pxl_background() || !self.mask.leftmost_8pxl_sprite();
+        for column in 0..8 {
+            let bit = if flip_horizontal { column } else { 7 - column };
+            if (low >> bit | high >> bit) & 1 == 0 {
+                continue;
+            }
+            // no hit on the last pixel of the line
+            let x = self.oam_data[3] as usize + column;
+            if x >= 255 || (clip_left && x < 8) {
+                continue;
+            }
+            if self.background_opaque(x, scanline) {
+                return Some(x + 1);
+            }
+        }
+        None
+    }
+
+    // looked up with the scroll `line` was latched with, like `render` does
+    fn background_opaque(&self, x: usize, line: usize) -> bool {
+        let (scroll_x, scroll_y) = self.scanline_scroll[line];
+        let (world_x, world_y) = ((scroll_x + x) % 512, scroll_y % 480);
+        let nametable = world_y / 240 * 2 + world_x / 256;
+        let (y, column) = (world_y % 240, world_x % 256 / 8);
+        let start = self.mirror_vram_address(0x2000 + nametable as u16 * 0x400) as usize;
+        let tile = self.vram[start + y / 8 * 32 + column] as u16;
+        let address = self.control.bknd_pattern_address() + tile * 16 + (y % 8) as u16;
+        let mapper = self.mapper.borrow();
+        (mapper.ppu_read(address) | mapper.ppu_read(address + 8)) >> (7 - world_x % 8) & 1 == 1
+    }
+
     pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
         self.nmi_interrupt.take()
     }
@@ -425,6 +764,7 @@ impl PPU {
     pub fn write_to_control(&mut self, value: u8) {
         let before_nmi_status = self.control.generate_vblank_nmi();
         self.control.update(value);
+        self.address.set_nametable(value);
         if !before_nmi_status && self.control.generate_vblank_nmi() && self.status.is_in_vblank() {
             self.nmi_interrupt = Some(1);
         }
@@ -438,10 +778,24 @@ impl PPU {
         let data = self.status.snapshot();
         self.status.reset_vblank_status();
         self.address.reset_latch();
-        self.scroll.reset_latch();
         data
     }
 
+    // the byte the next $2007 read below the palettes will return
+    pub fn read_buffer(&self) -> u8 {
+        self.internal_data_buf
+    }
+
+    // OAMADDR, DMA walks it through all 256 bytes so it ends where it started
+    pub fn oam_address(&self) -> u8 {
+        self.oam_address
+    }
+
+    // status as the CPU would see it, without clearing vblank or the latches
+    pub fn peek_status(&self) -> u8 {
+        self.status.snapshot()
+    }
+
     pub fn write_to_oam_address(&mut self, value: u8) {
         self.oam_address = value;
     }
@@ -451,12 +805,18 @@ impl PPU {
         self.oam_address = self.oam_address.wrapping_add(1);
     }
 
+    // reads don't move OAMADDR, and the unused attribute bits 2-4 read back as 0
     pub fn read_oam_data(&self) -> u8 {
-        self.oam_data[self.oam_address as usize]
+        let value = self.oam_data[self.oam_address as usize];
+        if self.oam_address % 4 == 2 {
+            value & 0b1110_0011
+        } else {
+            value
+        }
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
-        self.scroll.write(value);
+        self.address.write_scroll(value);
     }
 
     pub fn write_to_ppu_address(&mut self, value: u8) {
@@ -466,19 +826,22 @@ impl PPU {
     pub fn write_to_data(&mut self, value: u8) {
         let address = self.address.get();
         match address {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", address),
+            0..=0x1fff => {
+                let mut mapper = self.mapper.borrow_mut();
+                mapper.ppu_write(address, value);
+                if mapper.has_chr_ram() {
+                    self.chr_dirty.mark(address as usize);
+                }
+            }
             0x2000..=0x2fff => {
-                self.vram[self.mirror_vram_address(address) as usize] = value;
+                let index = self.mirror_vram_address(address) as usize;
+                self.vram[index] = value;
+                self.vram_dirty.mark(index);
             }
             0x3000..=0x3eff => unimplemented!("address {} shouldn't be used in reallity", address),
 
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = address - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize] = value;
-            }
             0x3f00..=0x3fff => {
-                self.palette_table[(address - 0x3f00) as usize] = value;
+                self.palette_table[palette_index(address)] = value;
             }
             _ => panic!("unexpected access to mirrored space {}", address),
         }
@@ -492,28 +855,45 @@ impl PPU {
 
         match address {
             0..=0x1fff => {
-                let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[address as usize];
-                result
+                let data = self.read_chr(address);
+                self.buffer_read(data)
             }
             0x2000..=0x2fff => {
-                let result = self.internal_data_buf;
-                self.internal_data_buf = self.vram[self.mirror_vram_address(address) as usize];
-                result
+                let data = self.vram[self.mirror_vram_address(address) as usize];
+                self.buffer_read(data)
             }
             0x3000..=0x3eff => unimplemented!("address {} shouldn't be used in reallity", address),
 
-            //Addresses $3F10/$3F14/$3F18/$3F1C are mirrors of $3F00/$3F04/$3F08/$3F0C
-            0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
-                let add_mirror = address - 0x10;
-                self.palette_table[(add_mirror - 0x3f00) as usize]
-            }
-
-            0x3f00..=0x3fff => self.palette_table[(address - 0x3f00) as usize],
+            0x3f00..=0x3fff => self.palette_table[palette_index(address)],
             _ => panic!("unexpected access to mirrored space {}", address),
         }
     }
 
+    // reads below the palettes return the buffer and refill it
+    fn buffer_read(&mut self, data: u8) -> u8 {
+        let result = self.internal_data_buf;
+        self.internal_data_buf = data;
+        if self.buffered_reads {
+            result
+        } else {
+            data
+        }
+    }
+
+    pub fn snapshot(&self) -> PpuState {
+        PpuState {
+            control: self.control.bits(),
+            mask: self.mask.bits(),
+            status: self.status.snapshot(),
+            oam_address: self.oam_address,
+            scanline: self.scanline,
+            cycles: self.cycles,
+            vram: self.vram,
+            oam_data: self.oam_data,
+            palette_table: self.palette_table,
+        }
+    }
+
     pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
             self.oam_data[self.oam_address as usize] = *x;
@@ -536,6 +916,81 @@ pub mod test {
         assert_eq!(ppu.vram[0x0305], 0x66);
     }
 
+    #[test]
+    fn test_writes_mark_only_their_region_dirty() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.chr_dirty.clear();
+        // CHR-ROM ignores the write, nothing changed
+        ppu.write_to_ppu_address(0x00);
+        ppu.write_to_ppu_address(0x31);
+        ppu.write_to_data(0xff);
+        assert!(ppu.chr_dirty.dirty_regions().is_empty());
+
+        let rom = crate::components::cartridge::test::chr_ram_rom();
+        let mut ppu = PPU::new_with_mapper(crate::components::mappers::create(rom).unwrap());
+        ppu.chr_dirty.clear();
+        ppu.vram_dirty.clear();
+
+        // second byte of tile 3
+        ppu.write_to_ppu_address(0x00);
+        ppu.write_to_ppu_address(0x31);
+        ppu.write_to_data(0xff);
+        assert_eq!(ppu.chr_dirty.dirty_regions(), vec![3]);
+        assert!(ppu.vram_dirty.dirty_regions().is_empty());
+
+        ppu.write_to_ppu_address(0x20);
+        ppu.write_to_ppu_address(0x25);
+        ppu.write_to_data(0x01);
+        assert_eq!(ppu.vram_dirty.dirty_regions(), vec![2]);
+
+        ppu.chr_dirty.clear();
+        assert!(!ppu.chr_dirty.is_dirty(3));
+    }
+
+    #[test]
+    fn test_master_slave_select_round_trips() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_control(0b0100_0000);
+        assert_eq!(ppu.control.master_slave_select(), 1);
+        assert_eq!(ppu.control.sprite_size(), 8);
+
+        ppu.write_to_control(0b0010_0000);
+        assert_eq!(ppu.control.master_slave_select(), 0);
+    }
+
+    #[test]
+    fn test_chr_ram_takes_pattern_writes() {
+        let rom = crate::components::cartridge::test::chr_ram_rom();
+        let mut ppu = PPU::new_with_mapper(crate::components::mappers::create(rom).unwrap());
+
+        ppu.write_to_ppu_address(0x10);
+        ppu.write_to_ppu_address(0x20);
+        ppu.write_to_data(0x66);
+        ppu.write_to_data(0x99);
+
+        ppu.write_to_ppu_address(0x10);
+        ppu.write_to_ppu_address(0x20);
+        ppu.read_data();
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x99);
+        assert_eq!(ppu.chr_tile(0x1020)[..2], [0x66, 0x99]);
+    }
+
+    #[test]
+    fn test_undersized_chr_is_mirrored() {
+        let mut chr = vec![0; 0x400];
+        chr[0x10] = 0x5a;
+        let mut ppu = PPU::new(chr, Mirroring::Horizontal);
+        ppu.buffered_reads = false;
+
+        ppu.write_to_ppu_address(0x14);
+        ppu.write_to_ppu_address(0x10);
+        assert_eq!(ppu.read_data(), 0x5a);
+
+        let ppu = PPU::new(vec![], Mirroring::Horizontal);
+        assert_eq!(ppu.chr_tile(0x1ff0), [0; 16]);
+    }
+
     #[test]
     fn test_ppu_vram_reads() {
         let mut ppu = PPU::new_empty_rom();
@@ -550,6 +1005,24 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_unbuffered_reads_return_byte_immediately() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0x0305] = 0x66;
+        ppu.vram[0x0306] = 0x77;
+
+        ppu.write_to_ppu_address(0x23);
+        ppu.write_to_ppu_address(0x05);
+        assert_eq!(ppu.read_data(), 0x00);
+        assert_eq!(ppu.read_data(), 0x66);
+
+        ppu.buffered_reads = false;
+        ppu.write_to_ppu_address(0x23);
+        ppu.write_to_ppu_address(0x05);
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_data(), 0x77);
+    }
+
     #[test]
     fn test_ppu_vram_reads_cross_page() {
         let mut ppu = PPU::new_empty_rom();
@@ -687,6 +1160,18 @@ pub mod test {
         assert_eq!(ppu.status.snapshot() >> 7, 0);
     }
 
+    #[test]
+    fn test_peek_status_has_no_side_effects() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.status.set_vblank_status(true);
+
+        assert_eq!(ppu.peek_status() >> 7, 1);
+        assert_eq!(ppu.peek_status() >> 7, 1);
+
+        assert_eq!(ppu.read_status() >> 7, 1);
+        assert_eq!(ppu.peek_status() >> 7, 0);
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = PPU::new_empty_rom();
@@ -701,6 +1186,121 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_oam_read_does_not_increment_address() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_oam_address(0x20);
+        ppu.write_to_oam_data(0x11);
+        ppu.write_to_oam_data(0x22);
+
+        ppu.write_to_oam_address(0x20);
+        assert_eq!(ppu.read_oam_data(), 0x11);
+        assert_eq!(ppu.read_oam_data(), 0x11);
+        assert_eq!(ppu.oam_address, 0x20);
+    }
+
+    #[test]
+    fn test_oam_attribute_unused_bits_read_as_zero() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_oam_address(0x06);
+        ppu.write_to_oam_data(0xff);
+        ppu.write_to_oam_data(0xff);
+
+        ppu.write_to_oam_address(0x06);
+        assert_eq!(ppu.read_oam_data(), 0xe3);
+        ppu.write_to_oam_address(0x07);
+        assert_eq!(ppu.read_oam_data(), 0xff);
+    }
+
+    #[test]
+    fn test_dump_nametable_follows_mirroring() {
+        let mut ppu = PPU::new(vec![0; 2048], Mirroring::Vertical);
+        ppu.write_to_ppu_address(0x24);
+        ppu.write_to_ppu_address(0x00);
+        ppu.write_to_data(0x1a);
+        ppu.write_to_data(0x2b);
+
+        ppu.write_to_ppu_address(0x27);
+        ppu.write_to_ppu_address(0xbf);
+        ppu.write_to_data(0xff);
+
+        let dump = ppu.dump_nametable(3);
+        let rows: Vec<&str> = dump.lines().collect();
+        assert_eq!(rows.len(), 30);
+        assert!(rows[0].starts_with("1a 2b 00"));
+        assert_eq!(rows[0].split(' ').count(), 32);
+        assert!(rows[29].ends_with("00 ff"));
+
+        assert!(ppu
+            .dump_nametable(0)
+            .lines()
+            .all(|row| row.split(' ').all(|t| t == "00")));
+    }
+
+    #[test]
+    fn test_palette_addresses_wrap_every_32_entries() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_address(0x3f);
+        ppu.write_to_ppu_address(0x20);
+        ppu.write_to_data(0x2a);
+
+        ppu.write_to_ppu_address(0x3f);
+        ppu.write_to_ppu_address(0x00);
+        assert_eq!(ppu.read_data(), 0x2a);
+
+        ppu.write_to_ppu_address(0x3f);
+        ppu.write_to_ppu_address(0xf5);
+        ppu.write_to_data(0x11);
+        assert_eq!(ppu.palette_table[0x15], 0x11);
+
+        // $3F30 lands on $3F10 which itself mirrors $3F00
+        ppu.write_to_ppu_address(0x3f);
+        ppu.write_to_ppu_address(0x30);
+        assert_eq!(ppu.read_data(), 0x2a);
+    }
+
+    #[test]
+    fn test_read_buffer_holds_delayed_byte() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_address(0x23);
+        ppu.write_to_ppu_address(0x05);
+        ppu.write_to_data(0x66);
+        ppu.write_to_data(0x77);
+
+        ppu.write_to_ppu_address(0x23);
+        ppu.write_to_ppu_address(0x05);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_buffer(), 0x66);
+
+        assert_eq!(ppu.read_data(), 0x66);
+        assert_eq!(ppu.read_buffer(), 0x77);
+    }
+
+    // PPU cycles between vblank being set and the NMI being raised
+    fn measure_nmi_latency(nmi_delay: u8) -> usize {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.nmi_delay = nmi_delay;
+        ppu.write_to_control(0b1000_0000);
+        while ppu.scanline < 241 {
+            ppu.tick(1);
+        }
+        assert!(ppu.status.is_in_vblank());
+
+        let mut latency = ppu.cycles;
+        while ppu.poll_nmi_interrupt().is_none() {
+            ppu.tick(1);
+            latency += 1;
+        }
+        latency
+    }
+
+    #[test]
+    fn test_nmi_is_raised_after_configured_delay() {
+        assert_eq!(measure_nmi_latency(0), 0);
+        assert_eq!(measure_nmi_latency(1), 3);
+        assert_eq!(measure_nmi_latency(2), 6);
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = PPU::new_empty_rom();
@@ -720,4 +1320,140 @@ pub mod test {
         ppu.write_to_oam_address(0x11);
         ppu.write_to_oam_address(0x66);
     }
+
+    #[test]
+    fn test_emphasis_follows_mask_bits() {
+        let mut mask = MaskRegister::new();
+        mask.update(0b0100_0000);
+        assert_eq!(
+            mask.emphasise(),
+            Emphasis {
+                red: false,
+                green: true,
+                blue: false
+            }
+        );
+
+        mask.update(0b1010_0000);
+        let emphasis = mask.emphasise();
+        assert!(emphasis.red && !emphasis.green && emphasis.blue);
+    }
+
+    // tile 1 is solid, sprite 0 uses it at (x, y) over a background of
+    // tile 1 in the nametable column `column` only
+    fn sprite_zero_ppu(x: u8, y: u8, column: usize) -> PPU {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xff; 8]);
+        let mut ppu = PPU::new(chr, Mirroring::Horizontal);
+        for row in 0..30 {
+            ppu.vram[row * 32 + column] = 1;
+        }
+        ppu.oam_data[0..4].copy_from_slice(&[y, 1, 0, x]);
+        ppu.mask.update(0b0001_1110);
+        ppu
+    }
+
+    // ticks the PPU dot by dot until the given scanline and dot
+    fn run_to(ppu: &mut PPU, scanline: u16, dot: usize) {
+        while ppu.scanline != scanline || ppu.cycles < dot {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_at_first_overlap() {
+        // sprite covers x 20-27, background x 24-31
+        let mut ppu = sprite_zero_ppu(20, 50, 3);
+        run_to(&mut ppu, 50, 24);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+        run_to(&mut ppu, 50, 25);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+
+        // stays set through vblank, cleared on the pre-render line
+        run_to(&mut ppu, 245, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+        run_to(&mut ppu, 261, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_follows_the_scroll() {
+        // scrolled 8 pixels, the background column moves to x 16-23
+        let mut ppu = sprite_zero_ppu(20, 50, 3);
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(0);
+        run_to(&mut ppu, 50, 20);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+        run_to(&mut ppu, 50, 21);
+        assert_eq!(ppu.read_status() & 0x40, 0x40);
+    }
+
+    #[test]
+    fn test_sprite_zero_misses_transparent_background() {
+        let mut ppu = sprite_zero_ppu(100, 50, 3);
+        run_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+
+        // hidden in the clipped leftmost column
+        let mut ppu = sprite_zero_ppu(0, 50, 0);
+        ppu.mask.update(0b0001_1000);
+        run_to(&mut ppu, 241, 0);
+        assert_eq!(ppu.read_status() & 0x40, 0);
+    }
+
+    #[test]
+    fn test_sprite_overflow_past_eight_on_a_line() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.oam_data = [0xff; 256];
+        for sprite in 0..9 {
+            ppu.oam_data[sprite * 4] = 100;
+        }
+        ppu.mask.update(0b0001_0000);
+
+        run_to(&mut ppu, 99, 0);
+        assert_eq!(ppu.read_status() & 0x20, 0);
+        run_to(&mut ppu, 100, 0);
+        assert_eq!(ppu.read_status() & 0x20, 0x20);
+        run_to(&mut ppu, 261, 0);
+        assert_eq!(ppu.read_status() & 0x20, 0);
+
+        // eight sprites are fine
+        ppu.oam_data[8 * 4] = 0xff;
+        run_to(&mut ppu, 120, 0);
+        assert_eq!(ppu.read_status() & 0x20, 0);
+    }
+
+    #[test]
+    fn test_scroll_writes_fill_t_and_fine_x() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_control(0b0000_0001);
+        ppu.write_to_scroll(0b0111_1101);
+        ppu.write_to_scroll(0b0101_1110);
+
+        // fine Y 110, nametable 01, coarse Y 01011, coarse X 01111
+        assert_eq!(ppu.address.temporary(), 0b110_01_01011_01111);
+        assert_eq!(ppu.address.fine_x(), 0b101);
+        assert_eq!(ppu.address.scroll_x(), 0b0111_1101);
+        assert_eq!(ppu.address.scroll_y(), 0b0101_1110);
+        // scrolling doesn't move the VRAM address
+        assert_eq!(ppu.address.get(), 0);
+    }
+
+    #[test]
+    fn test_scroll_and_address_share_the_write_toggle() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_scroll(0x08);
+        // second write of the pair, lands in the low byte
+        ppu.write_to_ppu_address(0x45);
+        assert_eq!(ppu.address.get(), 0x0045);
+        assert_eq!(ppu.address.temporary(), 0x0045);
+
+        // a $2006 pair overwrites the scroll written before it
+        ppu.write_to_ppu_address(0x24);
+        ppu.write_to_ppu_address(0x60);
+        assert_eq!(ppu.address.get(), 0x2460);
+        assert_eq!(ppu.address.scroll_x(), 0);
+        // coarse Y 3, fine Y 2
+        assert_eq!(ppu.address.scroll_y(), 26);
+    }
 }
@@ -0,0 +1,194 @@
+use super::cpu::{CpuState, CPU};
+use super::ppu::PpuState;
+
+/// A full copy of the machine, used to find where two runs start to diverge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachineState {
+    pub cpu: CpuState,
+    pub ram: [u8; 2048],
+    pub ppu: PpuState,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateDiff {
+    Register {
+        name: &'static str,
+        ours: u16,
+        theirs: u16,
+    },
+    Ram {
+        address: u16,
+        ours: u8,
+        theirs: u8,
+    },
+    Ppu {
+        field: &'static str,
+        index: usize,
+        ours: usize,
+        theirs: usize,
+    },
+}
+
+fn diff_bytes(field: &'static str, ours: &[u8], theirs: &[u8], result: &mut Vec<StateDiff>) {
+    for (index, (a, b)) in ours.iter().zip(theirs.iter()).enumerate() {
+        if a != b {
+            result.push(StateDiff::Ppu {
+                field,
+                index,
+                ours: *a as usize,
+                theirs: *b as usize,
+            });
+        }
+    }
+}
+
+impl MachineState {
+    pub fn capture(cpu: &CPU) -> Self {
+        MachineState {
+            cpu: cpu.save_state(),
+            ram: *cpu.bus.ram(),
+            ppu: cpu.bus.ppu().snapshot(),
+        }
+    }
+
+    // differences are ordered registers first, then RAM, then PPU
+    pub fn diff(&self, other: &Self) -> Vec<StateDiff> {
+        let mut result = Vec::new();
+
+        let registers = [
+            ("A", self.cpu.a as u16, other.cpu.a as u16),
+            ("X", self.cpu.x as u16, other.cpu.x as u16),
+            ("Y", self.cpu.y as u16, other.cpu.y as u16),
+            ("P", self.cpu.p as u16, other.cpu.p as u16),
+            ("PC", self.cpu.pc, other.cpu.pc),
+            ("SP", self.cpu.sp as u16, other.cpu.sp as u16),
+        ];
+        for (name, ours, theirs) in registers {
+            if ours != theirs {
+                result.push(StateDiff::Register { name, ours, theirs });
+            }
+        }
+
+        for (address, (ours, theirs)) in self.ram.iter().zip(other.ram.iter()).enumerate() {
+            if ours != theirs {
+                result.push(StateDiff::Ram {
+                    address: address as u16,
+                    ours: *ours,
+                    theirs: *theirs,
+                });
+            }
+        }
+
+        let (ours, theirs) = (&self.ppu, &other.ppu);
+        let fields = [
+            ("control", ours.control as usize, theirs.control as usize),
+            ("mask", ours.mask as usize, theirs.mask as usize),
+            ("status", ours.status as usize, theirs.status as usize),
+            (
+                "oam_address",
+                ours.oam_address as usize,
+                theirs.oam_address as usize,
+            ),
+            ("scanline", ours.scanline as usize, theirs.scanline as usize),
+            ("cycles", ours.cycles, theirs.cycles),
+        ];
+        for (field, a, b) in fields {
+            if a != b {
+                result.push(StateDiff::Ppu {
+                    field,
+                    index: 0,
+                    ours: a,
+                    theirs: b,
+                });
+            }
+        }
+        diff_bytes("vram", &ours.vram, &theirs.vram, &mut result);
+        diff_bytes("oam_data", &ours.oam_data, &theirs.oam_data, &mut result);
+        diff_bytes(
+            "palette_table",
+            &ours.palette_table,
+            &theirs.palette_table,
+            &mut result,
+        );
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::bus::BUS;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+
+    #[test]
+    fn test_identical_states_have_no_diff() {
+        let bus = BUS::new(
+            test_rom(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        let cpu = CPU::new(bus);
+
+        let state = MachineState::capture(&cpu);
+        assert!(state.diff(&state.clone()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_pinpoints_ram_address() {
+        let mut bus = BUS::new(
+            test_rom(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        bus.memory_write(0x75, 0x10);
+        let cpu = CPU::new(bus);
+
+        let state = MachineState::capture(&cpu);
+        let mut other = state.clone();
+        other.ram[0x75] = 0x11;
+
+        assert_eq!(
+            state.diff(&other),
+            vec![StateDiff::Ram {
+                address: 0x75,
+                ours: 0x10,
+                theirs: 0x11
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_reports_registers_and_ppu() {
+        let bus = BUS::new(
+            test_rom(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        let cpu = CPU::new(bus);
+
+        let state = MachineState::capture(&cpu);
+        let mut other = state.clone();
+        other.cpu.x = 0x42;
+        other.ppu.palette_table[3] = 0x0f;
+
+        assert_eq!(
+            state.diff(&other),
+            vec![
+                StateDiff::Register {
+                    name: "X",
+                    ours: 0x00,
+                    theirs: 0x42
+                },
+                StateDiff::Ppu {
+                    field: "palette_table",
+                    index: 3,
+                    ours: 0x00,
+                    theirs: 0x0f
+                },
+            ]
+        );
+    }
+}
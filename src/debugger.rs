@@ -0,0 +1,259 @@
+use crate::components::cpu::CPU;
+
+const JSR: u8 = 0x20;
+// how far apart the instructions of a spin loop can be
+const LOOP_RANGE: u16 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugCommand {
+    Pause,
+    Step,
+    StepOver,
+    Continue,
+}
+
+/// Spots the CPU spinning in a few bytes of code without writing anything,
+/// which usually means it waits on a flag that never changes.
+struct LoopDetector {
+    threshold: u32,
+    start: u16,
+    previous: u16,
+    writes: u64,
+    iterations: u32,
+}
+
+impl LoopDetector {
+    fn new(threshold: u32) -> Self {
+        LoopDetector {
+            threshold,
+            start: 0,
+            previous: 0,
+            writes: 0,
+            iterations: 0,
+        }
+    }
+
+    // an iteration is a jump backwards that stays in the range, any write starts over
+    fn observe(&mut self, pc: u16, writes: u64) -> bool {
+        if writes != self.writes || pc.wrapping_sub(self.start) >= LOOP_RANGE {
+            self.start = pc;
+            self.writes = writes;
+            self.iterations = 0;
+        } else if pc < self.previous {
+            self.iterations += 1;
+        }
+        self.previous = pc;
+
+        if self.iterations >= self.threshold {
+            self.iterations = 0;
+            return true;
+        }
+        false
+    }
+}
+
+/// Pauses the run loop between instructions. Commands are queued with
+/// `request` and take effect the next time `should_execute` is asked.
+#[derive(Default)]
+pub struct Debugger {
+    paused: bool,
+    pending: Option<DebugCommand>,
+    step_once: bool,
+    run_until: Option<u16>,
+    loop_detector: Option<LoopDetector>,
+    // the PC of the last loop that paused the run, until taken
+    detected_loop: Option<u16>,
+    pub overlay: bool,
+}
+
+impl Debugger {
+    pub fn new(start_paused: bool) -> Self {
+        Debugger {
+            paused: start_paused,
+            pending: None,
+            step_once: false,
+            run_until: None,
+            loop_detector: None,
+            detected_loop: None,
+            overlay: start_paused,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.run_until = None;
+    }
+
+    pub fn request(&mut self, command: DebugCommand) {
+        self.pending = Some(command);
+    }
+
+    /// Pauses once the CPU loops `threshold` times over the same few bytes
+    /// without a single memory write. Off by default.
+    pub fn detect_loops(&mut self, threshold: u32) {
+        self.loop_detector = Some(LoopDetector::new(threshold));
+    }
+
+    /// Where the loop detector last paused the run, once per detection.
+    pub fn take_detected_loop(&mut self) -> Option<u16> {
+        self.detected_loop.take()
+    }
+
+    // meant to be called right before each instruction, from the run loop callback
+    pub fn should_execute(&mut self, cpu: &mut CPU) -> bool {
+        if let Some(command) = self.pending.take() {
+            self.apply(command, cpu);
+        }
+
+        if let Some(detector) = self.loop_detector.as_mut() {
+            if !self.paused && detector.observe(cpu.register_pc, cpu.bus.write_count()) {
+                self.detected_loop = Some(cpu.register_pc);
+                self.pause();
+            }
+        }
+
+        if let Some(target) = self.run_until {
+            if cpu.register_pc != target {
+                return true;
+            }
+            self.run_until = None;
+            self.paused = true;
+        }
+
+        if !self.paused {
+            return true;
+        }
+
+        if self.step_once {
+            self.step_once = false;
+            return true;
+        }
+
+        false
+    }
+
+    fn apply(&mut self, command: DebugCommand, cpu: &mut CPU) {
+        match command {
+            DebugCommand::Pause => self.pause(),
+            DebugCommand::Step => {
+                self.paused = true;
+                self.step_once = true;
+            }
+            // runs the whole subroutine and stops on the instruction after the JSR
+            DebugCommand::StepOver => {
+                self.paused = true;
+                if cpu.peek(cpu.register_pc) == JSR {
+                    self.run_until = Some(cpu.register_pc.wrapping_add(3));
+                } else {
+                    self.step_once = true;
+                }
+            }
+            DebugCommand::Continue => {
+                self.paused = false;
+                self.run_until = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::bus::BUS;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+
+    // runs the program, answering every pause with the next scripted command
+    // and recording how many instructions had executed at each pause
+    fn run_scripted(program: Vec<u8>, script: Vec<DebugCommand>) -> (CPU<'static>, Vec<u64>) {
+        let bus = BUS::new(
+            test_rom(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.load(program);
+        cpu.reset();
+        cpu.register_pc = 0x0600;
+
+        let mut debugger = Debugger::new(true);
+        let mut script = script.into_iter();
+        let mut pauses = vec![];
+        cpu.run_with_callback(|cpu| {
+            while !debugger.should_execute(cpu) {
+                pauses.push(cpu.instruction_count());
+                debugger.request(script.next().unwrap());
+            }
+        })
+        .unwrap();
+
+        (cpu, pauses)
+    }
+
+    #[test]
+    fn test_starts_paused_until_step() {
+        // INX; INX; INX; BRK
+        let (cpu, pauses) = run_scripted(
+            vec![0xe8, 0xe8, 0xe8, 0x00],
+            vec![
+                DebugCommand::Step,
+                DebugCommand::Step,
+                DebugCommand::Continue,
+            ],
+        );
+
+        assert_eq!(pauses, vec![0, 1, 2]);
+        assert_eq!(cpu.register_x, 3);
+    }
+
+    #[test]
+    fn test_step_over_runs_whole_subroutine() {
+        // JSR $0604; BRK; INX; INX; RTS
+        let (cpu, pauses) = run_scripted(
+            vec![0x20, 0x04, 0x06, 0x00, 0xe8, 0xe8, 0x60],
+            vec![DebugCommand::StepOver, DebugCommand::Continue],
+        );
+
+        assert_eq!(pauses, vec![0, 4]);
+        assert_eq!(cpu.register_x, 2);
+    }
+
+    #[test]
+    fn test_loop_detector_pauses_spin_loop() {
+        let bus = BUS::new(
+            test_rom(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.brk_ends_run = true;
+        // LDA #$01; STA $10; loop: LDA $10; BNE loop; BRK
+        cpu.load(vec![0xa9, 0x01, 0x85, 0x10, 0xa5, 0x10, 0xd0, 0xfc, 0x00]);
+        cpu.reset();
+        cpu.register_pc = 0x0600;
+
+        let mut debugger = Debugger::new(false);
+        debugger.detect_loops(50);
+        let mut pauses = vec![];
+        cpu.run_with_callback(|cpu| {
+            while !debugger.should_execute(cpu) {
+                assert_eq!(debugger.take_detected_loop(), Some(0x0604));
+                assert_eq!(debugger.take_detected_loop(), None);
+                pauses.push(cpu.instruction_count());
+                // release the loop
+                cpu.memory_write(0x10, 0);
+                debugger.request(DebugCommand::Continue);
+            }
+        })
+        .unwrap();
+
+        // the two setup instructions, then 50 times LDA/BNE
+        assert_eq!(pauses, vec![2 + 50 * 2]);
+        assert_eq!(cpu.register_pc, 0x0609);
+    }
+}
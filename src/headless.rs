@@ -0,0 +1,155 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::components::bus::BUS;
+use crate::components::cartridge::Rom;
+use crate::components::cpu::{CpuError, CPU};
+use crate::components::joypads::Joypad;
+use crate::components::ppu::PPU;
+
+/// Result of a timed run, `frames` can fall short of the request when the
+/// program hits BRK or jams.
+#[derive(Debug, Clone, Copy)]
+pub struct RunTiming {
+    pub frames: u64,
+    pub elapsed: Duration,
+}
+
+impl RunTiming {
+    pub fn frames_per_second(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// A machine without a window, sound or frame pacing.
+pub struct HeadlessSession {
+    cpu: CPU<'static>,
+    frames: Rc<Cell<u64>>,
+}
+
+impl HeadlessSession {
+    /// Fails when the cartridge's mapper is not supported.
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        let frames = Rc::new(Cell::new(0));
+        let counter = frames.clone();
+        let bus = BUS::new(
+            rom,
+            move |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {
+                counter.set(counter.get() + 1);
+            },
+        )?;
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        Ok(HeadlessSession { cpu, frames })
+    }
+
+    /// Swaps the cartridge, the machine starts over as on a power cycle.
+    /// The running game is kept when the new one is not supported.
+    pub fn load_rom(&mut self, rom: Rom) -> Result<(), String> {
+        *self = HeadlessSession::new(rom)?;
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU<'static> {
+        &self.cpu
+    }
+
+    /// Frames completed since the session started.
+    pub fn frames(&self) -> u64 {
+        self.frames.get()
+    }
+
+    /// Runs CPU and PPU flat out for `frames` frames, nothing is rendered.
+    /// Meant for benchmarks, unlike fast-forward which still presents.
+    pub fn run_uncapped(&mut self, frames: u64) -> Result<RunTiming, CpuError> {
+        let start_frame = self.frames.get();
+        let target = start_frame + frames;
+        let counter = self.frames.clone();
+
+        let start = Instant::now();
+        self.cpu.run_with_callback(|cpu| {
+            if counter.get() >= target {
+                cpu.stop();
+            }
+        })?;
+
+        Ok(RunTiming {
+            frames: self.frames.get() - start_frame,
+            elapsed: start.elapsed(),
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::components::cartridge::Mirroring;
+    use crate::playlist::Playlist;
+
+    // JMP to itself at `start`, which is also the reset vector
+    fn spinning_prg(start: u16) -> Vec<u8> {
+        let mut prg = vec![0; 0x8000];
+        let offset = (start - 0x8000) as usize;
+        let [low, high] = start.to_le_bytes();
+        prg[offset..offset + 3].copy_from_slice(&[0x4c, low, high]);
+        prg[0x7FFC] = low;
+        prg[0x7FFD] = high;
+        prg
+    }
+
+    pub fn spinning_rom() -> Rom {
+        Rom::from_parts(
+            spinning_prg(0x8000),
+            vec![0; 0x2000],
+            0,
+            Mirroring::Horizontal,
+        )
+    }
+
+    // as an .nes file, NROM-256 with 8KB CHR
+    fn spinning_image(start: u16) -> Vec<u8> {
+        let mut image = vec![
+            0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        image.extend(spinning_prg(start));
+        image.extend(vec![0; 0x2000]);
+        image
+    }
+
+    #[test]
+    fn test_run_uncapped_runs_requested_frames() {
+        let mut session = HeadlessSession::new(spinning_rom()).unwrap();
+
+        let timing = session.run_uncapped(3).unwrap();
+        assert_eq!(timing.frames, 3);
+        assert!(timing.elapsed > Duration::ZERO);
+        assert!(timing.frames_per_second() > 0.0);
+
+        assert_eq!(session.run_uncapped(2).unwrap().frames, 2);
+        assert_eq!(session.frames(), 5);
+        assert_eq!(session.cpu().register_pc, 0x8000);
+    }
+
+    #[test]
+    fn test_playlist_switch_resets_machine() {
+        let mut playlist =
+            Playlist::new(vec![spinning_image(0x8000), spinning_image(0x9000)]).unwrap();
+        let mut session = HeadlessSession::new(Rom::new(playlist.current()).unwrap()).unwrap();
+        session.run_uncapped(2).unwrap();
+        assert_eq!(session.cpu().register_pc, 0x8000);
+
+        session
+            .load_rom(Rom::new(playlist.advance()).unwrap())
+            .unwrap();
+        assert_eq!(playlist.index(), 1);
+        assert_eq!(session.frames(), 0);
+        assert_eq!(session.cpu().register_pc, 0x9000);
+        assert_eq!(session.cpu().instruction_count(), 0);
+
+        session.run_uncapped(1).unwrap();
+        assert_eq!(session.cpu().register_pc, 0x9000);
+        assert_eq!(Rom::new(playlist.advance()).unwrap().prg_rom[0x7FFD], 0x80);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use sdl2::keyboard::Keycode;
+
+use crate::components::joypads::JoypadButton;
+use crate::debugger::DebugCommand;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatorAction {
+    Quit,
+    SwitchPlayer,
+    Pause,
+    Step,
+    StepOver,
+    Continue,
+    ToggleOverlay,
+    NextGame,
+    PreviousGame,
+    VolumeUp,
+    VolumeDown,
+    ToggleMute,
+    TurboA,
+    TurboB,
+}
+
+const ACTION_NAMES: [(&str, EmulatorAction); 14] = [
+    ("quit", EmulatorAction::Quit),
+    ("switch_player", EmulatorAction::SwitchPlayer),
+    ("pause", EmulatorAction::Pause),
+    ("step", EmulatorAction::Step),
+    ("step_over", EmulatorAction::StepOver),
+    ("continue", EmulatorAction::Continue),
+    ("toggle_overlay", EmulatorAction::ToggleOverlay),
+    ("next_game", EmulatorAction::NextGame),
+    ("previous_game", EmulatorAction::PreviousGame),
+    ("volume_up", EmulatorAction::VolumeUp),
+    ("volume_down", EmulatorAction::VolumeDown),
+    ("toggle_mute", EmulatorAction::ToggleMute),
+    ("turbo_a", EmulatorAction::TurboA),
+    ("turbo_b", EmulatorAction::TurboB),
+];
+
+// keys that aren't a single character, by their SDL names
+const KEY_NAMES: [(&str, Keycode); 25] = [
+    ("Escape", Keycode::Escape),
+    ("Return", Keycode::Return),
+    ("Tab", Keycode::Tab),
+    ("Space", Keycode::Space),
+    ("Backspace", Keycode::Backspace),
+    ("PageUp", Keycode::PageUp),
+    ("PageDown", Keycode::PageDown),
+    ("Home", Keycode::Home),
+    ("End", Keycode::End),
+    ("Up", Keycode::Up),
+    ("Down", Keycode::Down),
+    ("Left", Keycode::Left),
+    ("Right", Keycode::Right),
+    ("F1", Keycode::F1),
+    ("F2", Keycode::F2),
+    ("F3", Keycode::F3),
+    ("F4", Keycode::F4),
+    ("F5", Keycode::F5),
+    ("F6", Keycode::F6),
+    ("F7", Keycode::F7),
+    ("F8", Keycode::F8),
+    ("F9", Keycode::F9),
+    ("F10", Keycode::F10),
+    ("F11", Keycode::F11),
+    ("F12", Keycode::F12),
+];
+
+fn parse_key(name: &str) -> Option<Keycode> {
+    if let Some((_, key)) = KEY_NAMES
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+    {
+        return Some(*key);
+    }
+    // SDL keycodes of printable keys are their lowercase character
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => Keycode::from_i32(c.to_ascii_lowercase() as i32),
+        _ => None,
+    }
+}
+
+impl EmulatorAction {
+    pub fn debug_command(self) -> Option<DebugCommand> {
+        match self {
+            EmulatorAction::Pause => Some(DebugCommand::Pause),
+            EmulatorAction::Step => Some(DebugCommand::Step),
+            EmulatorAction::StepOver => Some(DebugCommand::StepOver),
+            EmulatorAction::Continue => Some(DebugCommand::Continue),
+            _ => None,
+        }
+    }
+
+    /// The joypad button a turbo action fires.
+    pub fn turbo_button(self) -> Option<JoypadButton> {
+        match self {
+            EmulatorAction::TurboA => Some(JoypadButton::BUTTON_A),
+            EmulatorAction::TurboB => Some(JoypadButton::BUTTON_B),
+            _ => None,
+        }
+    }
+}
+
+/// Emulator hotkeys, kept apart from the joypad keymap so features don't
+/// grab keys on their own. Each action is bound to a single key.
+#[derive(Clone)]
+pub struct Hotkeys {
+    bindings: HashMap<Keycode, EmulatorAction>,
+}
+
+impl Default for Hotkeys {
+    fn default() -> Self {
+        let mut hotkeys = Hotkeys {
+            bindings: HashMap::new(),
+        };
+        hotkeys.bind(Keycode::Escape, EmulatorAction::Quit);
+        hotkeys.bind(Keycode::F2, EmulatorAction::SwitchPlayer);
+        hotkeys.bind(Keycode::F6, EmulatorAction::Pause);
+        hotkeys.bind(Keycode::F7, EmulatorAction::Step);
+        hotkeys.bind(Keycode::F8, EmulatorAction::StepOver);
+        hotkeys.bind(Keycode::F9, EmulatorAction::Continue);
+        hotkeys.bind(Keycode::F10, EmulatorAction::ToggleOverlay);
+        hotkeys.bind(Keycode::PageDown, EmulatorAction::NextGame);
+        hotkeys.bind(Keycode::PageUp, EmulatorAction::PreviousGame);
+        hotkeys.bind(Keycode::Equals, EmulatorAction::VolumeUp);
+        hotkeys.bind(Keycode::Minus, EmulatorAction::VolumeDown);
+        hotkeys.bind(Keycode::M, EmulatorAction::ToggleMute);
+        hotkeys.bind(Keycode::J, EmulatorAction::TurboA);
+        hotkeys.bind(Keycode::K, EmulatorAction::TurboB);
+        hotkeys
+    }
+}
+
+impl Hotkeys {
+    /// The defaults with the `key=action` lines of `text` bound over them,
+    /// blank lines and `#` comments are skipped.
+    pub fn load(text: &str) -> Result<Self, String> {
+        let mut hotkeys = Hotkeys::default();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, action) = line
+                .split_once('=')
+                .ok_or(format!("Line {}: expected key=action", number))?;
+            let (key, action) = (key.trim(), action.trim());
+            let key = parse_key(key).ok_or(format!("Line {}: unknown key '{}'", number, key))?;
+            let action = ACTION_NAMES
+                .iter()
+                .find(|(name, _)| *name == action)
+                .map(|(_, action)| *action)
+                .ok_or(format!("Line {}: unknown action '{}'", number, action))?;
+            hotkeys.bind(key, action);
+        }
+        Ok(hotkeys)
+    }
+
+    // moves `action` to `key`, replacing whatever `key` did before
+    pub fn bind(&mut self, key: Keycode, action: EmulatorAction) {
+        self.bindings.retain(|_, bound| *bound != action);
+        self.bindings.insert(key, action);
+    }
+
+    pub fn action(&self, key: Keycode) -> Option<EmulatorAction> {
+        self.bindings.get(&key).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::bus::BUS;
+    use crate::components::cartridge::test::test_rom;
+    use crate::components::cpu::CPU;
+    use crate::components::joypads::Joypad;
+    use crate::components::ppu::PPU;
+    use crate::debugger::Debugger;
+
+    #[test]
+    fn test_loaded_hotkeys_overlay_the_defaults() {
+        let hotkeys = Hotkeys::load("# turbo on the home row\nl = turbo_a\n\nF12=quit\n").unwrap();
+        assert_eq!(hotkeys.action(Keycode::L), Some(EmulatorAction::TurboA));
+        assert_eq!(hotkeys.action(Keycode::J), None);
+        assert_eq!(hotkeys.action(Keycode::K), Some(EmulatorAction::TurboB));
+        assert_eq!(hotkeys.action(Keycode::F6), Some(EmulatorAction::Pause));
+        assert_eq!(hotkeys.action(Keycode::F12), Some(EmulatorAction::Quit));
+        assert_eq!(hotkeys.action(Keycode::Escape), None);
+
+        assert_eq!(
+            Hotkeys::load("F13=quit").err(),
+            Some("Line 1: unknown key 'F13'".to_string())
+        );
+        assert_eq!(
+            Hotkeys::load("p=rewind").err(),
+            Some("Line 1: unknown action 'rewind'".to_string())
+        );
+        assert_eq!(
+            Hotkeys::load("p").err(),
+            Some("Line 1: expected key=action".to_string())
+        );
+    }
+
+    #[test]
+    fn test_remapped_pause_triggers_pause() {
+        let mut hotkeys = Hotkeys::default();
+        hotkeys.bind(Keycode::P, EmulatorAction::Pause);
+
+        assert_eq!(hotkeys.action(Keycode::F6), None);
+        assert_eq!(hotkeys.action(Keycode::P), Some(EmulatorAction::Pause));
+
+        let mut cpu = CPU::new(
+            BUS::new(
+                test_rom(),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        let mut debugger = Debugger::new(false);
+        assert!(debugger.should_execute(&mut cpu));
+
+        let command = hotkeys
+            .action(Keycode::P)
+            .and_then(|action| action.debug_command())
+            .unwrap();
+        debugger.request(command);
+        assert!(!debugger.should_execute(&mut cpu));
+        assert!(debugger.is_paused());
+    }
+}
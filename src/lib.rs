@@ -1,19 +1,42 @@
+pub mod audio;
 pub mod components;
+pub mod debugger;
+pub mod font;
+pub mod headless;
+pub mod hotkeys;
+pub mod playlist;
 pub mod render;
+pub mod replay;
+pub mod threaded;
+pub mod timing;
 pub mod trace;
 
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
 
-use components::bus::BUS;
+use audio::{AudioConfig, Resampler};
+use components::apu::TvSystem;
+use components::bus::{UninitializedRead, BUS};
 use components::cartridge::Rom;
 use components::cpu::CPU;
-use components::joypads::{Joypad, JoypadButton};
+use components::joypads::{self, Joypad, JoypadButton, PlayerSelect};
 use components::ppu::PPU;
-use render::Frame;
+use debugger::Debugger;
+use hotkeys::{EmulatorAction, Hotkeys};
+use playlist::Playlist;
+use threaded::{InputEvent, SessionOptions, ThreadedSession};
+use render::{Frame, PixelFormat};
+use trace::trace;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
 
 use fps_clock::FpsClock;
 
@@ -23,7 +46,95 @@ extern crate lazy_static;
 #[macro_use]
 extern crate bitflags;
 
-pub fn run(game: &str) {
+#[derive(Default, Clone)]
+pub struct RunOptions {
+    /// Start paused at the reset vector with the debugger overlay on.
+    pub debug: bool,
+    pub hotkeys: Hotkeys,
+    /// Keys for the pads in both frontends, `joypads::default_keymap` when unset.
+    pub keymap: Option<HashMap<Keycode, JoypadButton>>,
+    /// Texture format the frames are uploaded as, some backends prefer ARGB8888.
+    pub pixel_format: PixelFormat,
+    /// Pause into the debugger after this many iterations of a spin loop.
+    pub loop_threshold: Option<u32>,
+    /// Emulate on a separate thread and only present frames on this one.
+    /// Player 1 input only, no debugger, turbo or playlist hotkeys.
+    pub threaded: bool,
+    pub audio: AudioConfig,
+    /// Draw each line as the PPU reaches it, for games that scroll mid-frame.
+    pub scanline_rendering: bool,
+    /// PAL games need the PAL APU tables and clock to play at the right pitch.
+    pub tv_system: TvSystem,
+}
+
+impl RunOptions {
+    fn keymap(&self) -> HashMap<Keycode, JoypadButton> {
+        self.keymap.clone().unwrap_or_else(joypads::default_keymap)
+    }
+}
+
+fn sdl_format(format: PixelFormat) -> PixelFormatEnum {
+    match format {
+        PixelFormat::Rgb24 => PixelFormatEnum::RGB24,
+        PixelFormat::Argb8888 => PixelFormatEnum::ARGB8888,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GameSwitch {
+    Next,
+    Previous,
+}
+
+// battery saves live next to the ROM
+fn sram_path(game: &str) -> PathBuf {
+    PathBuf::from(format!("games/{}.sav", game))
+}
+
+fn load_game(game: &str) -> Rom {
+    let path_to_game = format!("games/{}.nes", game);
+    let bytes: Vec<u8> = std::fs::read(path_to_game).unwrap();
+    let rom = Rom::new(&bytes).unwrap();
+    if let Some(warning) = rom.size_warning() {
+        println!("Warning: {}", warning);
+    }
+    rom
+}
+
+pub fn run(game: &str, options: RunOptions) {
+    run_rom(game, load_game(game), options);
+}
+
+pub fn run_rom(game: &str, rom: Rom, options: RunOptions) {
+    let keymap = options.keymap();
+    if options.threaded {
+        play_threaded(game, rom, options, &keymap);
+    } else {
+        play(game, rom, options, &keymap, false);
+    }
+}
+
+/// Runs the games in turn, the next/previous game hotkeys power cycle into
+/// the neighbouring entry.
+pub fn run_playlist(games: Vec<String>, options: RunOptions) {
+    let mut playlist = Playlist::new(games).unwrap();
+    let keymap = options.keymap();
+    loop {
+        let game = playlist.current().clone();
+        match play(&game, load_game(&game), options.clone(), &keymap, true) {
+            Some(GameSwitch::Next) => playlist.advance(),
+            Some(GameSwitch::Previous) => playlist.rewind(),
+            None => break,
+        };
+    }
+}
+
+fn play_threaded(
+    game: &str,
+    rom: Rom,
+    options: RunOptions,
+    keymap: &HashMap<Keycode, JoypadButton>,
+) {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
@@ -38,52 +149,288 @@ pub fn run(game: &str) {
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .create_texture_target(sdl_format(options.pixel_format), 256, 240)
         .unwrap();
+    let pixel_format = options.pixel_format;
 
-    let path_to_game = format!("games/{}.nes", game);
-    let bytes: Vec<u8> = std::fs::read(path_to_game).unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    let audio = open_audio(&sdl_context, options.audio);
+    let session = ThreadedSession::spawn_with_options(
+        rom,
+        SessionOptions {
+            sram_path: Some(sram_path(game)),
+            audio: Some(options.audio),
+        },
+    );
+    while !session.is_finished() {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } => return,
+
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                } if options.hotkeys.action(keycode) == Some(EmulatorAction::Quit) => return,
+
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                }
+                | Event::KeyUp {
+                    keycode: Some(keycode),
+                    ..
+                } => {
+                    if let Some(button) = keymap.get(&keycode) {
+                        session.send_input(InputEvent::Button {
+                            player: 0,
+                            button: *button,
+                            pressed: matches!(event, Event::KeyDown { .. }),
+                        });
+                    }
+                }
+
+                _ => { /* do nothing */ }
+            }
+        }
+
+        let samples = session.take_audio();
+        if !samples.is_empty() {
+            audio.queue_audio(&samples).unwrap();
+        }
+
+        // vsync paces this loop, the emulation waits on the frame channel
+        if let Some(frame) = session.recv_frame(Duration::from_millis(100)) {
+            texture
+                .update(None, &frame.texture_bytes(pixel_format), pixel_format.pitch())
+                .unwrap();
+            canvas.copy(&texture, None, None).unwrap();
+            canvas.present();
+        }
+    }
+}
+
+// samples handed to SDL at once
+const AUDIO_CHUNK: usize = 1024;
+const VOLUME_STEP: f32 = 0.1;
+
+fn open_audio(sdl_context: &sdl2::Sdl, config: AudioConfig) -> AudioQueue<f32> {
+    let desired = AudioSpecDesired {
+        freq: Some(config.sample_rate as i32),
+        channels: Some(config.channels.count()),
+        samples: None,
+    };
+    let queue = sdl_context
+        .audio()
+        .unwrap()
+        .open_queue::<f32, _>(None, &desired)
+        .unwrap();
+    queue.resume();
+    queue
+}
+
+const OVERLAY_COLOR: (u8, u8, u8) = (0xff, 0xff, 0xff);
+
+// the window and the last emulated frame, kept to show the debugger overlay
+// while paused, when no new frames come
+struct Screen<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    pixel_format: PixelFormat,
+    frame: Frame,
+}
+
+impl Screen<'_> {
+    // the overlay is drawn on a copy, the emulated frame stays as it was
+    fn present(&mut self, overlay: &[String]) {
+        let mut shown = None;
+        if !overlay.is_empty() {
+            let frame = shown.insert(self.frame.clone());
+            for (row, line) in overlay.iter().enumerate() {
+                font::draw_text(frame, 0, row * font::GLYPH_SIZE, line, OVERLAY_COLOR);
+            }
+        }
+        let frame = shown.as_ref().unwrap_or(&self.frame);
+        self.texture
+            .update(None, &frame.texture_bytes(self.pixel_format), self.pixel_format.pitch())
+            .unwrap();
+        self.canvas.copy(&self.texture, None, None).unwrap();
+        self.canvas.present();
+    }
+}
+
+// the next instruction and the PPU latches, wrapped to the screen width
+fn overlay_lines(cpu: &mut CPU) -> Vec<String> {
+    let line = trace(cpu);
+    let (instruction, registers) = line.split_at(line.find(" A:").unwrap_or(line.len()));
+    let ppu = cpu.bus.ppu();
+    let latches = format!("BUF:{:02X} OAM:{:02X}", ppu.read_buffer(), ppu.oam_address());
+
+    let columns = Frame::WIDTH / font::GLYPH_SIZE;
+    let characters: Vec<char> = instruction.trim_end().chars().collect();
+    let mut lines: Vec<String> = characters
+        .chunks(columns)
+        .map(|chunk| chunk.iter().collect())
+        .collect();
+    lines.push(registers.trim().to_string());
+    lines.push(latches);
+    lines
+}
+
+// each place a game read RAM it never wrote once, however often it did
+fn print_uninitialized_reads(reads: &[UninitializedRead]) {
+    let mut seen = HashSet::new();
+    for read in reads {
+        if seen.insert(*read) {
+            println!(
+                "Read of uninitialized RAM at {:04x} from PC {:04x}",
+                read.address, read.pc
+            );
+        }
+    }
+}
+
+// returns the game switch that ended the run, if any
+fn play(
+    game: &str,
+    rom: Rom,
+    options: RunOptions,
+    keymap: &HashMap<Keycode, JoypadButton>,
+    playlist: bool,
+) -> Option<GameSwitch> {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let window = video_subsystem
+        .window(game, (256.0 * 3.0) as u32, (240.0 * 3.0) as u32)
+        .position_centered()
+        .build()
+        .unwrap();
+
+    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
+    let event_pump = Rc::new(RefCell::new(sdl_context.event_pump().unwrap()));
+    canvas.set_scale(3.0, 3.0).unwrap();
+
+    let creator = canvas.texture_creator();
+    let texture = creator
+        .create_texture_target(sdl_format(options.pixel_format), 256, 240)
+        .unwrap();
 
     let mut frame = Frame::new();
+    let screen = Rc::new(RefCell::new(Screen {
+        canvas,
+        texture,
+        pixel_format: options.pixel_format,
+        frame: Frame::new(),
+    }));
+    let frame_screen = screen.clone();
+    let overlay = Rc::new(RefCell::new(vec![]));
+    let frame_overlay = overlay.clone();
 
-    let mut keymap = HashMap::new();
-    keymap.insert(Keycode::W, JoypadButton::UP);
-    keymap.insert(Keycode::A, JoypadButton::LEFT);
-    keymap.insert(Keycode::S, JoypadButton::DOWN);
-    keymap.insert(Keycode::D, JoypadButton::RIGHT);
-    keymap.insert(Keycode::Space, JoypadButton::BUTTON_A);
-    keymap.insert(Keycode::E, JoypadButton::BUTTON_B);
-    keymap.insert(Keycode::Return, JoypadButton::START);
-    keymap.insert(Keycode::Tab, JoypadButton::SELECT);
+    let title = game.to_string();
+    let mut players = PlayerSelect::new();
 
-    let mut fps = FpsClock::new(60);
-    let bus = BUS::new(rom, move |ppu: &PPU, joypad: &mut Joypad| {
+    let debugger = Rc::new(RefCell::new(Debugger::new(options.debug)));
+    if let Some(threshold) = options.loop_threshold {
+        debugger.borrow_mut().detect_loops(threshold);
+    }
+    let frame_debugger = debugger.clone();
+    let frame_event_pump = event_pump.clone();
+    let hotkeys = options.hotkeys;
+    let frame_hotkeys = hotkeys.clone();
+    let switch = Rc::new(Cell::new(None));
+    let frame_switch = switch.clone();
+    let volume = Rc::new(Cell::new(1.0f32));
+    let frame_volume = volume.clone();
+    let muted = Rc::new(Cell::new(false));
+    let frame_muted = muted.clone();
+    let quit = Rc::new(Cell::new(false));
+    let frame_quit = quit.clone();
 
-        render::render(ppu, &mut frame);
+    let tv_system = options.tv_system;
+    let mut fps = FpsClock::new(tv_system.frame_rate().round() as u32);
+    let bus = BUS::new_with_tv_system(rom, tv_system, move |ppu: &PPU, joypad1: &mut Joypad, joypad2: &mut Joypad| {
 
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+        joypad1.next_frame();
+        joypad2.next_frame();
 
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
+        let shown = match ppu.frame() {
+            Some(lines) => lines,
+            None => {
+                render::render(ppu, &mut frame);
+                &frame
+            }
+        };
 
-        for event in event_pump.poll_iter() {
+        {
+            let mut screen = frame_screen.borrow_mut();
+            screen.frame.clone_from(shown);
+            if frame_debugger.borrow().is_paused() {
+                screen.present(&frame_overlay.borrow());
+            } else {
+                screen.present(&[]);
+            }
+        }
+
+        for event in frame_event_pump.borrow_mut().poll_iter() {
             match event {
-                Event::Quit { .. }
-                | Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
+                Event::Quit { .. } => frame_quit.set(true),
+
+                Event::KeyDown {
+                    keycode: Some(keycode),
                     ..
-                } => std::process::exit(0),
+                } if frame_hotkeys.action(keycode).is_some() => {
+                    match frame_hotkeys.action(keycode) {
+                        Some(EmulatorAction::Quit) => frame_quit.set(true),
+                        Some(EmulatorAction::SwitchPlayer) => {
+                            players.toggle(joypad1, joypad2);
+                            let title = format!("{} - player {}", title, players.active() + 1);
+                            frame_screen.borrow_mut().canvas.window_mut().set_title(&title).unwrap();
+                        }
+                        Some(EmulatorAction::ToggleOverlay) => {
+                            let mut debugger = frame_debugger.borrow_mut();
+                            debugger.overlay = !debugger.overlay;
+                        }
+                        Some(EmulatorAction::NextGame) if playlist => {
+                            frame_switch.set(Some(GameSwitch::Next));
+                        }
+                        Some(EmulatorAction::PreviousGame) if playlist => {
+                            frame_switch.set(Some(GameSwitch::Previous));
+                        }
+                        Some(EmulatorAction::VolumeUp) => {
+                            frame_volume.set((frame_volume.get() + VOLUME_STEP).min(1.0));
+                        }
+                        Some(EmulatorAction::VolumeDown) => {
+                            frame_volume.set((frame_volume.get() - VOLUME_STEP).max(0.0));
+                        }
+                        Some(EmulatorAction::ToggleMute) => frame_muted.set(!frame_muted.get()),
+                        Some(action) => {
+                            if let Some(button) = action.turbo_button() {
+                                players
+                                    .select(joypad1, joypad2)
+                                    .set_turbo_pressed_status(button, true)
+                            } else if let Some(command) = action.debug_command() {
+                                frame_debugger.borrow_mut().request(command);
+                            }
+                        }
+                        None => {}
+                    }
+                }
 
                 Event::KeyDown { keycode, .. } => {
-                    if let Some(key) = keymap.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, true)
-                    }
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    players
+                        .select(joypad1, joypad2)
+                        .set_key_pressed_status(keycode, true);
                 }
 
                 Event::KeyUp { keycode, .. } => {
-                    if let Some(key) = keymap.get(&keycode.unwrap_or(Keycode::Ampersand)) {
-                        joypad.set_button_pressed_status(*key, false)
+                    let keycode = keycode.unwrap_or(Keycode::Ampersand);
+                    players
+                        .select(joypad1, joypad2)
+                        .set_key_pressed_status(keycode, false);
+                    let action = frame_hotkeys.action(keycode);
+                    if let Some(button) = action.and_then(EmulatorAction::turbo_button) {
+                        players
+                            .select(joypad1, joypad2)
+                            .set_turbo_pressed_status(button, false)
                     }
                 }
 
@@ -92,9 +439,106 @@ pub fn run(game: &str) {
         }
         fps.tick();
     });
+    let mut bus = match bus {
+        Ok(bus) => bus,
+        Err(err) => {
+            println!("{}", err);
+            return None;
+        }
+    };
 
+    if options.scanline_rendering {
+        bus.ppu_mut().enable_scanline_rendering();
+    }
+    // either pad can be the active player, so both take the keys
+    bus.joypad1_mut().keymap = keymap.clone();
+    bus.joypad2_mut().keymap = keymap.clone();
+    let sram_path = sram_path(game);
+    if let Err(err) = bus.load_sram(&sram_path) {
+        println!("Could not load {}: {}", sram_path.display(), err);
+    }
     let mut cpu = CPU::new(bus);
+    if options.debug {
+        cpu.bus.track_uninitialized_ram();
+    }
+    let audio = open_audio(&sdl_context, options.audio);
+    let mut resampler = Resampler::new(options.audio, tv_system.cpu_clock());
+    let mut last_cycles = 0;
 
     cpu.reset();
-    cpu.run();
+    let run_switch = switch.clone();
+    let run_quit = quit.clone();
+    let result = cpu.run_with_callback(move |cpu| {
+        let apu = cpu.bus.apu_mut();
+        apu.set_master_volume(volume.get());
+        apu.set_muted(muted.get());
+
+        // the level after each instruction stands for all of its cycles
+        let cycles = cpu.bus.cycles();
+        resampler.push(cpu.bus.apu().current_output(), cycles - last_cycles);
+        last_cycles = cycles;
+        if resampler.pending() >= AUDIO_CHUNK {
+            audio.queue_audio(&resampler.take_samples()).unwrap();
+        }
+
+        if run_switch.get().is_some() || run_quit.get() {
+            cpu.stop();
+            return;
+        }
+
+        if debugger.borrow_mut().should_execute(cpu) {
+            return;
+        }
+        if let Some(pc) = debugger.borrow_mut().take_detected_loop() {
+            println!("Possible infinite loop at {:04x}", pc);
+        }
+
+        if debugger.borrow().overlay {
+            *overlay.borrow_mut() = overlay_lines(cpu);
+        } else {
+            overlay.borrow_mut().clear();
+        }
+        // no frames are produced while paused, so show the overlay on the last
+        // one and keep polling here
+        screen.borrow_mut().present(&overlay.borrow());
+        while !debugger.borrow_mut().should_execute(cpu) {
+            for event in event_pump.borrow_mut().poll_iter() {
+                match event {
+                    Event::Quit { .. } => run_quit.set(true),
+
+                    Event::KeyDown {
+                        keycode: Some(keycode),
+                        ..
+                    } => match hotkeys.action(keycode) {
+                        Some(EmulatorAction::Quit) => run_quit.set(true),
+                        Some(action) => {
+                            if let Some(command) = action.debug_command() {
+                                debugger.borrow_mut().request(command);
+                            }
+                        }
+                        None => {}
+                    },
+
+                    _ => { /* do nothing */ }
+                }
+            }
+            if run_quit.get() {
+                cpu.stop();
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    });
+    if let Err(err) = result {
+        println!("{}", err);
+    }
+
+    if let Err(err) = cpu.bus.save_sram(&sram_path) {
+        println!("Could not save {}: {}", sram_path.display(), err);
+    }
+    print_uninitialized_reads(cpu.bus.uninitialized_reads());
+    if quit.get() {
+        std::process::exit(0);
+    }
+    switch.get()
 }
@@ -1,12 +1,61 @@
 use std::io::stdin;
 
+use nes::audio::{AudioConfig, Channels};
+use nes::components::apu::TvSystem;
+use nes::hotkeys::Hotkeys;
+use nes::RunOptions;
+
+// --sample-rate=48000 and --stereo, 44100Hz mono otherwise
+fn audio_config() -> AudioConfig {
+    let mut config = AudioConfig::default();
+    for arg in std::env::args() {
+        if let Some(rate) = arg.strip_prefix("--sample-rate=") {
+            config.sample_rate = rate.parse().expect("Invalid sample rate");
+        } else if arg == "--stereo" {
+            config.channels = Channels::Stereo;
+        }
+    }
+    config
+}
+
+// hotkeys.cfg overrides the default hotkeys, one key=action per line
+fn hotkeys() -> Hotkeys {
+    match std::fs::read_to_string("hotkeys.cfg") {
+        Ok(text) => Hotkeys::load(&text).unwrap_or_else(|err| {
+            println!("Ignoring hotkeys.cfg: {}", err);
+            Hotkeys::default()
+        }),
+        Err(_) => Hotkeys::default(),
+    }
+}
+
 fn main() {
+    let options = RunOptions {
+        audio: audio_config(),
+        hotkeys: hotkeys(),
+        debug: std::env::args().any(|arg| arg == "--debug"),
+        threaded: std::env::args().any(|arg| arg == "--threaded"),
+        scanline_rendering: std::env::args().any(|arg| arg == "--scanline"),
+        tv_system: if std::env::args().any(|arg| arg == "--pal") {
+            TvSystem::Pal
+        } else {
+            TvSystem::Ntsc
+        },
+        ..Default::default()
+    };
+
     let mut game = String::new();
 
     println!("Enter the name of game (without symbols and spaces)");
+    println!("Several names separated by spaces make a playlist");
     stdin().read_line(&mut game).expect("Read Line error");
 
     game = game.trim().to_lowercase();
+    let games: Vec<String> = game.split_whitespace().map(str::to_string).collect();
 
-    nes::run(&game);
+    if games.len() > 1 {
+        nes::run_playlist(games, options);
+    } else {
+        nes::run(&game, options);
+    }
 }
@@ -0,0 +1,52 @@
+/// Games to cycle through with the next/previous hotkeys, wrapping around
+/// at both ends.
+pub struct Playlist<T> {
+    entries: Vec<T>,
+    current: usize,
+}
+
+impl<T> Playlist<T> {
+    pub fn new(entries: Vec<T>) -> Result<Self, String> {
+        if entries.is_empty() {
+            return Err("Playlist is empty".to_string());
+        }
+        Ok(Playlist {
+            entries,
+            current: 0,
+        })
+    }
+
+    pub fn current(&self) -> &T {
+        &self.entries[self.current]
+    }
+
+    pub fn index(&self) -> usize {
+        self.current
+    }
+
+    pub fn advance(&mut self) -> &T {
+        self.current = (self.current + 1) % self.entries.len();
+        self.current()
+    }
+
+    pub fn rewind(&mut self) -> &T {
+        self.current = (self.current + self.entries.len() - 1) % self.entries.len();
+        self.current()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_playlist_wraps_both_ways() {
+        let mut playlist = Playlist::new(vec!["pacman", "mario", "zelda"]).unwrap();
+
+        assert_eq!(*playlist.rewind(), "zelda");
+        assert_eq!(*playlist.advance(), "pacman");
+        assert_eq!(*playlist.advance(), "mario");
+        assert_eq!(playlist.index(), 1);
+        assert!(Playlist::<&str>::new(vec![]).is_err());
+    }
+}
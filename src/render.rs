@@ -1,4 +1,6 @@
-use crate::components::ppu::PPU;
+use std::borrow::Cow;
+
+use crate::components::ppu::{Emphasis, PPU};
 
 pub static SYSTEM_PALLETE: [(u8, u8, u8); 64] = [
     (0x80, 0x80, 0x80),
@@ -67,17 +69,45 @@ pub static SYSTEM_PALLETE: [(u8, u8, u8); 64] = [
     (0x11, 0x11, 0x11),
 ];
 
+// roughly how much an emphasis bit dims the other channels on NTSC sets
+const EMPHASIS_ATTENUATION: f32 = 0.816;
+
+/// Texture layouts the frame can be uploaded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PixelFormat {
+    #[default]
+    Rgb24,
+    Argb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb24 => 3,
+            PixelFormat::Argb8888 => 4,
+        }
+    }
+
+    // bytes per texture row
+    pub fn pitch(self) -> usize {
+        Frame::WIDTH * self.bytes_per_pixel()
+    }
+}
+
+#[derive(Clone)]
 pub struct Frame {
     pub data: Vec<u8>,
+    index_buffer: Vec<u8>,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HIGHT: usize = 240;
+    pub const WIDTH: usize = 256;
+    pub const HIGHT: usize = 240;
 
     pub fn new() -> Self {
         Frame {
             data: vec![0; (Frame::WIDTH) * (Frame::HIGHT) * 3],
+            index_buffer: vec![0; (Frame::WIDTH) * (Frame::HIGHT)],
         }
     }
 
@@ -89,11 +119,114 @@ impl Frame {
             self.data[base + 2] = rgb.2;
         }
     }
+
+    // sets both the RGB pixel and its NES palette index
+    pub fn set_palette_pixel(&mut self, x: usize, y: usize, palette_index: u8) {
+        if x < Frame::WIDTH && y < Frame::HIGHT {
+            self.index_buffer[y * Frame::WIDTH + x] = palette_index;
+        }
+        self.set_pixel(x, y, SYSTEM_PALLETE[palette_index as usize]);
+    }
+
+    /// The system palette index (0-63) of every pixel, row by row.
+    pub fn index_buffer(&self) -> &[u8] {
+        &self.index_buffer
+    }
+
+    /// The distinct palette indices in a `width` x `height` box, sorted. The
+    /// box is clipped to the screen.
+    pub fn region_indices(&self, x: usize, y: usize, width: usize, height: usize) -> Vec<u8> {
+        let mut present = [false; 64];
+        for row in y..(y + height).min(Frame::HIGHT) {
+            for column in x..(x + width).min(Frame::WIDTH) {
+                present[self.index_buffer[row * Frame::WIDTH + column] as usize] = true;
+            }
+        }
+        (0..64u8).filter(|&index| present[index as usize]).collect()
+    }
+
+    // RGB24 is the frame's own layout, ARGB8888 is a packed native endian u32
+    pub fn texture_bytes(&self, format: PixelFormat) -> Cow<'_, [u8]> {
+        match format {
+            PixelFormat::Rgb24 => Cow::Borrowed(&self.data),
+            PixelFormat::Argb8888 => Cow::Owned(
+                self.data
+                    .chunks(3)
+                    .flat_map(|rgb| {
+                        u32::from_be_bytes([0xff, rgb[0], rgb[1], rgb[2]]).to_ne_bytes()
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Decodes tile `tile_index` of a pattern table into RGB pixels, `palette` holds
+/// the system palette indices for color values 0-3.
+pub fn render_tile(chr: &[u8], tile_index: usize, palette: [u8; 4]) -> [[(u8, u8, u8); 8]; 8] {
+    let tile = &chr[tile_index * 16..tile_index * 16 + 16];
+    let mut pixels = [[(0, 0, 0); 8]; 8];
+
+    for y in 0..=7 {
+        let mut upper = tile[y];
+        let mut lower = tile[y + 8];
+
+        for x in (0..=7).rev() {
+            let value = (1 & lower) << 1 | (1 & upper);
+            upper >>= 1;
+            lower >>= 1;
+            pixels[y][x] = SYSTEM_PALLETE[palette[value as usize] as usize];
+        }
+    }
+
+    pixels
+}
+
+const PATTERN_TILES: usize = 512;
+
+/// Both pattern tables decoded to color values 0-3, for viewers that redraw
+/// every frame. Only the tiles the PPU marked dirty are decoded again.
+pub struct TileCache {
+    tiles: Vec<[[u8; 8]; 8]>,
+}
+
+impl Default for TileCache {
+    fn default() -> Self {
+        TileCache::new()
+    }
+}
+
+impl TileCache {
+    pub fn new() -> Self {
+        TileCache {
+            tiles: vec![[[0; 8]; 8]; PATTERN_TILES],
+        }
+    }
+
+    /// Decodes the dirty tiles and clears them, returns how many there were.
+    pub fn update(&mut self, ppu: &mut PPU) -> usize {
+        let dirty = ppu.chr_dirty.dirty_regions();
+        for &index in &dirty {
+            let tile = ppu.chr_tile(index as u16 * 16);
+            for (y, row) in self.tiles[index].iter_mut().enumerate() {
+                for (x, value) in row.iter_mut().enumerate() {
+                    let bit = 7 - x;
+                    *value = (tile[y] >> bit & 1) | (tile[y + 8] >> bit & 1) << 1;
+                }
+            }
+        }
+        ppu.chr_dirty.clear();
+        dirty.len()
+    }
+
+    pub fn tile(&self, index: usize) -> &[[u8; 8]; 8] {
+        &self.tiles[index]
+    }
 }
 
-fn bg_pallette(ppu: &PPU, tile_column: usize, tile_row: usize) -> [u8; 4] {
+fn bg_pallette(ppu: &PPU, attribute_table: &[u8], tile_column: usize, tile_row: usize) -> [u8; 4] {
     let attr_table_idx = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3c0 + attr_table_idx]; // note: still using hardcoded first nametable
+    let attr_byte = attribute_table[attr_table_idx];
 
     let pallet_idx = match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
@@ -122,79 +255,509 @@ fn sprite_palette(ppu: &PPU, pallete_idx: u8) -> [u8; 4] {
     ]
 }
 
+/// Draws the whole frame at once from the current PPU state, scrolled to the
+/// top left of the nametable PPUCTRL selects.
 pub fn render(ppu: &PPU, frame: &mut Frame) {
+    let nametable = ((ppu.control.nametable_address() - 0x2000) / 0x400) as usize;
+    let (x, y) = ((nametable & 1) * 256, (nametable >> 1) * 240);
+    for line in 0..Frame::HIGHT {
+        render_line(ppu, frame, line, (x, y + line));
+    }
+
+    apply_emphasis(ppu.mask.emphasise(), &mut frame.data);
+}
+
+/// Draws one line, `scroll` is where its first pixel is in the 512x480 plane
+/// of the four nametables.
+pub fn render_scanline(ppu: &PPU, frame: &mut Frame, line: usize, scroll: (usize, usize)) {
+    render_line(ppu, frame, line, scroll);
+
+    let row = line * Frame::WIDTH * 3;
+    apply_emphasis(
+        ppu.mask.emphasise(),
+        &mut frame.data[row..row + Frame::WIDTH * 3],
+    );
+}
+
+fn render_line(ppu: &PPU, frame: &mut Frame, line: usize, scroll: (usize, usize)) {
+    // sprites behind the background only show through its color 0 pixels
+    let mut background_opaque = [false; Frame::WIDTH];
+    if ppu.force_render || ppu.mask.show_background() {
+        render_background(ppu, frame, line, scroll, &mut background_opaque);
+    } else {
+        let backdrop = ppu.palette_table[0];
+        for x in 0..Frame::WIDTH {
+            frame.set_palette_pixel(x, line, backdrop);
+        }
+    }
+
+    if ppu.force_render || ppu.mask.show_sprites() {
+        render_sprites(ppu, frame, line, &background_opaque);
+    }
+}
+
+// each emphasis bit dims the two other channels, the index buffer keeps the
+// undimmed palette indices
+fn apply_emphasis(emphasis: Emphasis, pixels: &mut [u8]) {
+    if !emphasis.any() {
+        return;
+    }
+
+    let bits = [emphasis.red, emphasis.green, emphasis.blue];
+    let scales: Vec<f32> = (0..3)
+        .map(|channel| {
+            let dimming = (0..3)
+                .filter(|&other| other != channel && bits[other])
+                .count();
+            EMPHASIS_ATTENUATION.powi(dimming as i32)
+        })
+        .collect();
+
+    for rgb in pixels.chunks_mut(3) {
+        for (value, scale) in rgb.iter_mut().zip(scales.iter()) {
+            *value = (*value as f32 * scale) as u8;
+        }
+    }
+}
+
+fn render_background(
+    ppu: &PPU,
+    frame: &mut Frame,
+    line: usize,
+    (scroll_x, scroll_y): (usize, usize),
+    opaque: &mut [bool],
+) {
     let bank = ppu.control.bknd_pattern_address();
+    let clip_left = !ppu.force_render && !ppu.mask.leftmost_8pxl_background();
 
-    for i in 0..0x3c0 {
-        let tile = ppu.vram[i] as u16;
-        let tile_column = i % 32;
-        let tile_row = i / 32;
-        let tile = &ppu.chr_rom[(bank + tile * 16) as usize..=(bank + tile * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, tile_column, tile_row);
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => SYSTEM_PALLETE[ppu.palette_table[0] as usize],
-                    1 => SYSTEM_PALLETE[palette[1] as usize],
-                    2 => SYSTEM_PALLETE[palette[2] as usize],
-                    3 => SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                frame.set_pixel(tile_column * 8 + x, tile_row * 8 + y, rgb)
-            }
+    let world_y = scroll_y % 480;
+    let (nametable_row, y) = (world_y / 240, world_y % 240);
+    let mut fetched = None;
+    let mut tile = [0; 16];
+    let mut palette = [0; 4];
+
+    for (x, opaque) in opaque.iter_mut().enumerate() {
+        let world_x = (scroll_x + x) % 512;
+        let nametable = nametable_row * 2 + world_x / 256;
+        let tile_column = world_x % 256 / 8;
+
+        // a new tile every 8 pixels, keep the fetched one until then
+        if fetched != Some((nametable, tile_column)) {
+            let start = ppu.mirror_vram_address(0x2000 + nametable as u16 * 0x400) as usize;
+            let names = &ppu.vram[start..start + 0x400];
+            tile = ppu.chr_tile(bank + names[y / 8 * 32 + tile_column] as u16 * 16);
+            palette = bg_pallette(ppu, &names[0x3c0..], tile_column, y / 8);
+            fetched = Some((nametable, tile_column));
         }
+
+        let bit = 7 - world_x % 8;
+        let value = (tile[y % 8] >> bit & 1) | (tile[y % 8 + 8] >> bit & 1) << 1;
+        let value = if clip_left && x < 8 { 0 } else { value };
+        let color = match value {
+            0 => ppu.palette_table[0],
+            1 => palette[1],
+            2 => palette[2],
+            3 => palette[3],
+            _ => panic!("can't be"),
+        };
+        *opaque = value != 0;
+        frame.set_palette_pixel(x, line, color)
     }
+}
+
+// the first OAM entry with an opaque pixel wins it, and only then its priority
+// bit decides against the background, so a sprite behind the background still
+// hides the sprites after it
+fn render_sprites(ppu: &PPU, frame: &mut Frame, line: usize, background_opaque: &[bool]) {
+    let clip_left = !ppu.force_render && !ppu.mask.leftmost_8pxl_sprite();
+    let height = ppu.control.sprite_size() as usize;
+    let mut sprite_opaque = [false; Frame::WIDTH];
 
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
+    for i in (0..ppu.oam_data.len()).step_by(4) {
+        let tile_y = ppu.oam_data[i] as usize;
+        if line < tile_y || line >= tile_y + height {
+            continue;
+        }
         let tile_idx = ppu.oam_data[i + 1] as u16;
         let tile_x = ppu.oam_data[i + 3] as usize;
-        let tile_y = ppu.oam_data[i] as usize;
+        let attributes = ppu.oam_data[i + 2];
+
+        let flip_vertical = attributes >> 7 & 1 == 1;
+        let flip_horizontal = attributes >> 6 & 1 == 1;
+        let behind_background = attributes >> 5 & 1 == 1;
+        let pallette_idx = attributes & 0b11;
+        let sprite_palette = sprite_palette(ppu, pallette_idx);
 
-        let flip_vertical = if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
-            true
+        // 8x16 sprites pick their pattern table with bit 0 of the tile byte
+        // and stack the even tile on top of the next one
+        let (bank, first_tile) = if height == 16 {
+            ((tile_idx & 1) * 0x1000, tile_idx & 0xfe)
         } else {
-            false
+            (ppu.control.sprt_pattern_address(), tile_idx)
         };
-        let flip_horizontal = if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
-            true
+        let row = if flip_vertical {
+            height - 1 - (line - tile_y)
         } else {
-            false
+            line - tile_y
         };
-        let pallette_idx = ppu.oam_data[i + 2] & 0b11;
-        let sprite_palette = sprite_palette(ppu, pallette_idx);
-        let bank: u16 = ppu.control.sprt_pattern_address();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            'ololo: for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue 'ololo, // skip coloring the pixel
-                    1 => SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("can't be"),
-                };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
-                }
+        let tile = ppu.chr_tile(bank + (first_tile + row as u16 / 8) * 16);
+
+        let mut upper = tile[row % 8];
+        let mut lower = tile[row % 8 + 8];
+        'ololo: for x in (0..=7).rev() {
+            let value = (1 & lower) << 1 | (1 & upper);
+            upper >>= 1;
+            lower >>= 1;
+            let color = match value {
+                0 => continue 'ololo, // skip coloring the pixel
+                1 => sprite_palette[1],
+                2 => sprite_palette[2],
+                3 => sprite_palette[3],
+                _ => panic!("can't be"),
+            };
+
+            let pixel_x = tile_x + if flip_horizontal { 7 - x } else { x };
+            // sprites hanging off the right edge don't wrap
+            if pixel_x >= Frame::WIDTH {
+                continue;
             }
+            if clip_left && pixel_x < 8 || sprite_opaque[pixel_x] {
+                continue;
+            }
+            sprite_opaque[pixel_x] = true;
+            if behind_background && background_opaque[pixel_x] {
+                continue;
+            }
+            frame.set_palette_pixel(pixel_x, line, color);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::cartridge::Mirroring;
+
+    fn striped_ppu() -> PPU {
+        // tiles 0 and 1 have their first row set to color 1 and 3
+        let mut chr = vec![0; 0x2000];
+        chr[0x00] = 0xff;
+        chr[0x10] = 0xff;
+        chr[0x18] = 0xff;
+        let mut ppu = PPU::new(chr, Mirroring::Horizontal);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu
+    }
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = (y * Frame::WIDTH + x) * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_tile_cache_decodes_only_dirty_tiles() {
+        let rom = crate::components::cartridge::test::chr_ram_rom();
+        let mut ppu = PPU::new_with_mapper(crate::components::mappers::create(rom).unwrap());
+        let mut cache = TileCache::new();
+        assert_eq!(cache.update(&mut ppu), 512);
+        assert_eq!(cache.update(&mut ppu), 0);
+
+        // first row of tile 5 to color 1, second to color 2
+        ppu.write_to_ppu_address(0x00);
+        ppu.write_to_ppu_address(0x50);
+        ppu.write_to_data(0xff);
+        ppu.write_to_ppu_address(0x00);
+        ppu.write_to_ppu_address(0x59);
+        ppu.write_to_data(0x80);
+        assert_eq!(cache.update(&mut ppu), 1);
+        assert_eq!(cache.tile(5)[0], [1; 8]);
+        assert_eq!(cache.tile(5)[1], [2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(cache.tile(4)[0], [0; 8]);
+    }
+
+    #[test]
+    fn test_texture_bytes_layout_per_format() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, (0x11, 0x22, 0x33));
+
+        let rgb = frame.texture_bytes(PixelFormat::Rgb24);
+        assert_eq!(rgb.len(), PixelFormat::Rgb24.pitch() * Frame::HIGHT);
+        assert_eq!(&rgb[3..6], &[0x11, 0x22, 0x33]);
+
+        let argb = frame.texture_bytes(PixelFormat::Argb8888);
+        assert_eq!(argb.len(), PixelFormat::Argb8888.pitch() * Frame::HIGHT);
+        let packed = u32::from_ne_bytes([argb[4], argb[5], argb[6], argb[7]]);
+        assert_eq!(packed, 0xff11_2233);
+    }
+
+    #[test]
+    fn test_render_disabled_shows_backdrop() {
+        let ppu = striped_ppu();
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let backdrop = SYSTEM_PALLETE[0x0f];
+        assert!(frame
+            .data
+            .chunks(3)
+            .all(|rgb| (rgb[0], rgb[1], rgb[2]) == backdrop));
+    }
+
+    #[test]
+    fn test_force_render_ignores_mask() {
+        let mut ppu = striped_ppu();
+        ppu.force_render = true;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        // skip the first tile, the empty OAM puts sprites there
+        assert_eq!(pixel(&frame, 8, 0), SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(&frame, 8, 1), SYSTEM_PALLETE[0x0f]);
+    }
+
+    #[test]
+    fn test_index_buffer_matches_rendered_colors() {
+        let mut ppu = striped_ppu();
+        ppu.mask.update(0b0000_1000);
+        ppu.palette_table[3] = 0x16;
+        // the second tile of the nametable is tile 1, drawn with color 3
+        ppu.vram[1] = 1;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let indices = frame.index_buffer();
+        assert_eq!(indices.len(), Frame::WIDTH * Frame::HIGHT);
+        assert_eq!(indices[Frame::WIDTH], 0x0f);
+        assert_eq!(indices[8], 0x16);
+        assert_eq!(indices[16], 0x30);
+        assert_eq!(pixel(&frame, 8, 0), SYSTEM_PALLETE[0x16]);
+    }
+
+    #[test]
+    fn test_region_indices_lists_colors_in_box() {
+        let mut ppu = striped_ppu();
+        ppu.mask.update(0b0000_1010);
+        ppu.palette_table[3] = 0x16;
+        ppu.vram[1] = 1;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        // straddles the first rows of tiles 0 and 1
+        assert_eq!(frame.region_indices(4, 0, 8, 2), vec![0x0f, 0x16, 0x30]);
+        assert_eq!(frame.region_indices(8, 1, 8, 7), vec![0x0f]);
+        assert_eq!(frame.region_indices(250, 235, 20, 20), vec![0x0f]);
+        assert!(frame.region_indices(0, 0, 0, 8).is_empty());
+    }
+
+    #[test]
+    fn test_render_tile_decodes_bit_planes() {
+        let mut chr = vec![0; 32];
+        // tile 1, row 0: plane 0 sets the left half, plane 1 every other pixel
+        chr[16] = 0b1111_0000;
+        chr[24] = 0b1010_1010;
+        // row 7 only uses plane 1
+        chr[31] = 0b0000_0001;
+
+        let pixels = render_tile(&chr, 1, [0x00, 0x01, 0x02, 0x03]);
+
+        let row: Vec<(u8, u8, u8)> = [3, 1, 3, 1, 2, 0, 2, 0]
+            .iter()
+            .map(|&i| SYSTEM_PALLETE[i])
+            .collect();
+        assert_eq!(pixels[0].to_vec(), row);
+        assert_eq!(pixels[7][7], SYSTEM_PALLETE[2]);
+        assert_eq!(pixels[7][0], SYSTEM_PALLETE[0]);
+        assert_eq!(pixels[3], [SYSTEM_PALLETE[0]; 8]);
+    }
+
+    #[test]
+    fn test_emphasis_dims_the_other_channels() {
+        let mut ppu = striped_ppu();
+        // show background with its leftmost pixels, emphasise red
+        ppu.mask.update(0b0010_1010);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let (r, g, b) = SYSTEM_PALLETE[0x30];
+        let dim = |value: u8| (value as f32 * EMPHASIS_ATTENUATION) as u8;
+        assert_eq!(pixel(&frame, 0, 0), (r, dim(g), dim(b)));
+        assert_eq!(frame.index_buffer()[0], 0x30);
+    }
+
+    #[test]
+    fn test_background_uses_selected_nametable_and_attributes() {
+        let mut ppu = striped_ppu();
+        // show background and its leftmost 8 pixels, nametable $2800
+        ppu.mask.update(0b0000_1010);
+        ppu.write_to_control(0b0000_0010);
+        ppu.palette_table[5] = 0x21;
+        ppu.palette_table[7] = 0x16;
+        // horizontal mirroring puts $2800 at the second KB of VRAM
+        ppu.vram[0x400] = 1;
+        ppu.vram[0x400 + 0x3c0] = 0b01;
+        // the first nametable is ignored
+        ppu.vram[1] = 1;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.index_buffer()[0], 0x16);
+        assert_eq!(frame.index_buffer()[8], 0x21);
+        assert_eq!(frame.index_buffer()[Frame::WIDTH], 0x0f);
+        assert_eq!(pixel(&frame, 0, 0), SYSTEM_PALLETE[0x16]);
+    }
+
+    #[test]
+    fn test_background_leftmost_column_is_clipped() {
+        let mut ppu = striped_ppu();
+        ppu.mask.update(0b0000_1000);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.region_indices(0, 0, 8, 8), vec![0x0f]);
+        assert_eq!(frame.index_buffer()[8], 0x30);
+    }
+
+    // tile 1 has column 0 set to color 1, tile 2 is solid color 1
+    fn sprite_ppu() -> PPU {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0b1000_0000; 8]);
+        chr[0x20..0x28].copy_from_slice(&[0xff; 8]);
+        let mut ppu = PPU::new(chr, Mirroring::Horizontal);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[1] = 0x30;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.palette_table[0x15] = 0x2a;
+        // hide every sprite below the screen
+        ppu.oam_data = [0xff; 256];
+        ppu
+    }
+
+    fn place_sprite(ppu: &mut PPU, index: usize, x: u8, y: u8, tile: u8, attributes: u8) {
+        ppu.oam_data[index * 4..index * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+    }
+
+    #[test]
+    fn test_sprite_flips_and_palette() {
+        let mut ppu = sprite_ppu();
+        // show sprites and their leftmost pixels
+        ppu.mask.update(0b0001_0100);
+        place_sprite(&mut ppu, 0, 16, 32, 1, 0b0000_0000);
+        // horizontal flip, palette 1
+        place_sprite(&mut ppu, 1, 48, 32, 1, 0b0100_0001);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 16], 0x16);
+        assert_eq!(frame.index_buffer()[39 * Frame::WIDTH + 16], 0x16);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 17], 0x0f);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 48], 0x0f);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 55], 0x2a);
+    }
+
+    #[test]
+    fn test_sprite_zero_is_drawn_on_top() {
+        let mut ppu = sprite_ppu();
+        ppu.mask.update(0b0001_0100);
+        place_sprite(&mut ppu, 0, 16, 32, 1, 0b0000_0000);
+        place_sprite(&mut ppu, 1, 16, 32, 1, 0b0000_0001);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 16], 0x16);
+    }
+
+    #[test]
+    fn test_sprite_behind_background_shows_through_color_0() {
+        let mut ppu = sprite_ppu();
+        // background and sprites, leftmost pixels included
+        ppu.mask.update(0b0001_1110);
+        // the background tile at column 2, row 4 is opaque
+        ppu.vram[4 * 32 + 2] = 2;
+        place_sprite(&mut ppu, 0, 16, 32, 1, 0b0010_0000);
+        place_sprite(&mut ppu, 1, 24, 32, 1, 0b0010_0000);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 16], 0x30);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 24], 0x16);
+    }
+
+    #[test]
+    fn test_sprite_behind_background_hides_later_sprites() {
+        let mut ppu = sprite_ppu();
+        ppu.mask.update(0b0001_1110);
+        ppu.vram[4 * 32 + 2] = 2;
+        // sprite 0 is behind the opaque tile, sprite 1 in front of it
+        place_sprite(&mut ppu, 0, 16, 32, 1, 0b0010_0000);
+        place_sprite(&mut ppu, 1, 16, 32, 1, 0b0000_0001);
+        // over the transparent background sprite 0 still shows
+        place_sprite(&mut ppu, 2, 32, 32, 1, 0b0010_0000);
+        place_sprite(&mut ppu, 3, 32, 32, 1, 0b0000_0001);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 16], 0x30);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 32], 0x16);
+    }
+
+    #[test]
+    fn test_sprites_clip_on_the_edges() {
+        let mut ppu = sprite_ppu();
+        place_sprite(&mut ppu, 0, 4, 32, 1, 0b0000_0000);
+        // flipped, its pixel lands on x = 259
+        place_sprite(&mut ppu, 1, 252, 40, 1, 0b0100_0000);
+
+        ppu.mask.update(0b0001_0000);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 4], 0x0f);
+
+        ppu.mask.update(0b0001_0100);
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.index_buffer()[32 * Frame::WIDTH + 4], 0x16);
+        // nothing wraps around into the next row
+        assert_eq!(frame.region_indices(0, 40, 8, 8), vec![0x0f]);
+        assert_eq!(frame.region_indices(248, 40, 8, 8), vec![0x0f]);
+    }
+
+    #[test]
+    fn test_tall_sprites_stack_two_tiles() {
+        let mut chr = vec![0; 0x2000];
+        // in the second pattern table, tile 2 sets column 0, tile 3 column 7
+        chr[0x1020..0x1028].copy_from_slice(&[0b1000_0000; 8]);
+        chr[0x1030..0x1038].copy_from_slice(&[0b0000_0001; 8]);
+        // the same tiles in the first table must not be used
+        chr[0x20..0x28].copy_from_slice(&[0xff; 8]);
+        let mut ppu = PPU::new(chr, Mirroring::Horizontal);
+        ppu.palette_table[0] = 0x0f;
+        ppu.palette_table[0x11] = 0x16;
+        ppu.oam_data = [0xff; 256];
+        ppu.write_to_control(0b0010_0000);
+        ppu.mask.update(0b0001_0100);
+        // tile byte 3: pattern table $1000, tiles 2 and 3
+        place_sprite(&mut ppu, 0, 16, 32, 3, 0b0000_0000);
+        place_sprite(&mut ppu, 1, 64, 32, 3, 0b1000_0000);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let at = |x: usize, y: usize| frame.index_buffer()[y * Frame::WIDTH + x];
+
+        assert_eq!(frame.region_indices(16, 32, 8, 8), vec![0x0f, 0x16]);
+        assert_eq!((at(16, 32), at(16, 39), at(23, 39)), (0x16, 0x16, 0x0f));
+        assert_eq!((at(16, 40), at(23, 40), at(23, 47)), (0x0f, 0x16, 0x16));
+
+        // flipped vertically, the bottom tile is drawn on top
+        assert_eq!((at(71, 32), at(71, 39), at(64, 39)), (0x16, 0x16, 0x0f));
+        assert_eq!((at(64, 40), at(64, 47), at(71, 40)), (0x16, 0x16, 0x0f));
+    }
+}
@@ -0,0 +1,259 @@
+//! Recorded sessions for full-game regression tests: the ROM hash, the
+//! player 1 buttons of every frame and checksums of some of the frames.
+//!
+//! ```text
+//! rom 5f2e0c0a8b1d3c77
+//! 0 00
+//! 1 01 checksum 9a3b4c5d6e7f8091
+//! ```
+//!
+//! Lines are `<frame> <buttons>` with an optional checksum, frames count up
+//! from 0 without gaps. Buttons are the `JoypadButton` bits held during the
+//! frame, the checksum covers the RGB bytes that frame rendered to.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::components::bus::BUS;
+use crate::components::cartridge::Rom;
+use crate::components::cpu::CPU;
+use crate::components::joypads::{Joypad, JoypadButton};
+use crate::components::ppu::PPU;
+use crate::render::{self, Frame};
+
+/// 64 bit FNV-1a, enough to tell frames apart without another dependency.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Replay {
+    pub rom_hash: u64,
+    pub inputs: Vec<u8>,
+    // (frame, checksum), in frame order
+    pub checksums: Vec<(usize, u64)>,
+}
+
+fn parse_hex<T>(
+    field: Option<&str>,
+    line: usize,
+    parse: fn(&str, u32) -> Result<T, std::num::ParseIntError>,
+) -> Result<T, String> {
+    let field = field.ok_or(format!("Line {}: missing field", line))?;
+    parse(field, 16).map_err(|_| format!("Line {}: '{}' is not hex", line, field))
+}
+
+impl Replay {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut rom_hash = None;
+        let mut inputs = vec![];
+        let mut checksums = vec![];
+
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let first = fields.next().unwrap_or_default();
+            if first == "rom" {
+                rom_hash = Some(parse_hex(fields.next(), number, u64::from_str_radix)?);
+                continue;
+            }
+
+            let frame: usize = first
+                .parse()
+                .map_err(|_| format!("Line {}: '{}' is not a frame number", number, first))?;
+            if frame != inputs.len() {
+                return Err(format!(
+                    "Line {}: expected frame {}, found {}",
+                    number,
+                    inputs.len(),
+                    frame
+                ));
+            }
+            inputs.push(parse_hex(fields.next(), number, u8::from_str_radix)?);
+
+            match fields.next() {
+                Some("checksum") => checksums.push((
+                    frame,
+                    parse_hex(fields.next(), number, u64::from_str_radix)?,
+                )),
+                Some(other) => return Err(format!("Line {}: unexpected '{}'", number, other)),
+                None => {}
+            }
+        }
+
+        Ok(Replay {
+            rom_hash: rom_hash.ok_or("Replay has no rom line")?,
+            inputs,
+            checksums,
+        })
+    }
+
+    /// Plays `inputs` on `image` headlessly and keeps the checksum of every
+    /// `interval`-th frame.
+    pub fn record(image: &Vec<u8>, inputs: Vec<u8>, interval: usize) -> Result<Self, String> {
+        let interval = interval.max(1);
+        let frames = frame_checksums(image, &inputs)?;
+        Ok(Replay {
+            rom_hash: fnv1a(image),
+            checksums: frames
+                .into_iter()
+                .enumerate()
+                .filter(|(frame, _)| (frame + 1) % interval == 0)
+                .collect(),
+            inputs,
+        })
+    }
+
+    /// Replays the inputs on `image`, failing on a different ROM or at the
+    /// first frame whose checksum doesn't match the recording.
+    pub fn verify(&self, image: &Vec<u8>) -> Result<(), String> {
+        let hash = fnv1a(image);
+        if hash != self.rom_hash {
+            return Err(format!(
+                "ROM hash is {:016x}, the replay was recorded on {:016x}",
+                hash, self.rom_hash
+            ));
+        }
+
+        let frames = frame_checksums(image, &self.inputs)?;
+        for (frame, expected) in self.checksums.iter() {
+            match frames.get(*frame) {
+                Some(actual) if actual == expected => {}
+                Some(actual) => {
+                    return Err(format!(
+                        "Frame {} diverged: checksum {:016x}, recorded {:016x}",
+                        frame, actual, expected
+                    ))
+                }
+                None => return Err(format!("Frame {} was never reached", frame)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        let mut checksums = self.checksums.iter().peekable();
+        for (frame, buttons) in self.inputs.iter().enumerate() {
+            write!(f, "{} {:02x}", frame, buttons)?;
+            if let Some((_, checksum)) = checksums.next_if(|(at, _)| *at == frame) {
+                write!(f, " checksum {:016x}", checksum)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+fn hold(joypad: &mut Joypad, buttons: u8) {
+    joypad.set_button_pressed_status(JoypadButton::all(), false);
+    joypad.set_button_pressed_status(JoypadButton::from_bits_truncate(buttons), true);
+}
+
+// checksum of every frame, as many as there are inputs unless the CPU stops
+fn frame_checksums(image: &Vec<u8>, inputs: &[u8]) -> Result<Vec<u64>, String> {
+    let rom = Rom::new(image)?;
+    let checksums = Rc::new(RefCell::new(vec![]));
+    let frame_checksums = checksums.clone();
+    let next_inputs = inputs.to_vec();
+    let mut frame = Frame::new();
+
+    let bus = BUS::new(
+        rom,
+        move |ppu: &PPU, joypad1: &mut Joypad, _joypad2: &mut Joypad| {
+            render::render(ppu, &mut frame);
+            let mut checksums = frame_checksums.borrow_mut();
+            checksums.push(fnv1a(&frame.data));
+            if let Some(buttons) = next_inputs.get(checksums.len()) {
+                hold(joypad1, *buttons);
+            }
+        },
+    )?;
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    if let Some(buttons) = inputs.first() {
+        hold(cpu.bus.joypad1_mut(), *buttons);
+    }
+
+    let frames = inputs.len();
+    let counter = checksums.clone();
+    cpu.run_with_callback(|cpu| {
+        if counter.borrow().len() >= frames {
+            cpu.stop();
+        }
+    })
+    .map_err(|err| err.to_string())?;
+
+    let checksums = checksums.borrow().clone();
+    Ok(checksums)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../fixtures/pacman_input.replay");
+
+    fn pacman() -> Vec<u8> {
+        std::fs::read("games/pacman.nes").unwrap()
+    }
+
+    // start is pressed on the title screen, then pac-man goes left and up
+    fn pacman_inputs() -> Vec<u8> {
+        let mut inputs = vec![0; 240];
+        inputs[60..70].fill(JoypadButton::START.bits());
+        inputs[120..180].fill(JoypadButton::LEFT.bits());
+        inputs[180..240].fill(JoypadButton::UP.bits());
+        inputs
+    }
+
+    #[test]
+    fn test_recorded_session_replays() {
+        let replay = Replay::parse(FIXTURE).unwrap();
+        assert_eq!(replay.inputs.len(), 240);
+        assert_eq!(replay.verify(&pacman()), Ok(()));
+    }
+
+    #[test]
+    fn test_record_round_trips_through_text() {
+        let replay = Replay::record(&pacman(), pacman_inputs(), 20).unwrap();
+
+        assert_eq!(Replay::parse(&replay.to_string()), Ok(replay.clone()));
+        assert_eq!(replay.to_string(), FIXTURE);
+    }
+
+    #[test]
+    fn test_divergence_reports_first_frame() {
+        let mut replay = Replay::parse(FIXTURE).unwrap();
+        // start is never pressed, so the game stays on the title screen
+        replay.inputs.iter_mut().for_each(|buttons| *buttons = 0);
+
+        let error = replay.verify(&pacman()).unwrap_err();
+        assert!(error.starts_with("Frame 79 diverged"), "{}", error);
+    }
+
+    #[test]
+    fn test_other_rom_is_rejected() {
+        let replay = Replay::parse(FIXTURE).unwrap();
+        let mut image = pacman();
+        image[0x10] ^= 0xff;
+        assert!(replay.verify(&image).unwrap_err().starts_with("ROM hash"));
+    }
+
+    #[test]
+    fn test_frames_must_be_consecutive() {
+        let error = Replay::parse("rom 00\n0 00\n2 00\n").unwrap_err();
+        assert_eq!(error, "Line 3: expected frame 1, found 2");
+    }
+}
@@ -0,0 +1,297 @@
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::audio::{AudioConfig, AudioSink};
+use crate::components::bus::BUS;
+use crate::components::cartridge::Rom;
+use crate::components::cpu::CPU;
+use crate::components::joypads::{Joypad, JoypadButton};
+use crate::components::ppu::PPU;
+use crate::render::{self, Frame};
+
+// two frames in flight plus the one being presented make the triple buffer
+const FRAMES_IN_FLIGHT: usize = 2;
+// samples sent at once, and how many batches may wait before new ones are
+// dropped, about 190ms at 44.1kHz
+const AUDIO_BATCH: usize = 1024;
+const AUDIO_BATCHES_IN_FLIGHT: usize = 8;
+
+/// What the emulation thread does besides producing frames.
+#[derive(Debug, Default, Clone)]
+pub struct SessionOptions {
+    /// The battery save is loaded from here and written back when the
+    /// emulation ends.
+    pub sram_path: Option<PathBuf>,
+    /// Resample the APU output to this and send it to `take_audio`.
+    pub audio: Option<AudioConfig>,
+}
+
+/// Sent from the presentation thread to the emulation thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Button {
+        player: usize,
+        button: JoypadButton,
+        pressed: bool,
+    },
+    Quit,
+}
+
+/// The machine running on its own thread. Completed frames come out of
+/// `recv_frame`, the emulation blocks while both in flight frames are unread
+/// so it is paced by whoever presents them.
+pub struct ThreadedSession {
+    frames: Receiver<Frame>,
+    audio: Receiver<Vec<f32>>,
+    input: Sender<InputEvent>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ThreadedSession {
+    pub fn spawn(rom: Rom) -> Self {
+        Self::spawn_with_options(rom, SessionOptions::default())
+    }
+
+    pub fn spawn_with_options(rom: Rom, options: SessionOptions) -> Self {
+        let (frame_sender, frames) = mpsc::sync_channel(FRAMES_IN_FLIGHT);
+        let (audio_sender, audio) = mpsc::sync_channel(AUDIO_BATCHES_IN_FLIGHT);
+        let (input, input_receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            emulate(rom, options, frame_sender, audio_sender, input_receiver)
+        });
+
+        ThreadedSession {
+            frames,
+            audio,
+            input,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send_input(&self, event: InputEvent) {
+        // the emulation thread is gone once the program ended, nothing to do
+        let _ = self.input.send(event);
+    }
+
+    /// Waits up to `timeout` for the next frame, None when there is none yet
+    /// or the emulation has stopped.
+    pub fn recv_frame(&self, timeout: Duration) -> Option<Frame> {
+        match self.frames.recv_timeout(timeout) {
+            Ok(frame) => Some(frame),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+
+    /// Every sample batch sent so far, in order. Empty without
+    /// `SessionOptions::audio`.
+    pub fn take_audio(&self) -> Vec<f32> {
+        self.audio.try_iter().flatten().collect()
+    }
+
+    /// The newest frame already produced, older ones are dropped.
+    pub fn latest_frame(&self) -> Option<Frame> {
+        self.frames.try_iter().last()
+    }
+
+    /// True once the CPU stopped, on BRK, a jam or a quit request.
+    pub fn is_finished(&self) -> bool {
+        self.thread
+            .as_ref()
+            .is_none_or(|thread| thread.is_finished())
+    }
+}
+
+impl Drop for ThreadedSession {
+    fn drop(&mut self) {
+        self.send_input(InputEvent::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// true when the event asks the emulation to end
+fn apply_input(event: InputEvent, joypad1: &mut Joypad, joypad2: &mut Joypad) -> bool {
+    match event {
+        InputEvent::Button {
+            player,
+            button,
+            pressed,
+        } => {
+            let joypad = if player == 0 { joypad1 } else { joypad2 };
+            joypad.set_button_pressed_status(button, pressed);
+            false
+        }
+        InputEvent::Quit => true,
+    }
+}
+
+// batches the bus samples for the presentation thread, a batch it has no room
+// for is dropped rather than stalling the emulation
+struct ChannelSink {
+    batch: Vec<f32>,
+    sender: SyncSender<Vec<f32>>,
+}
+
+impl AudioSink for ChannelSink {
+    fn push_sample(&mut self, sample: f32) {
+        self.batch.push(sample);
+        if self.batch.len() >= AUDIO_BATCH {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(AUDIO_BATCH));
+            let _ = self.sender.try_send(batch);
+        }
+    }
+}
+
+fn emulate(
+    rom: Rom,
+    options: SessionOptions,
+    frames: SyncSender<Frame>,
+    audio: SyncSender<Vec<f32>>,
+    input: Receiver<InputEvent>,
+) {
+    let sram_path = options.sram_path;
+    let quit = Rc::new(Cell::new(false));
+    let frame_quit = quit.clone();
+    let mut frame = Frame::new();
+
+    let bus = BUS::new(
+        rom,
+        move |ppu: &PPU, joypad1: &mut Joypad, joypad2: &mut Joypad| {
+            joypad1.next_frame();
+            joypad2.next_frame();
+
+            for event in input.try_iter() {
+                if apply_input(event, joypad1, joypad2) {
+                    frame_quit.set(true);
+                }
+            }
+            if frame_quit.get() {
+                return;
+            }
+
+            render::render(ppu, &mut frame);
+            let mut pending = frame.clone();
+            // keep taking input while the presenter is behind
+            loop {
+                match frames.try_send(pending) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(returned)) => {
+                        for event in input.try_iter() {
+                            if apply_input(event, joypad1, joypad2) {
+                                frame_quit.set(true);
+                            }
+                        }
+                        if frame_quit.get() {
+                            break;
+                        }
+                        pending = returned;
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    Err(TrySendError::Disconnected(_)) => {
+                        frame_quit.set(true);
+                        break;
+                    }
+                }
+            }
+        },
+    );
+    let mut bus = match bus {
+        Ok(bus) => bus,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    if let Some(path) = &sram_path {
+        if let Err(err) = bus.load_sram(path) {
+            println!("Could not load {}: {}", path.display(), err);
+        }
+    }
+    if let Some(config) = options.audio {
+        let sink = ChannelSink {
+            batch: Vec::with_capacity(AUDIO_BATCH),
+            sender: audio,
+        };
+        bus.set_audio_sink(Box::new(sink), config);
+    }
+
+    let mut cpu = CPU::new(bus);
+    cpu.reset();
+    let result = cpu.run_with_callback(|cpu| {
+        if quit.get() {
+            cpu.stop();
+        }
+    });
+    if let Err(err) = result {
+        println!("{}", err);
+    }
+
+    if let Some(path) = &sram_path {
+        if let Err(err) = cpu.bus.save_sram(path) {
+            println!("Could not save {}: {}", path.display(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::headless::test::spinning_rom;
+    use std::time::Instant;
+
+    #[test]
+    fn test_frames_arrive_on_the_channel() {
+        let session = ThreadedSession::spawn(spinning_rom());
+
+        let start = Instant::now();
+        let mut received = 0;
+        while start.elapsed() < Duration::from_millis(500) && received < 3 {
+            if session.recv_frame(Duration::from_millis(100)).is_some() {
+                received += 1;
+            }
+        }
+        assert_eq!(received, 3);
+        assert!(!session.is_finished());
+    }
+
+    #[test]
+    fn test_audio_arrives_on_the_channel() {
+        let options = SessionOptions {
+            sram_path: None,
+            audio: Some(AudioConfig::default()),
+        };
+        let session = ThreadedSession::spawn_with_options(spinning_rom(), options);
+        for _ in 0..3 {
+            session.recv_frame(Duration::from_secs(1)).unwrap();
+        }
+
+        // a frame is about 735 samples at 44.1kHz, whole batches only
+        let samples = session.take_audio();
+        assert!(samples.len() >= AUDIO_BATCH, "{} samples", samples.len());
+        assert_eq!(samples.len() % AUDIO_BATCH, 0);
+
+        let silent = ThreadedSession::spawn(spinning_rom());
+        silent.recv_frame(Duration::from_secs(1)).unwrap();
+        assert!(silent.take_audio().is_empty());
+    }
+
+    #[test]
+    fn test_quit_stops_the_emulation_thread() {
+        let session = ThreadedSession::spawn(spinning_rom());
+        session.recv_frame(Duration::from_secs(1)).unwrap();
+        session.send_input(InputEvent::Quit);
+
+        // the frames already in flight still come out, then the channel closes
+        while session.recv_frame(Duration::from_secs(1)).is_some() {}
+        let start = Instant::now();
+        while !session.is_finished() && start.elapsed() < Duration::from_secs(1) {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert!(session.is_finished());
+    }
+}
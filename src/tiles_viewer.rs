@@ -1,94 +1,29 @@
-pub mod bus;
-pub mod cartridge;
-pub mod cpu;
-pub mod opcodes;
-pub mod ppu;
-pub mod render;
-pub mod trace;
-
-use bus::Bus;
-use cartridge::Rom;
-use cpu::Mem;
-use cpu::CPU;
-use render::frame::Frame;
-use render::palette;
-use trace::trace;
-// use rand::Rng;
+use nes::components::cartridge::Rom;
+use nes::render::{self, Frame};
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
-use sdl2::EventPump;
-// use std::time::Duration;
-
-#[macro_use]
-extern crate lazy_static;
 
-#[macro_use]
-extern crate bitflags;
+const VIEWER_PALETTE: [u8; 4] = [0x01, 0x23, 0x27, 0x30];
 
-fn show_tile(chr_rom: &Vec<u8>, bank: usize, tile_n: usize) -> Frame {
-    assert!(bank <= 1);
-
-    let mut frame = Frame::new();
-    let bank = (bank * 0x1000) as usize;
-
-    let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
-
-    for y in 0..=7 {
-        let mut upper = tile[y];
-        let mut lower = tile[y + 8];
-
-        for x in (0..=7).rev() {
-            let value = (1 & upper) << 1 | (1 & lower);
-            upper = upper >> 1;
-            lower = lower >> 1;
-            let rgb = match value {
-                0 => palette::SYSTEM_PALLETE[0x01],
-                1 => palette::SYSTEM_PALLETE[0x23],
-                2 => palette::SYSTEM_PALLETE[0x27],
-                3 => palette::SYSTEM_PALLETE[0x30],
-                _ => panic!("can't be"),
-            };
-            frame.set_pixel(x, y, rgb)
-        }
-    }
-
-    frame
-}
-
-fn show_tile_bank(chr_rom: &Vec<u8>, bank: usize) -> Frame {
+fn show_tile_bank(chr_rom: &[u8], bank: usize) -> Frame {
     assert!(bank <= 1);
 
     let mut frame = Frame::new();
     let mut tile_y = 0;
     let mut tile_x = 0;
-    let bank = (bank * 0x1000) as usize;
 
     for tile_n in 0..255 {
         if tile_n != 0 && tile_n % 20 == 0 {
             tile_y += 10;
             tile_x = 0;
         }
-        let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
+        let pixels = render::render_tile(chr_rom, bank * 256 + tile_n, VIEWER_PALETTE);
 
-            for x in (0..=7).rev() {
-                let value = (1 & upper) << 1 | (1 & lower);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[0x01],
-                    1 => palette::SYSTEM_PALLETE[0x23],
-                    2 => palette::SYSTEM_PALLETE[0x27],
-                    3 => palette::SYSTEM_PALLETE[0x30],
-                    _ => panic!("can't be"),
-                };
-                frame.set_pixel(tile_x + x, tile_y + y, rgb)
+        for (y, row) in pixels.iter().enumerate() {
+            for (x, rgb) in row.iter().enumerate() {
+                frame.set_pixel(tile_x + x, tile_y + y, *rgb)
             }
         }
 
@@ -117,7 +52,7 @@ fn main() {
         .unwrap();
 
     //load the game
-    let bytes: Vec<u8> = std::fs::read("pacman.nes").unwrap();
+    let bytes: Vec<u8> = std::fs::read("games/pacman.nes").unwrap();
     let rom = Rom::new(&bytes).unwrap();
 
     let right_bank = show_tile_bank(&rom.chr_rom, 1);
@@ -0,0 +1,30 @@
+//! Clock rates of the NTSC (2C02/2A03) and PAL (2C07/2A07) consoles. The CPU
+//! and PPU clocks are both divided down from the master crystal.
+
+pub const NTSC_MASTER_CLOCK: u32 = 21_477_272;
+pub const PAL_MASTER_CLOCK: u32 = 26_601_712;
+
+pub const NTSC_CPU_CLOCK: u32 = 1_789_773;
+pub const PAL_CPU_CLOCK: u32 = 1_662_607;
+
+// 3 dots per CPU cycle on NTSC, 3.2 on PAL
+pub const NTSC_PPU_CLOCK: u32 = 5_369_318;
+pub const PAL_PPU_CLOCK: u32 = 5_320_342;
+
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+pub const PAL_FRAME_RATE: f64 = 50.007;
+
+pub const DOTS_PER_SCANLINE: usize = 341;
+pub const NTSC_SCANLINES: u16 = 262;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ntsc_cycles_per_frame() {
+        let cycles = NTSC_CPU_CLOCK as f64 / NTSC_FRAME_RATE;
+        // 29780.5, every other frame is a cycle shorter
+        assert_eq!(cycles as u32, 29780);
+    }
+}
@@ -1,12 +1,128 @@
 use crate::components::assembly;
 use crate::components::cpu::AddressingMode;
 use crate::components::cpu::CPU;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+
+/// Decides which instructions get logged, so full runs can be traced
+/// without producing gigantic logs. Every predicate that is set must match.
+#[derive(Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<u16>>,
+    pub mnemonics: Option<HashSet<&'static str>>,
+    pub touched_address: Option<u16>,
+}
+
+impl TraceFilter {
+    pub fn new() -> Self {
+        TraceFilter {
+            pc_range: None,
+            mnemonics: None,
+            touched_address: None,
+        }
+    }
+
+    pub fn pc_range(mut self, range: RangeInclusive<u16>) -> Self {
+        self.pc_range = Some(range);
+        self
+    }
+
+    // mnemonics are matched without the "*" prefix of unofficial opcodes
+    pub fn mnemonics(mut self, mnemonics: &[&'static str]) -> Self {
+        self.mnemonics = Some(mnemonics.iter().copied().collect());
+        self
+    }
+
+    pub fn touched_address(mut self, address: u16) -> Self {
+        self.touched_address = Some(address);
+        self
+    }
+
+    pub fn matches(&self, cpu: &mut CPU) -> bool {
+        let opscodes: &HashMap<u8, &'static assembly::OpCode> = &assembly::OPCODES_MAP;
+
+        if let Some(range) = &self.pc_range {
+            if !range.contains(&cpu.register_pc) {
+                return false;
+            }
+        }
+
+        let code = cpu.peek(cpu.register_pc);
+        let ops = match opscodes.get(&code) {
+            Some(ops) => ops,
+            None => return false,
+        };
+
+        if let Some(mnemonics) = &self.mnemonics {
+            if !mnemonics.contains(ops.mnemonic.trim_start_matches('*')) {
+                return false;
+            }
+        }
+
+        if let Some(touched) = self.touched_address {
+            let address = match ops.mode {
+                AddressingMode::Immediate | AddressingMode::NoneAddressing => None,
+                _ => Some(cpu.peek_absolute_address(&ops.mode, cpu.register_pc + 1)),
+            };
+
+            if address != Some(touched) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Keeps the last `capacity` traced instructions for post-mortem dumps.
+pub struct TraceHistory {
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl TraceHistory {
+    pub fn new(capacity: usize) -> Self {
+        TraceHistory {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    // oldest first
+    pub fn dump_history(&self) -> Vec<String> {
+        self.lines.iter().cloned().collect()
+    }
+}
+
+pub fn trace_filtered(cpu: &mut CPU, filter: &TraceFilter) -> Option<String> {
+    if filter.matches(cpu) {
+        Some(trace(cpu))
+    } else {
+        None
+    }
+}
+
+// tracing only peeks, so read watches and registers see the CPU's reads alone
+fn peek_u16(cpu: &CPU, address: u16) -> u16 {
+    let low = cpu.peek(address) as u16;
+    let high = cpu.peek(address.wrapping_add(1)) as u16;
+    (high << 8) | low
+}
 
 pub fn trace(cpu: &mut CPU) -> String {
     let ref opscodes: HashMap<u8, &'static assembly::OpCode> = *assembly::OPCODES_MAP;
 
-    let code = cpu.memory_read(cpu.register_pc);
+    let code = cpu.peek(cpu.register_pc);
     let ops = opscodes.get(&code).unwrap();
 
     let begin = cpu.register_pc;
@@ -16,8 +132,8 @@ pub fn trace(cpu: &mut CPU) -> String {
     let (memory_address, stored_value) = match ops.mode {
         AddressingMode::Immediate | AddressingMode::NoneAddressing => (0, 0),
         _ => {
-            let (address, _) = cpu.get_absolute_address(&ops.mode, begin + 1);
-            (address, cpu.memory_read(address))
+            let address = cpu.peek_absolute_address(&ops.mode, begin + 1);
+            (address, cpu.peek(address))
         }
     };
 
@@ -27,8 +143,8 @@ pub fn trace(cpu: &mut CPU) -> String {
             _ => String::from(""),
         },
         2 => {
-            let address: u8 = cpu.memory_read(begin + 1);
-            // let value = cpu.memory_read(address));
+            let address: u8 = cpu.peek(begin + 1);
+            // let value = cpu.peek(address));
             hex_dump.push(address);
 
             match ops.mode {
@@ -72,26 +188,26 @@ pub fn trace(cpu: &mut CPU) -> String {
             }
         }
         3 => {
-            let address_lo = cpu.memory_read(begin + 1);
-            let address_hi = cpu.memory_read(begin + 2);
+            let address_lo = cpu.peek(begin + 1);
+            let address_hi = cpu.peek(begin + 2);
             hex_dump.push(address_lo);
             hex_dump.push(address_hi);
 
-            let address = cpu.memory_read_u16(begin + 1);
+            let address = peek_u16(cpu, begin + 1);
 
             match ops.mode {
                 AddressingMode::NoneAddressing => {
                     if ops.code == 0x6c {
                         //jmp indirect
                         let jmp_address = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.memory_read(address);
-                            let hi = cpu.memory_read(address & 0xFF00);
+                            let lo = cpu.peek(address);
+                            let hi = cpu.peek(address & 0xFF00);
                             (hi as u16) << 8 | (lo as u16)
                         } else {
-                            cpu.memory_read_u16(address)
+                            peek_u16(cpu, address)
                         };
 
-                        // let jmp_address = cpu.memory_read_u16(address);
+                        // let jmp_address = peek_u16(cpu, address);
                         format!("(${:04x}) = {:04x}", address, jmp_address)
                     } else {
                         format!("${:04x}", address)
@@ -133,32 +249,166 @@ pub fn trace(cpu: &mut CPU) -> String {
     .to_ascii_uppercase()
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceField {
+    ProgramCounter,
+    OpcodeBytes,
+    // mnemonic and operand
+    Instruction,
+    Registers,
+    Cycles,
+    // the reference log ends before our trace does
+    Missing,
+}
+
+/// First line where our trace and a reference log disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceMismatch {
+    pub line: usize,
+    pub field: TraceField,
+    pub ours: String,
+    pub reference: String,
+}
+
+impl std::fmt::Display for TraceMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "line {}: {:?} differs", self.line, self.field)?;
+        writeln!(f, "  ours:      {}", self.ours)?;
+        write!(f, "  reference: {}", self.reference)
+    }
+}
+
+// a trace line cut into the fields compare_traces reports on
+struct TraceFields<'a> {
+    pc: &'a str,
+    bytes: Vec<&'a str>,
+    instruction: Vec<&'a str>,
+    registers: Vec<&'a str>,
+    cycles: Vec<&'a str>,
+}
+
+fn split_trace_line(line: &str) -> TraceFields<'_> {
+    let mut tokens = line.split_whitespace();
+    let pc = tokens.next().unwrap_or_default();
+    let mut fields = TraceFields {
+        pc,
+        bytes: vec![],
+        instruction: vec![],
+        registers: vec![],
+        cycles: vec![],
+    };
+
+    for token in tokens {
+        if token.starts_with("PPU:") || token.starts_with("CYC:") {
+            fields.cycles.push(token);
+        } else if token.contains(':') || !fields.registers.is_empty() {
+            // the nestest PPU column has a space after the comma
+            if fields.cycles.is_empty() {
+                fields.registers.push(token);
+            } else {
+                fields.cycles.push(token);
+            }
+        } else if fields.instruction.is_empty()
+            && token.len() == 2
+            && token.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            fields.bytes.push(token);
+        } else {
+            fields.instruction.push(token);
+        }
+    }
+    fields
+}
+
+fn diverging_field(ours: &str, reference: &str) -> Option<TraceField> {
+    let ours = split_trace_line(ours);
+    let reference = split_trace_line(reference);
+    if ours.pc != reference.pc {
+        Some(TraceField::ProgramCounter)
+    } else if ours.bytes != reference.bytes {
+        Some(TraceField::OpcodeBytes)
+    } else if ours.instruction != reference.instruction {
+        Some(TraceField::Instruction)
+    } else if ours.registers != reference.registers {
+        Some(TraceField::Registers)
+    } else if !ours.cycles.is_empty()
+        && !reference.cycles.is_empty()
+        && ours.cycles != reference.cycles
+    {
+        // only when both logs have the cycle columns
+        Some(TraceField::Cycles)
+    } else {
+        None
+    }
+}
+
+/// Compares the lines we traced with a reference log such as nestest's,
+/// ignoring case and spacing. A reference longer than our trace is fine.
+pub fn compare_traces(ours: &[String], reference: &str) -> Result<(), TraceMismatch> {
+    let mut reference_lines = reference.lines();
+    for (index, line) in ours.iter().enumerate() {
+        let ours = line.to_ascii_uppercase();
+        let expected = match reference_lines.next() {
+            Some(expected) => expected.to_ascii_uppercase(),
+            None => {
+                return Err(TraceMismatch {
+                    line: index + 1,
+                    field: TraceField::Missing,
+                    ours,
+                    reference: String::new(),
+                })
+            }
+        };
+
+        if let Some(field) = diverging_field(&ours, &expected) {
+            return Err(TraceMismatch {
+                line: index + 1,
+                field,
+                ours: ours.split_whitespace().collect::<Vec<_>>().join(" "),
+                reference: expected.split_whitespace().collect::<Vec<_>>().join(" "),
+            });
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::components::bus::BUS;
     use crate::components::cartridge::test::test_rom;
+    use crate::components::cartridge::Rom;
     use crate::components::joypads::Joypad;
     use crate::components::ppu::PPU;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn test_cpu(program: &[u8]) -> CPU<'static> {
+        let mut cpu = CPU::new(
+            BUS::new(
+                test_rom(),
+                |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+            )
+            .unwrap(),
+        );
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.bus.memory_write(0x64 + offset as u16, byte);
+        }
+        cpu.register_pc = 0x64;
+        cpu
+    }
 
     #[test]
     fn test_format_trace() {
-        let mut bus = BUS::new(test_rom(), |_ppu: &PPU, _joypad: &mut Joypad| {});
-        bus.memory_write(100, 0xa2);
-        bus.memory_write(101, 0x01);
-        bus.memory_write(102, 0xca);
-        bus.memory_write(103, 0x88);
-        bus.memory_write(104, 0x00);
-
-        let mut cpu = CPU::new(bus);
-        cpu.register_pc = 0x64;
+        let mut cpu = test_cpu(&[0xa2, 0x01, 0xca, 0x88, 0x00]);
         cpu.register_a = 1;
         cpu.register_x = 2;
         cpu.register_y = 3;
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        })
+        .unwrap();
         assert_eq!(
             "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
             result[0]
@@ -175,28 +425,173 @@ mod test {
 
     #[test]
     fn test_format_memory_access() {
-        let mut bus = BUS::new(test_rom(), |_ppu: &PPU, _joypad: &mut Joypad| {});
         // ORA ($33), Y
-        bus.memory_write(100, 0x11);
-        bus.memory_write(101, 0x33);
+        let mut cpu = test_cpu(&[0x11, 0x33]);
 
         //data
-        bus.memory_write(0x33, 00);
-        bus.memory_write(0x34, 04);
+        cpu.bus.memory_write(0x33, 00);
+        cpu.bus.memory_write(0x34, 04);
 
         //target cell
-        bus.memory_write(0x400, 0xAA);
+        cpu.bus.memory_write(0x400, 0xAA);
 
-        let mut cpu = CPU::new(bus);
-        cpu.register_pc = 0x64;
         cpu.register_y = 0;
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(|cpu| {
             result.push(trace(cpu));
-        });
+        })
+        .unwrap();
         assert_eq!(
             "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
             result[0]
         );
     }
+
+    #[test]
+    fn test_history_keeps_last_instructions() {
+        let mut cpu = test_cpu(&[0xa2, 0x01, 0xca, 0x88, 0x00]);
+        cpu.brk_ends_run = true;
+        cpu.enable_history(2);
+        cpu.run();
+
+        let history = cpu.dump_history();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].starts_with("0067  88        DEY"));
+        assert!(history[1].starts_with("0068  00        BRK"));
+    }
+
+    #[test]
+    fn test_history_does_not_add_watched_reads() {
+        let reads = Rc::new(RefCell::new(vec![]));
+        // LDA $0300; BRK
+        let mut cpu = test_cpu(&[0xad, 0x00, 0x03, 0x00]);
+        cpu.bus.memory_write(0x0300, 0x42);
+        for range in [0x0064..=0x0066, 0x0300..=0x0300] {
+            let reads = reads.clone();
+            cpu.bus.add_read_watch(
+                range,
+                Box::new(move |address, _value| reads.borrow_mut().push(address)),
+            );
+        }
+
+        cpu.brk_ends_run = true;
+        cpu.enable_history(2);
+        cpu.run();
+
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(*reads.borrow(), vec![0x0064, 0x0065, 0x0066, 0x0300]);
+    }
+
+    #[test]
+    fn test_history_ring_wraps() {
+        let mut history = TraceHistory::new(3);
+        for i in 0..5 {
+            history.record(format!("line {}", i));
+        }
+        assert_eq!(history.dump_history(), vec!["line 2", "line 3", "line 4"]);
+    }
+
+    #[test]
+    fn test_trace_filter_pc_range() {
+        let mut cpu = test_cpu(&[0xa2, 0x01, 0xca, 0x88, 0x00]);
+        let filter = TraceFilter::new().pc_range(0x66..=0x67);
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if let Some(line) = trace_filtered(cpu, &filter) {
+                result.push(line);
+            }
+        })
+        .unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("0066  CA        DEX"));
+        assert!(result[1].starts_with("0067  88        DEY"));
+    }
+
+    #[test]
+    fn test_trace_filter_mnemonics() {
+        let mut cpu = test_cpu(&[0xa2, 0x01, 0xca, 0x88, 0x00]);
+        cpu.brk_ends_run = true;
+        let filter = TraceFilter::new().mnemonics(&["DEY", "BRK"]);
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if let Some(line) = trace_filtered(cpu, &filter) {
+                result.push(line);
+            }
+        })
+        .unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("0067  88        DEY"));
+        assert!(result[1].starts_with("0068  00        BRK"));
+    }
+
+    #[test]
+    fn test_trace_filter_touched_address() {
+        // LDA $10; STA $20; LDA $20
+        let mut cpu = test_cpu(&[0xa5, 0x10, 0x85, 0x20, 0xa5, 0x20, 0x00]);
+        let filter = TraceFilter::new().touched_address(0x20);
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            if let Some(line) = trace_filtered(cpu, &filter) {
+                result.push(line);
+            }
+        })
+        .unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].starts_with("0066  85 20     STA $20"));
+        assert!(result[1].starts_with("0068  A5 20     LDA $20"));
+    }
+
+    #[test]
+    fn test_compare_traces_finds_diverging_field() {
+        let reference = "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7\n\
+                         C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 30 CYC:10\n";
+
+        let ours = vec![
+            "c5f5  a2 00     LDX #$00    A:00 X:00 Y:00 P:24 SP:FD".to_string(),
+            "C5F7  86 00     STX $00 = 00    A:00 X:00 Y:00 P:24 SP:FD".to_string(),
+        ];
+        let mismatch = compare_traces(&ours, reference).unwrap_err();
+        assert_eq!(mismatch.line, 2);
+        assert_eq!(mismatch.field, TraceField::Registers);
+        assert_eq!(
+            mismatch.ours,
+            "C5F7 86 00 STX $00 = 00 A:00 X:00 Y:00 P:24 SP:FD"
+        );
+
+        let ours = vec![
+            "C5F5  A2 00     LDX #$00  A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 24 CYC:8".to_string(),
+        ];
+        let mismatch = compare_traces(&ours, reference).unwrap_err();
+        assert_eq!(mismatch.field, TraceField::Cycles);
+
+        assert_eq!(compare_traces(&ours[..0], ""), Ok(()));
+    }
+
+    #[test]
+    #[ignore = "needs games/nestest.nes, which is not checked in"]
+    fn test_nestest_matches_the_reference_log() {
+        let reference = include_str!("../logs/nestest_no_cycle.log");
+        let bytes = std::fs::read("games/nestest.nes").unwrap();
+        let bus = BUS::new(
+            Rom::new(&bytes).unwrap(),
+            |_ppu: &PPU, _joypad1: &mut Joypad, _joypad2: &mut Joypad| {},
+        )
+        .unwrap();
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        // the automated run, the log starts after its JMP $C5F5 at $C000
+        cpu.register_pc = 0xC5F5;
+
+        let lines = reference.lines().count();
+        let mut ours = vec![];
+        cpu.run_with_callback(|cpu| {
+            ours.push(trace(cpu));
+            if ours.len() == lines {
+                cpu.stop();
+            }
+        })
+        .unwrap();
+
+        compare_traces(&ours, reference).unwrap();
+    }
 }